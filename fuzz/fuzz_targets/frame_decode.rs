@@ -0,0 +1,23 @@
+//! Throws arbitrary bytes at the frame decoder and both ends of a transfer,
+//! i.e. at everything a peer can write to us.
+
+#![no_main]
+
+use dap_up_protocol_rs::protocol::frame::Frame;
+use dap_up_protocol_rs::protocol::link::Link;
+use dap_up_protocol_rs::protocol::transfer::{Receiver, Sender};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Whatever decodes has to encode back to the same bytes
+    if let Ok(frame) = Frame::decode(data) {
+        assert_eq!(frame.encode().as_deref(), Ok(data));
+    }
+
+    let mut sender = Sender::default();
+    let _ = sender.send(b"profile".to_vec());
+    sender.on_receive(data);
+
+    Receiver::new().on_receive(data);
+    Link::new(517).on_receive(data);
+});
@@ -0,0 +1,265 @@
+//! DapUp advertisement payload, carried as service data for [`SERVICE_UUID16`].
+//!
+//! Layout of protocol version 1, all integers little endian:
+//!
+//! | offset | size | field                                   |
+//! |--------|------|-----------------------------------------|
+//! | 0      | 1    | protocol version                        |
+//! | 1      | 1    | capability flags, see [`flags`]         |
+//! | 2      | 8    | rotating device token                   |
+//! | 10     | 2    | profile revision                        |
+//! | 12     | 2    | event code, 0 for "any event"           |
+//! | 14     | 1    | RSSI at 1 m (dBm, signed), only present |
+//! |        |      | with [`flags::TX_POWER`]                |
+//!
+//! Decoders ignore bytes past the fields they know so later versions can
+//! append fields without breaking older badges.
+//!
+//! Golden vector: version 1, flags `ACCEPTING | PROVISIONED`, token
+//! `01 02 03 04 05 06 07 08`, revision `0x1234`, event `0xbeef` encodes to
+//! `01 03 01 02 03 04 05 06 07 08 34 12 ef be`. With an RSSI at 1 m of -59 dBm
+//! the flags gain [`flags::TX_POWER`] and it is appended:
+//! `01 07 01 02 03 04 05 06 07 08 34 12 ef be c5`.
+
+use crate::utils::provisioning;
+use crate::utils::serial::DeviceInfo;
+use crate::utils::settings::Settings;
+use crate::utils::storage::KeyValueStore;
+use std::fmt;
+
+/// 16 bit service UUID the payload is advertised under
+pub const SERVICE_UUID16: u16 = 0xfe9f;
+
+pub const PROTOCOL_VERSION: u8 = 1;
+
+pub const TOKEN_LEN: usize = 8;
+
+/// Size of a version 1 payload without optional fields
+pub const PAYLOAD_LEN: usize = 14;
+
+pub mod flags {
+    /// Willing to start an exchange right now
+    pub const ACCEPTING: u8 = 1 << 0;
+    /// The serial number has been provisioned at the factory
+    pub const PROVISIONED: u8 = 1 << 1;
+    /// The calibrated RSSI at 1 m follows the fixed fields
+    pub const TX_POWER: u8 = 1 << 2;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdvPayload {
+    pub version: u8,
+    pub flags: u8,
+    pub token: [u8; TOKEN_LEN],
+    /// Changes whenever the advertised profile changes
+    pub profile_revision: u16,
+    pub event_code: u16,
+    /// Calibrated RSSI measured 1 m away, used for distance estimates
+    pub tx_power: Option<i8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdvError {
+    TooShort(usize),
+    UnsupportedVersion(u8),
+}
+
+impl fmt::Display for AdvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdvError::TooShort(len) => {
+                write!(f, "payload is {} bytes, expected {}", len, PAYLOAD_LEN)
+            }
+            AdvError::UnsupportedVersion(version) => {
+                write!(f, "protocol version {} is not supported", version)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AdvError {}
+
+impl AdvPayload {
+    pub fn new(flags: u8, token: [u8; TOKEN_LEN], profile_revision: u16, event_code: u16) -> Self {
+        AdvPayload {
+            version: PROTOCOL_VERSION,
+            flags,
+            token,
+            profile_revision,
+            event_code,
+            tx_power: None,
+        }
+    }
+
+    pub fn with_tx_power(mut self, tx_power: Option<i8>) -> Self {
+        self.tx_power = tx_power;
+        self
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut flags = self.flags & !flags::TX_POWER;
+        if self.tx_power.is_some() {
+            flags |= flags::TX_POWER;
+        }
+
+        let mut bytes = Vec::with_capacity(PAYLOAD_LEN + 1);
+        bytes.push(self.version);
+        bytes.push(flags);
+        bytes.extend_from_slice(&self.token);
+        bytes.extend_from_slice(&self.profile_revision.to_le_bytes());
+        bytes.extend_from_slice(&self.event_code.to_le_bytes());
+        if let Some(tx_power) = self.tx_power {
+            bytes.push(tx_power as u8);
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, AdvError> {
+        // Check the version first so a short payload from a future version is
+        // reported as such rather than as truncated
+        match bytes.first() {
+            Some(&PROTOCOL_VERSION) => {}
+            Some(&version) => return Err(AdvError::UnsupportedVersion(version)),
+            None => return Err(AdvError::TooShort(0)),
+        }
+
+        if bytes.len() < PAYLOAD_LEN {
+            return Err(AdvError::TooShort(bytes.len()));
+        }
+
+        let mut token = [0u8; TOKEN_LEN];
+        token.copy_from_slice(&bytes[2..10]);
+
+        let flags = bytes[1];
+        let tx_power = if flags & flags::TX_POWER != 0 {
+            match bytes.get(PAYLOAD_LEN) {
+                Some(&tx_power) => Some(tx_power as i8),
+                None => return Err(AdvError::TooShort(bytes.len())),
+            }
+        } else {
+            None
+        };
+
+        Ok(AdvPayload {
+            version: bytes[0],
+            flags,
+            token,
+            profile_revision: u16::from_le_bytes([bytes[10], bytes[11]]),
+            event_code: u16::from_le_bytes([bytes[12], bytes[13]]),
+            tx_power,
+        })
+    }
+}
+
+/// Short fingerprint of an encoded contact card, peers compare it to tell
+/// whether our profile changed since they last saw us
+pub fn profile_revision(encoded_card: &[u8]) -> u16 {
+    const CRC16: crc::Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_IBM_SDLC);
+    CRC16.checksum(encoded_card)
+}
+
+/// Builds the payload describing this device from what is in storage
+pub fn local_payload<S: KeyValueStore>(
+    store: &S,
+    token: [u8; TOKEN_LEN],
+) -> anyhow::Result<AdvPayload> {
+    let mut flags = 0;
+
+    if provisioning::is_locked(store)? {
+        flags |= flags::PROVISIONED;
+    }
+
+    // Only a registered device has something worth exchanging
+    let info = DeviceInfo::new(store);
+    if DeviceInfo::load(store).is_ok() {
+        flags |= flags::ACCEPTING;
+    }
+
+    let revision = profile_revision(&info.card.encode()?);
+    let settings = Settings::load(store);
+
+    Ok(AdvPayload::new(flags, token, revision, settings.event_code)
+        .with_tx_power(settings.tx_power_at_1m))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GOLDEN: &[u8] = &[
+        0x01, 0x03, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x34, 0x12, 0xef, 0xbe,
+    ];
+    const GOLDEN_TX_POWER: &[u8] = &[
+        0x01, 0x07, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x34, 0x12, 0xef, 0xbe, 0xc5,
+    ];
+
+    fn golden() -> AdvPayload {
+        AdvPayload::new(
+            flags::ACCEPTING | flags::PROVISIONED,
+            [1, 2, 3, 4, 5, 6, 7, 8],
+            0x1234,
+            0xbeef,
+        )
+    }
+
+    #[test]
+    fn golden_vector() {
+        assert_eq!(golden().encode(), GOLDEN);
+        assert_eq!(AdvPayload::decode(GOLDEN), Ok(golden()));
+    }
+
+    #[test]
+    fn golden_vector_with_tx_power() {
+        let payload = golden().with_tx_power(Some(-59));
+        assert_eq!(payload.encode(), GOLDEN_TX_POWER);
+
+        let decoded = AdvPayload::decode(GOLDEN_TX_POWER).unwrap();
+        assert_eq!(decoded.tx_power, Some(-59));
+        assert_eq!(
+            decoded.flags,
+            flags::ACCEPTING | flags::PROVISIONED | flags::TX_POWER
+        );
+    }
+
+    #[test]
+    fn tx_power_flag_follows_the_field() {
+        let mut payload = golden();
+        payload.flags |= flags::TX_POWER;
+        assert_eq!(payload.encode(), GOLDEN);
+    }
+
+    #[test]
+    fn tx_power_flag_without_the_byte_is_truncated() {
+        let mut bytes = GOLDEN.to_vec();
+        bytes[1] |= flags::TX_POWER;
+        assert_eq!(
+            AdvPayload::decode(&bytes),
+            Err(AdvError::TooShort(PAYLOAD_LEN))
+        );
+    }
+
+    #[test]
+    fn trailing_bytes_are_ignored() {
+        let mut bytes = GOLDEN.to_vec();
+        bytes.extend_from_slice(&[0xaa, 0xbb]);
+        assert_eq!(AdvPayload::decode(&bytes), Ok(golden()));
+    }
+
+    #[test]
+    fn rejects_short_and_unknown_payloads() {
+        assert_eq!(AdvPayload::decode(&[]), Err(AdvError::TooShort(0)));
+        assert_eq!(
+            AdvPayload::decode(&GOLDEN[..PAYLOAD_LEN - 1]),
+            Err(AdvError::TooShort(PAYLOAD_LEN - 1))
+        );
+        // A future version is reported as such, however short it is
+        assert_eq!(
+            AdvPayload::decode(&[2]),
+            Err(AdvError::UnsupportedVersion(2))
+        );
+        assert_eq!(
+            AdvPayload::decode(&[0; 20]),
+            Err(AdvError::UnsupportedVersion(0))
+        );
+    }
+}
@@ -0,0 +1,48 @@
+use super::advertisement::{AdvPayload, SERVICE_UUID16};
+use super::ble_error;
+use esp32_nimble::utilities::mutex::Mutex;
+use esp32_nimble::utilities::BleUuid;
+use esp32_nimble::{BLEAdvertisementData, BLEAdvertising, BLEDevice};
+
+/// Advertises the DapUp payload through NimBLE
+#[derive(Clone, Copy)]
+pub struct Advertiser {
+    advertising: &'static Mutex<BLEAdvertising>,
+}
+
+impl Advertiser {
+    pub fn new(device: &'static BLEDevice) -> Self {
+        Advertiser {
+            advertising: device.get_advertising(),
+        }
+    }
+
+    /// Replaces the advertised payload and (re)starts advertising every
+    /// `interval` (in units of 0.625 ms)
+    pub fn start(&self, payload: &AdvPayload, interval: u16) -> anyhow::Result<()> {
+        let uuid = BleUuid::from_uuid16(SERVICE_UUID16);
+        let mut advertising = self.advertising.lock();
+
+        // The interval only changes when advertising restarts
+        if advertising.is_advertising() {
+            advertising.stop().map_err(ble_error)?;
+        }
+
+        advertising
+            .set_data(
+                BLEAdvertisementData::new()
+                    .add_service_uuid(uuid)
+                    .service_data(uuid, &payload.encode()),
+            )
+            .map_err(ble_error)?;
+        advertising.min_interval(interval).max_interval(interval);
+        advertising.start().map_err(ble_error)?;
+
+        log::debug!("Advertising {:?}", payload);
+        Ok(())
+    }
+
+    pub fn stop(&self) -> anyhow::Result<()> {
+        self.advertising.lock().stop().map_err(ble_error)
+    }
+}
@@ -0,0 +1,468 @@
+use super::gatt::{self, ControlOp, Status};
+use super::outcome::{ExchangeOutcome, RetryPolicy, Step, StepError};
+use super::scan::AddressType;
+use crate::protocol::link::{Link, LinkError, LinkEvent};
+use crate::utils::error::DeviceInfoError;
+use crate::utils::exchange::{self, ExchangeProfile};
+use esp32_nimble::utilities::BleUuid;
+use esp32_nimble::{BLEAddress, BLEAddressType, BLEClient, BLEError, BLERemoteCharacteristic};
+use esp_idf_svc::hal::task::block_on;
+use esp_idf_svc::timer::{EspAsyncTimer, EspTaskTimerService};
+use std::future::{poll_fn, Future};
+use std::pin::pin;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+type StepResult<T> = Result<T, (Step, StepError)>;
+
+/// How often a pending connection checks whether to give way to the peer's
+const GIVE_WAY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Client half of the README process: connects to the discovered peer, reads
+/// its profile and hands over ours, see [`gatt`] for the sequence
+pub struct GattClient {
+    policy: RetryPolicy,
+    timer: EspAsyncTimer,
+}
+
+impl GattClient {
+    pub fn new(policy: RetryPolicy) -> anyhow::Result<Self> {
+        Ok(GattClient {
+            policy,
+            timer: EspTaskTimerService::new()?.timer_async()?,
+        })
+    }
+
+    /// Exchanges profiles with the peer at `address`, retrying whole attempts
+    /// as allowed by the [`RetryPolicy`]. `keep` checks that the peer's
+    /// profile can be kept before the commit is started, `give_way` whether
+    /// the peer connected to us in the meantime and its connection is the one
+    /// to go on with.
+    pub fn exchange<F, G>(
+        &mut self,
+        address: [u8; 6],
+        address_type: AddressType,
+        own: &ExchangeProfile,
+        keep: F,
+        give_way: G,
+    ) -> ExchangeOutcome
+    where
+        F: Fn(&ExchangeProfile) -> Result<(), DeviceInfoError>,
+        G: Fn() -> bool,
+    {
+        let own = match own.encode() {
+            Ok(own) => own,
+            Err(e) => {
+                return ExchangeOutcome::Failed {
+                    step: Step::WriteProfile,
+                    error: StepError::InvalidProfile(e.to_string()),
+                    attempts: 0,
+                }
+            }
+        };
+        let address = BLEAddress::from_be_bytes(address, nimble_address_type(address_type));
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+
+            let mut client = BLEClient::new();
+            let result = block_on(self.attempt(&mut client, &address, &own, &keep, &give_way));
+            if client.connected() {
+                if let Err(e) = client.disconnect() {
+                    log::warn!("Unable to disconnect from {}: {:?}", address, e);
+                }
+            }
+
+            match result {
+                Ok(profile) => {
+                    return ExchangeOutcome::Exchanged {
+                        profile: Box::new(profile),
+                        attempts: attempt,
+                    }
+                }
+                Err((step, error)) => {
+                    log::warn!("Exchange attempt {} failed, {}: {}", attempt, step, error);
+                    if !self.policy.should_retry(attempt, &error) {
+                        return ExchangeOutcome::Failed {
+                            step,
+                            error,
+                            attempts: attempt,
+                        };
+                    }
+                    std::thread::sleep(self.policy.backoff(attempt));
+                }
+            }
+        }
+    }
+
+    async fn attempt<F, G>(
+        &mut self,
+        client: &mut BLEClient,
+        address: &BLEAddress,
+        own: &[u8],
+        keep: &F,
+        give_way: &G,
+    ) -> StepResult<ExchangeProfile>
+    where
+        F: Fn(&ExchangeProfile) -> Result<(), DeviceInfoError>,
+        G: Fn() -> bool,
+    {
+        let policy = self.policy;
+        let timer = &mut self.timer;
+
+        let timeout = policy.timeout(Step::Connect);
+        connect(timer, timeout, client, address, give_way).await?;
+        // Answered before discovery is, ATT handles one request at a time
+        super::exchange_mtu(client.conn_handle());
+
+        // Subscribe before writing anything so no status change is missed
+        let timeout = policy.timeout(Step::Discover);
+        let (sender, statuses) = mpsc::channel();
+        let status = discover(timer, timeout, client, gatt::STATUS_UUID).await?;
+        status.on_notify(move |data| {
+            if let Some(status) = Status::decode(data) {
+                let _ = sender.send(status);
+            }
+        });
+        ble_step(
+            timer,
+            timeout,
+            Step::Discover,
+            status.subscribe_notify(false),
+        )
+        .await?;
+
+        let (sender, frames) = mpsc::channel();
+        let profile = discover(timer, timeout, client, gatt::PROFILE_UUID).await?;
+        profile.on_notify(move |data| {
+            let _ = sender.send(data.to_vec());
+        });
+        ble_step(
+            timer,
+            timeout,
+            Step::Discover,
+            profile.subscribe_notify(false),
+        )
+        .await?;
+
+        // Begin makes the peer start sending its profile
+        let mut link = Link::new(super::att_mtu(client.conn_handle()));
+        write_control(timer, timeout, client, ControlOp::Begin, Step::Discover).await?;
+
+        let timeout = policy.timeout(Step::ReadProfile);
+        let transfer = Transfer {
+            frames: &frames,
+            timeout,
+            step: Step::ReadProfile,
+        };
+        let bytes = match transfer.run(timer, client, &mut link, None).await? {
+            Some(bytes) => bytes,
+            // Sent, only reported once we send something ourselves
+            None => return Err((Step::ReadProfile, StepError::NotDapUp)),
+        };
+        let profile = match ExchangeProfile::decode(&bytes) {
+            Ok(profile) => profile,
+            Err(e) => {
+                // Best effort, the error below is what gets reported
+                let _ =
+                    write_control(timer, timeout, client, ControlOp::Nack, Step::ReadProfile).await;
+                return Err((Step::ReadProfile, StepError::InvalidProfile(e.to_string())));
+            }
+        };
+
+        let first = link
+            .send(own)
+            .map_err(|e| (Step::WriteProfile, StepError::InvalidProfile(e.to_string())))?;
+        let transfer = Transfer {
+            frames: &frames,
+            timeout: policy.timeout(Step::WriteProfile),
+            step: Step::WriteProfile,
+        };
+        transfer.run(timer, client, &mut link, Some(first)).await?;
+
+        let timeout = policy.timeout(Step::AwaitReceived);
+        wait_for(&statuses, timeout, Step::AwaitReceived, |status| {
+            (status == Status::Received).then_some(())
+        })?;
+
+        // Acknowledging promises to keep their profile
+        let timeout = policy.timeout(Step::AwaitPrepared);
+        if let Err(e) = keep(&profile) {
+            // Best effort, the error below is what gets reported
+            let _ = write_control(
+                timer,
+                timeout,
+                client,
+                ControlOp::Abort,
+                Step::AwaitPrepared,
+            )
+            .await;
+            return Err((Step::AwaitPrepared, StepError::CannotKeep(e.to_string())));
+        }
+
+        // First phase, their profile decoded above so confirm it (ACK_OK) by
+        // its digest, they answer with the digest of ours
+        let ack = ControlOp::AckOk(exchange::digest(&bytes));
+        write_control(timer, timeout, client, ack, Step::AwaitPrepared).await?;
+        let acked = wait_for(
+            &statuses,
+            timeout,
+            Step::AwaitPrepared,
+            |status| match status {
+                Status::Prepared(digest) => Some(digest),
+                _ => None,
+            },
+        )?;
+        if acked != exchange::digest(own) {
+            return Err((Step::AwaitPrepared, StepError::DigestMismatch));
+        }
+
+        // Second phase, the peer keeps our profile once the commit arrives and
+        // we keep theirs once it reports Done
+        let timeout = policy.timeout(Step::Commit);
+        write_control(timer, timeout, client, ControlOp::Commit, Step::Commit).await?;
+        wait_for(&statuses, timeout, Step::Commit, |status| {
+            (status == Status::Done).then_some(())
+        })?;
+
+        Ok(profile)
+    }
+}
+
+/// One direction of the profile transfer, frames from the peer arrive as
+/// profile notifications and ours go to the inbox
+struct Transfer<'a> {
+    frames: &'a Receiver<Vec<u8>>,
+    /// How long to wait for each frame before repeating or asking again
+    timeout: Duration,
+    step: Step,
+}
+
+impl Transfer<'_> {
+    /// Runs `link` until the peer's payload is complete (`Some`) or ours was
+    /// acknowledged (`None`), starting with `first` if we are the sender
+    async fn run(
+        &self,
+        timer: &mut EspAsyncTimer,
+        client: &mut BLEClient,
+        link: &mut Link,
+        first: Option<Vec<u8>>,
+    ) -> StepResult<Option<Vec<u8>>> {
+        let mut transmit = first;
+        let mut done = None;
+
+        loop {
+            if let Some(bytes) = transmit.take() {
+                write(
+                    timer,
+                    self.timeout,
+                    client,
+                    gatt::INBOX_UUID,
+                    &bytes,
+                    self.step,
+                )
+                .await?;
+            }
+            // Only now, the last chunk we received still needed its ACK_OK
+            if let Some(done) = done {
+                return Ok(done);
+            }
+
+            let action = match self.frames.recv_timeout(self.timeout) {
+                Ok(bytes) => link.on_receive(&bytes),
+                Err(RecvTimeoutError::Timeout) => link.on_timeout(),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err((self.step, StepError::Ble("Notifications stopped".into())))
+                }
+            };
+
+            transmit = action.transmit;
+            match action.event {
+                Some(LinkEvent::Received(bytes)) => done = Some(Some(bytes)),
+                Some(LinkEvent::Sent) => done = Some(None),
+                Some(LinkEvent::Failed(LinkError::Chunk(e))) => {
+                    return Err((self.step, StepError::InvalidProfile(e.to_string())))
+                }
+                Some(LinkEvent::Failed(LinkError::Transfer(_))) => {
+                    return Err((self.step, StepError::Timeout))
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+fn nimble_address_type(address_type: AddressType) -> BLEAddressType {
+    match address_type {
+        AddressType::Public => BLEAddressType::Public,
+        AddressType::Random => BLEAddressType::Random,
+        AddressType::PublicId => BLEAddressType::PublicID,
+        AddressType::RandomId => BLEAddressType::RandomID,
+    }
+}
+
+/// Connects to `address` unless `give_way` tells us to leave the connection
+/// to the peer first. A connection that is still pending is cancelled.
+async fn connect(
+    timer: &mut EspAsyncTimer,
+    timeout: Duration,
+    client: &mut BLEClient,
+    address: &BLEAddress,
+    give_way: &impl Fn() -> bool,
+) -> StepResult<()> {
+    let deadline = Instant::now() + timeout;
+    let mut connecting = pin!(client.connect(address));
+
+    let error = loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break StepError::Timeout;
+        }
+
+        match with_timeout(timer, left.min(GIVE_WAY_INTERVAL), connecting.as_mut()).await {
+            Some(Ok(())) => return Ok(()),
+            Some(Err(e)) => return Err((Step::Connect, StepError::Ble(format!("{:?}", e)))),
+            None if give_way() => break StepError::GaveWay,
+            None => {}
+        }
+    };
+
+    super::cancel_connect();
+    Err((Step::Connect, error))
+}
+
+/// Finds a characteristic of the DapUp service. The service is only discovered
+/// once per connection, NimBLE caches it after that.
+async fn discover<'a>(
+    timer: &mut EspAsyncTimer,
+    timeout: Duration,
+    client: &'a mut BLEClient,
+    uuid: &str,
+) -> StepResult<&'a mut BLERemoteCharacteristic> {
+    let uuid = BleUuid::from_uuid128_string(uuid)
+        .map_err(|e| (Step::Discover, StepError::Ble(format!("{:?}", e))))?;
+
+    let service = BleUuid::from_uuid16(gatt::SERVICE_UUID);
+
+    let found = with_timeout(timer, timeout, async {
+        client.get_service(service).await.map(|_| ())
+    })
+    .await;
+    match found {
+        Some(Ok(())) => {}
+        // Still connected means the peer answered, just without our service
+        Some(Err(_)) if client.connected() => return Err((Step::Discover, StepError::NotDapUp)),
+        Some(Err(e)) => return Err((Step::Discover, StepError::Ble(format!("{:?}", e)))),
+        None => return Err((Step::Discover, StepError::Timeout)),
+    }
+
+    let found = with_timeout(timer, timeout, async move {
+        client
+            .get_service(service)
+            .await?
+            .get_characteristic(uuid)
+            .await
+    })
+    .await;
+    match found {
+        Some(Ok(characteristic)) => Ok(characteristic),
+        Some(Err(e)) => Err((Step::Discover, StepError::Ble(format!("{:?}", e)))),
+        None => Err((Step::Discover, StepError::Timeout)),
+    }
+}
+
+async fn write_control(
+    timer: &mut EspAsyncTimer,
+    timeout: Duration,
+    client: &mut BLEClient,
+    op: ControlOp,
+    step: Step,
+) -> StepResult<()> {
+    let uuid = gatt::CONTROL_POINT_UUID;
+    write(timer, timeout, client, uuid, &op.encode(), step).await
+}
+
+async fn write(
+    timer: &mut EspAsyncTimer,
+    timeout: Duration,
+    client: &mut BLEClient,
+    uuid: &str,
+    bytes: &[u8],
+    step: Step,
+) -> StepResult<()> {
+    let characteristic = discover(timer, timeout, client, uuid).await?;
+    ble_step(
+        timer,
+        timeout,
+        step,
+        characteristic.write_value(bytes, true),
+    )
+    .await
+}
+
+/// Blocks until the peer notifies a status `wanted` accepts, failing early on
+/// a status that ends the exchange
+fn wait_for<T>(
+    statuses: &Receiver<Status>,
+    timeout: Duration,
+    step: Step,
+    mut wanted: impl FnMut(Status) -> Option<T>,
+) -> StepResult<T> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match statuses.recv_timeout(left) {
+            Ok(status @ (Status::Rejected | Status::Aborted)) => {
+                return Err((step, StepError::Refused(status)))
+            }
+            Ok(status) => {
+                if let Some(value) = wanted(status) {
+                    return Ok(value);
+                }
+            }
+            Err(_) => return Err((step, StepError::Timeout)),
+        }
+    }
+}
+
+async fn ble_step<T, F>(
+    timer: &mut EspAsyncTimer,
+    timeout: Duration,
+    step: Step,
+    future: F,
+) -> StepResult<T>
+where
+    F: Future<Output = Result<T, BLEError>>,
+{
+    match with_timeout(timer, timeout, future).await {
+        Some(Ok(value)) => Ok(value),
+        Some(Err(e)) => Err((step, StepError::Ble(format!("{:?}", e)))),
+        None => Err((step, StepError::Timeout)),
+    }
+}
+
+/// Runs `future` until it completes or `timeout` passes, `None` on timeout.
+/// The future is dropped on timeout, the caller disconnects afterwards.
+async fn with_timeout<F: Future>(
+    timer: &mut EspAsyncTimer,
+    timeout: Duration,
+    future: F,
+) -> Option<F::Output> {
+    let mut future = pin!(future);
+    let mut expired = pin!(timer.after(timeout));
+
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Some(output));
+        }
+
+        match expired.as_mut().poll(cx) {
+            Poll::Ready(_) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    })
+    .await
+}
@@ -0,0 +1,637 @@
+use super::advertisement::{local_payload, AdvPayload};
+use super::advertiser::Advertiser;
+use super::client::GattClient;
+use super::duty_cycle::DutyCycle;
+use super::election::{self, Direction, Role};
+use super::gatt::{ControlOp, Status};
+use super::outcome::{ExchangeOutcome, RetryPolicy, StepError};
+use super::peers::{DiscoveredPeer, PeerPolicy, PeerTable};
+use super::privacy::{self, KnownPeers};
+use super::scan::{classify, PeerCandidate, RawAdvertisement};
+use super::server::{GattServer, ServerEvent};
+use super::session::{Event, Failure, Session, State, Timeouts};
+use crate::utils::address;
+use crate::utils::blocklist::Blocklist;
+use crate::utils::clock::{Clock, SystemClock};
+use crate::utils::contacts::Contacts;
+use crate::utils::error::DeviceInfoError;
+use crate::utils::exchange::ExchangeProfile;
+use crate::utils::identity;
+use crate::utils::remember;
+use crate::utils::settings::Settings;
+use crate::utils::storage::{DeviceStorage, KeyValueStore};
+use esp32_nimble::{BLEDevice, BLEScan};
+use esp_idf_svc::hal::task::block_on;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const SCAN_STACK_SIZE: usize = 6 * 1024;
+/// How often the stop flag is checked while the scanner pauses, and the
+/// server's events while waiting for a peer
+const PAUSE_STEP: Duration = Duration::from_millis(100);
+/// How long a peer may stay connected while we discover without a frame
+/// going across, it holds one of the connection slots and keeps us from
+/// advertising
+const IDLE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Advertises and scans until a peer is found.
+///
+/// Follows the README: one side advertises our payload, a scan thread looks
+/// for other badges. After every scan pass the best ranked peer in the
+/// [`PeerTable`], if any stayed above the proximity threshold for the dwell
+/// time, is sent over a channel and both roles are torn down before the
+/// caller moves on to connecting.
+///
+/// Advertising interval and scan windows follow the shared [`DutyCycle`],
+/// which every accepted candidate counts as activity for.
+///
+/// No exchange is served while discovering, but peers may still connect to
+/// us. Advertising starts again once they hang up, and they are hung up on
+/// once they go [`IDLE_CONNECTION_TIMEOUT`] without a frame.
+pub struct Discovery {
+    advertiser: Advertiser,
+    payload: AdvPayload,
+    duty: Arc<Mutex<DutyCycle>>,
+    /// Peers connected to our server, when they last sent a frame
+    connections: HashMap<u16, Instant>,
+    stop: Arc<AtomicBool>,
+    scanner: Option<JoinHandle<()>>,
+    found: Receiver<DiscoveredPeer>,
+}
+
+impl Discovery {
+    /// Starts advertising `payload` and scanning. `accept` decides which
+    /// classified candidates may be connected to at all, e.g. not blocklisted,
+    /// and `policy` how close and for how long they have to be.
+    pub fn start<F>(
+        device: &'static BLEDevice,
+        payload: &AdvPayload,
+        event_code: u16,
+        policy: PeerPolicy,
+        duty: Arc<Mutex<DutyCycle>>,
+        accept: F,
+    ) -> anyhow::Result<Self>
+    where
+        F: Fn(&PeerCandidate) -> bool + Send + 'static,
+    {
+        let mut timing = lock(&duty).timing(Instant::now());
+        let advertiser = Advertiser::new(device);
+        advertiser.start(payload, timing.adv_interval)?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let (sender, found) = mpsc::sync_channel(1);
+
+        let scanner = {
+            let stop = stop.clone();
+            let payload = *payload;
+            let duty = duty.clone();
+
+            std::thread::Builder::new()
+                .name("scan".to_string())
+                .stack_size(SCAN_STACK_SIZE)
+                .spawn(move || {
+                    // Same singleton as `device`, taken here so no reference
+                    // to it has to cross the thread boundary
+                    let device = BLEDevice::take();
+                    let mut peers = PeerTable::new(policy, accept);
+                    let mut scan = BLEScan::new();
+                    scan.active_scan(false);
+
+                    while !stop.load(Ordering::Relaxed) {
+                        let now = Instant::now();
+                        peers.expire(now);
+
+                        let next = lock(&duty).timing(now);
+                        if next.adv_interval != timing.adv_interval {
+                            if let Err(e) = advertiser.start(&payload, next.adv_interval) {
+                                log::error!("Unable to change the advertising interval: {}", e);
+                            }
+                        }
+                        if next != timing {
+                            log::debug!(
+                                "Radio timing {:?}, scanning {:.0}% of the time",
+                                next,
+                                next.scan_duty() * 100.0
+                            );
+                        }
+                        timing = next;
+                        scan.interval(timing.scan_interval)
+                            .window(timing.scan_window);
+
+                        let duration = timing.scan_duration.as_millis() as i32;
+                        let result =
+                            block_on(scan.start(device, duration, |device, data| -> Option<()> {
+                                let raw = RawAdvertisement::from_nimble(device, &data);
+                                match classify(&raw, event_code) {
+                                    // Our own advertisement can be echoed back
+                                    Ok(peer) if peer.payload.token == payload.token => {}
+                                    Ok(peer) => {
+                                        let now = Instant::now();
+                                        if let Some(peer) = peers.observe(peer, now) {
+                                            lock(&duty).record_activity(now);
+                                            log::trace!(
+                                                "{} at {:.1} dBm (~{:.1} m) for {:?}",
+                                                address::format(&peer.candidate.address),
+                                                peer.smoothed_rssi,
+                                                peer.distance_m,
+                                                peer.dwell
+                                            );
+                                        }
+                                    }
+                                    Err(rejection) => log::trace!(
+                                        "Ignoring {}: {}",
+                                        address::format(&raw.address),
+                                        rejection
+                                    ),
+                                }
+                                // Scan the whole pass so every peer in range gets ranked
+                                None
+                            }));
+
+                        match result {
+                            Ok(_) => {
+                                if let Some(peer) = peers.next(Instant::now()) {
+                                    let _ = sender.send(peer.clone());
+                                    break;
+                                }
+                                pause(&stop, timing.scan_pause);
+                            }
+                            Err(e) => {
+                                log::error!("Scan failed: {:?}", e);
+                                std::thread::sleep(Duration::from_millis(500));
+                            }
+                        }
+                    }
+                })?
+        };
+
+        log::info!("Discovery started");
+        Ok(Discovery {
+            advertiser,
+            payload: *payload,
+            duty,
+            connections: HashMap::new(),
+            stop,
+            scanner: Some(scanner),
+            found,
+        })
+    }
+
+    /// Blocks until a peer is found, then stops advertising and scanning.
+    /// Looks after the peers connecting to `server` in the meantime.
+    pub fn wait(mut self, server: &GattServer) -> anyhow::Result<DiscoveredPeer> {
+        let peer = self.next(server, None);
+        self.shutdown(server)?;
+
+        peer.ok_or_else(|| anyhow::anyhow!("Scan thread stopped without a peer"))
+    }
+
+    /// Like [`Discovery::wait`] but gives up after `timeout`
+    pub fn wait_timeout(
+        mut self,
+        server: &GattServer,
+        timeout: Duration,
+    ) -> anyhow::Result<Option<DiscoveredPeer>> {
+        let peer = self.next(server, Some(Instant::now() + timeout));
+        self.shutdown(server)?;
+
+        Ok(peer)
+    }
+
+    /// The next peer found before `deadline`, if any
+    fn next(&mut self, server: &GattServer, deadline: Option<Instant>) -> Option<DiscoveredPeer> {
+        loop {
+            let step = match deadline {
+                Some(deadline) => deadline
+                    .saturating_duration_since(Instant::now())
+                    .min(PAUSE_STEP),
+                None => PAUSE_STEP,
+            };
+
+            match self.found.recv_timeout(step) {
+                Ok(peer) => return Some(peer),
+                Err(RecvTimeoutError::Disconnected) => return None,
+                Err(RecvTimeoutError::Timeout) if step.is_zero() => return None,
+                Err(RecvTimeoutError::Timeout) => self.serve(server),
+            }
+        }
+    }
+
+    /// Keeps track of the peers connected to `server`, nothing they write is
+    /// committed while we discover
+    fn serve(&mut self, server: &GattServer) {
+        let now = Instant::now();
+        if server.take_progress() {
+            for seen in self.connections.values_mut() {
+                *seen = now;
+            }
+        }
+
+        while let Ok(event) = server.events().try_recv() {
+            match event {
+                ServerEvent::Connected { address, handle } => {
+                    log::debug!("{} connected while discovering", address::format(&address));
+                    self.connections.insert(handle, now);
+                }
+                ServerEvent::Disconnected { handle, .. } => {
+                    self.connections.remove(&handle);
+                    // A connection stops advertising
+                    let interval = lock(&self.duty).timing(now).adv_interval;
+                    if let Err(e) = self.advertiser.start(&self.payload, interval) {
+                        log::error!("Unable to advertise again: {}", e);
+                    }
+                }
+                ServerEvent::Received(_) | ServerEvent::Control(_) => {}
+            }
+        }
+
+        self.connections.retain(|&handle, seen| {
+            if now.duration_since(*seen) < IDLE_CONNECTION_TIMEOUT {
+                return true;
+            }
+
+            log::debug!("Hanging up on an idle peer");
+            if let Err(e) = server.disconnect(handle) {
+                log::warn!("Unable to hang up on an idle peer: {}", e);
+            }
+            false
+        });
+    }
+
+    /// Stops advertising and scanning and hangs up on whoever is still
+    /// connected, they can not commit anything
+    fn shutdown(&mut self, server: &GattServer) -> anyhow::Result<()> {
+        self.stop_radio()?;
+
+        for (handle, _) in self.connections.drain() {
+            if let Err(e) = server.disconnect(handle) {
+                log::warn!("Unable to hang up on a peer: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn stop_radio(&mut self) -> anyhow::Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(scanner) = self.scanner.take() {
+            // At most one scan pass or pause step away
+            let _ = scanner.join();
+        }
+
+        self.advertiser.stop()?;
+        log::info!("Discovery stopped");
+        Ok(())
+    }
+}
+
+/// Sleeps for `duration` with the radio idle, returning early once `stop` is set
+fn pause(stop: &AtomicBool, duration: Duration) {
+    let until = Instant::now() + duration;
+    while !stop.load(Ordering::Relaxed) {
+        let left = until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        std::thread::sleep(left.min(PAUSE_STEP));
+    }
+}
+
+/// The duty cycle holds no invariants a panicking thread could break
+fn lock(duty: &Mutex<DutyCycle>) -> MutexGuard<'_, DutyCycle> {
+    duty.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        if self.scanner.is_some() {
+            if let Err(e) = self.stop_radio() {
+                log::error!("Unable to stop discovery: {}", e);
+            }
+        }
+    }
+}
+
+/// Discovery cycle of the README: find a peer, tear discovery down, hand the
+/// peer over, start again. Only returns if something fails.
+///
+/// The advertised token rotates every `Settings::rotation_interval` and after
+/// a collision, the address together with it. Known peers resolve the token
+/// through [`KnownPeers`] so blocklisted badges stay skipped even though their
+/// address and token keep changing.
+pub fn run<S: KeyValueStore>(
+    device: &'static BLEDevice,
+    server: &GattServer,
+    mut storage: DeviceStorage<S>,
+) -> anyhow::Result<()> {
+    // Only generated now that the radio is on and feeds the RNG
+    let identity_key = identity::identity_key(&mut storage)?;
+    let mut token = privacy::rotating_token(&identity_key);
+    let mut rotated_at = Instant::now();
+    // `enable_private_address` picked the address for the first token
+    let mut addressed = token;
+    let duty = Arc::new(Mutex::new(DutyCycle::new(
+        Settings::load(&storage).power_profile,
+    )));
+    let policy = RetryPolicy::default();
+    let mut client = GattClient::new(policy)?;
+    let mut session = Session::new(SystemClock, Timeouts::from_policy(&policy));
+
+    loop {
+        let settings = Settings::load(&storage);
+        lock(&duty).set_profile(settings.power_profile);
+        if rotated_at.elapsed() >= settings.rotation_interval {
+            token = privacy::rotating_token(&identity_key);
+            rotated_at = Instant::now();
+            log::debug!("Rotated advertisement token");
+        }
+        // A new token on an old address could be linked to the old token
+        if addressed != token {
+            match privacy::rotate_address() {
+                Ok(()) => addressed = token,
+                Err(e) => log::error!("Advertising the new token from the old address: {}", e),
+            }
+        }
+
+        let payload = local_payload(&storage, token)?;
+        // Picked up again every cycle so console edits reach the next peer
+        let own = ExchangeProfile::local(&mut storage)?;
+        server.set_profile(&own)?;
+        server.set_status(Status::Idle);
+        // A peer that connected since the last exchange is left to discovery,
+        // which looks after its events
+        server.take_progress();
+        let blocklist = Blocklist::load(&storage)?;
+        let known = KnownPeers::new(&blocklist, &Contacts::load(&storage)?);
+
+        let discovery = Discovery::start(
+            device,
+            &payload,
+            settings.event_code,
+            PeerPolicy::from_settings(&settings),
+            duty.clone(),
+            move |peer| {
+                if blocklist.contains(&peer.address) {
+                    return false;
+                }
+
+                match known.resolve(&peer.payload.token) {
+                    Some(known) if known.blocked => false,
+                    Some(known) => {
+                        log::trace!("Recognised contact {}", known.serial_num);
+                        true
+                    }
+                    None => true,
+                }
+            },
+        )?;
+
+        let remaining = settings
+            .rotation_interval
+            .saturating_sub(rotated_at.elapsed());
+        let peer = match discovery.wait_timeout(server, remaining)? {
+            Some(peer) => peer,
+            // Time to rotate, restart with a new token
+            None => continue,
+        };
+
+        let role = match election::elect(&payload.token, &peer.candidate.payload.token) {
+            Some(role) => role,
+            None => {
+                log::warn!("Token collision with peer, restarting with a new token");
+                token = privacy::rotating_token(&identity_key);
+                rotated_at = Instant::now();
+                continue;
+            }
+        };
+
+        log::info!(
+            "Found peer {} ({:.1} dBm, ~{:.1} m), acting as {:?}",
+            address::format(&peer.candidate.address),
+            peer.smoothed_rssi,
+            peer.distance_m,
+            role
+        );
+        session.handle(Event::PeerFound { role });
+        // `wait_timeout` tore advertising and scanning down
+        session.handle(Event::DiscoveryStopped);
+
+        let address = peer.candidate.address;
+        let keep = |profile: &ExchangeProfile| remember::check(&*storage.lock(), address, profile);
+        let advertiser = Advertiser::new(device);
+        let interval = lock(&duty).timing(Instant::now()).adv_interval;
+        let advertise = || advertiser.start(&payload, interval);
+        let mut received = None;
+        if role == Role::Responder {
+            advertise()?;
+            received = respond(server, &mut session, keep, advertise);
+        }
+
+        // Initiator, or the initiator never showed up. A responder goes on
+        // advertising and serving in case the initiator still connects, and
+        // only one of the two connections is kept.
+        if session.state() == State::Connecting {
+            let address_type = peer.candidate.address_type;
+            let give_way =
+                || election::keep_connection(role, Direction::Incoming) && server.has_peer();
+            let outcome = client.exchange(address, address_type, &own, keep, give_way);
+            received = record(&mut session, outcome);
+            // We gave way, the initiator's connection is waiting to be served
+            if session.state() == State::Connecting {
+                received = respond(server, &mut session, keep, advertise);
+            }
+        }
+        if role == Role::Responder {
+            advertiser.stop()?;
+        }
+
+        if session.state() == State::Blocklisting {
+            // Checked before the commit, so this only fails if NVS itself does.
+            // A failure must not end discovery for the rest of the boot.
+            let kept = match &received {
+                Some(profile) => remember::remember(&mut *storage.lock(), address, profile, now()),
+                // Blocklisting is only reached once the profile arrived
+                None => Ok(()),
+            };
+            // The initiator only keeps our profile once it sees Done
+            if session.role() == Some(Role::Responder) {
+                server.set_status(match kept {
+                    Ok(()) => Status::Done,
+                    Err(_) => Status::Aborted,
+                });
+            }
+            match kept {
+                Ok(()) => session.handle(Event::Remembered),
+                Err(e) => session.handle(Event::Failed(Failure::Storage(e.to_string()))),
+            };
+        }
+        // Nothing more is committed this cycle
+        server.set_accepting(false);
+        if session.state() == State::Disconnecting {
+            await_disconnect(server, &mut session);
+        }
+
+        match session.outcome() {
+            Some(Ok(())) => log::info!("Exchange with peer complete"),
+            Some(Err(failure)) => log::warn!(
+                "Exchange with {} failed: {}",
+                address::format(&peer.candidate.address),
+                failure
+            ),
+            None => {}
+        }
+    }
+}
+
+/// Serves the exchange to the initiator until the session moves past it,
+/// returns the profile the initiator wrote. Leaves the session in
+/// Connecting as initiator, with the commit still armed, if the peer did not
+/// connect in time.
+///
+/// The initiator's acknowledgement is only answered once `keep` agreed to
+/// keep its profile. A connection stops advertising, `advertise` restarts it
+/// whenever the session waits for the initiator to retry after a dropped
+/// connection.
+fn respond<C: Clock>(
+    server: &GattServer,
+    session: &mut Session<C>,
+    keep: impl Fn(&ExchangeProfile) -> Result<(), DeviceInfoError>,
+    advertise: impl Fn() -> anyhow::Result<()>,
+) -> Option<ExchangeProfile> {
+    let mut received = None;
+    // The initiator's connection, others are from before we served
+    let mut connection = None;
+    server.set_accepting(true);
+
+    while let State::Connecting | State::Exchanging | State::Confirming | State::Committing =
+        session.state()
+    {
+        let left = session.time_left().unwrap_or(Duration::ZERO);
+        let event = match server.events().recv_timeout(left) {
+            // Also serves an initiator that connected while we were connecting
+            // ourselves, the session decides whether it is kept
+            Ok(ServerEvent::Connected { handle, .. }) => {
+                connection = Some(handle);
+                Event::Connected(Direction::Incoming)
+            }
+            // Hung up on when discovery stopped
+            Ok(ServerEvent::Disconnected { handle, .. }) if connection != Some(handle) => continue,
+            Ok(ServerEvent::Disconnected { .. }) => {
+                // A retry starts over, nothing from the dropped attempt counts
+                received = None;
+                if session.handle(Event::Disconnected) == State::Connecting {
+                    if let Err(e) = advertise() {
+                        log::error!("Unable to advertise for the retry: {}", e);
+                    }
+                }
+                continue;
+            }
+            Ok(ServerEvent::Received(profile)) => {
+                received = Some(profile);
+                Event::ProfileReceived
+            }
+            // The server only passes these on once the digests matched
+            Ok(ServerEvent::Control(ControlOp::AckOk(_)))
+                if session.state() == State::Confirming =>
+            {
+                // Confirming means the profile arrived
+                match received.as_ref().map_or(Ok(()), &keep) {
+                    Ok(()) => {
+                        server.prepare();
+                        Event::Prepared
+                    }
+                    Err(e) => {
+                        server.set_status(Status::Aborted);
+                        Event::Failed(Failure::Storage(e.to_string()))
+                    }
+                }
+            }
+            // A repeat after a lost write response, answered by the server
+            Ok(ServerEvent::Control(ControlOp::AckOk(_))) => Event::Prepared,
+            Ok(ServerEvent::Control(ControlOp::Commit)) => Event::Committed,
+            Ok(ServerEvent::Control(ControlOp::Abort)) => Event::Failed(Failure::Aborted),
+            Ok(ServerEvent::Control(_)) => Event::Progress,
+            // Frames are only looked at once the timeout is up, a stalled
+            // exchange is given up on after one to two timeouts
+            Err(_) if server.take_progress() && session.state() == State::Exchanging => {
+                Event::Progress
+            }
+            Err(_) => {
+                let dialing = session.role() == Some(Role::Initiator);
+                session.poll();
+                // The initiator did not show up, we connect ourselves
+                if !dialing && session.role() == Some(Role::Initiator) {
+                    break;
+                }
+                continue;
+            }
+        };
+        session.handle(event);
+    }
+
+    // Past this point the session no longer keeps what the peer commits. A
+    // committed exchange stays armed until Done is reported, one we connect
+    // to ourselves in case the initiator shows up after all.
+    if let State::Discovering | State::Disconnecting = session.state() {
+        server.set_accepting(false);
+    }
+    received
+}
+
+/// The client runs a whole exchange in one call and enforces its timeouts
+/// itself, so the session only learns afterwards how it went. Returns the
+/// peer's profile if it went well.
+fn record<C: Clock>(session: &mut Session<C>, outcome: ExchangeOutcome) -> Option<ExchangeProfile> {
+    match outcome {
+        ExchangeOutcome::Exchanged { profile, attempts } => {
+            log::info!("Exchanged profiles after {} attempt(s)", attempts);
+            for event in [
+                Event::Connected(Direction::Outgoing),
+                Event::ProfileReceived,
+                Event::Prepared,
+                Event::Committed,
+                // The client hangs up before returning
+                Event::Disconnected,
+            ] {
+                session.handle(event);
+            }
+            Some(*profile)
+        }
+        // The peer's connection to us is served instead, see `respond`
+        ExchangeOutcome::Failed {
+            error: StepError::GaveWay,
+            ..
+        } => None,
+        ExchangeOutcome::Failed { step, error, .. } => {
+            session.handle(Event::Failed(Failure::Exchange { step, error }));
+            None
+        }
+    }
+}
+
+/// Waits for the initiator to hang up after it got Done
+fn await_disconnect<C: Clock>(server: &GattServer, session: &mut Session<C>) {
+    while session.state() == State::Disconnecting {
+        let left = session.time_left().unwrap_or(Duration::ZERO);
+        match server.events().recv_timeout(left) {
+            Ok(ServerEvent::Disconnected { .. }) => {
+                session.handle(Event::Disconnected);
+            }
+            Ok(_) => {}
+            Err(_) => {
+                session.poll();
+            }
+        }
+    }
+}
+
+/// Seconds since the unix epoch, or since boot if the clock was never set
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,269 @@
+//! Decides how hard the radio works while discovering.
+//!
+//! Advertising and scanning nonstop is what finds peers fastest but also what
+//! empties a badge battery in a few hours. The timing follows the power profile
+//! picked in the settings and backs off the longer no other DapUp device has
+//! been seen. Like the peer table, time is passed in so the schedule can be
+//! stepped through with made up timestamps.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// A DapUp device seen this recently keeps the radio at full speed
+pub const ACTIVE_HOLD: Duration = Duration::from_secs(30);
+
+/// After this long without seeing anyone the radio drops to its slowest pace
+pub const DORMANT_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// How much battery discovery may use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerProfile {
+    /// Always scanning, for badges on USB power
+    Aggressive,
+    Balanced,
+    /// Long pauses while nobody is around, slower to notice the first peer
+    Saver,
+}
+
+impl fmt::Display for PowerProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PowerProfile::Aggressive => write!(f, "aggressive"),
+            PowerProfile::Balanced => write!(f, "balanced"),
+            PowerProfile::Saver => write!(f, "saver"),
+        }
+    }
+}
+
+impl FromStr for PowerProfile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "aggressive" => Ok(PowerProfile::Aggressive),
+            "balanced" => Ok(PowerProfile::Balanced),
+            "saver" => Ok(PowerProfile::Saver),
+            _ => anyhow::bail!("'{}' is not a power profile (aggressive|balanced|saver)", s),
+        }
+    }
+}
+
+/// How recently another DapUp device was seen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activity {
+    /// Within [`ACTIVE_HOLD`]
+    Active,
+    Idle,
+    /// Nothing for [`DORMANT_AFTER`], or never
+    Dormant,
+}
+
+/// Radio parameters for one scan pass. Intervals and windows are in the BLE
+/// unit of 0.625 ms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RadioTiming {
+    pub adv_interval: u16,
+    pub scan_interval: u16,
+    pub scan_window: u16,
+    /// Length of one scan pass
+    pub scan_duration: Duration,
+    /// Radio time off between two scan passes, advertising continues
+    pub scan_pause: Duration,
+}
+
+impl RadioTiming {
+    const fn new(
+        adv_interval: u16,
+        scan_interval: u16,
+        scan_window: u16,
+        scan_pause_ms: u64,
+    ) -> Self {
+        RadioTiming {
+            adv_interval,
+            scan_interval,
+            scan_window,
+            scan_duration: Duration::from_secs(1),
+            scan_pause: Duration::from_millis(scan_pause_ms),
+        }
+    }
+
+    /// Fraction of the time the receiver is on, between 0 and 1
+    pub fn scan_duty(&self) -> f32 {
+        let window = self.scan_window as f32 / self.scan_interval.max(1) as f32;
+        let on = self.scan_duration.as_secs_f32();
+        let total = on + self.scan_pause.as_secs_f32();
+
+        if total == 0.0 {
+            return 0.0;
+        }
+        window.min(1.0) * on / total
+    }
+}
+
+/// Every active timing pauses for less than `peers::MAX_SIGHTING_GAP`,
+/// otherwise a peer could never build up its dwell time
+const fn timing(profile: PowerProfile, activity: Activity) -> RadioTiming {
+    match (profile, activity) {
+        (PowerProfile::Aggressive, Activity::Active) => RadioTiming::new(160, 160, 160, 0),
+        (PowerProfile::Aggressive, Activity::Idle) => RadioTiming::new(160, 160, 80, 0),
+        (PowerProfile::Aggressive, Activity::Dormant) => RadioTiming::new(320, 320, 80, 1000),
+        (PowerProfile::Balanced, Activity::Active) => RadioTiming::new(160, 160, 80, 0),
+        (PowerProfile::Balanced, Activity::Idle) => RadioTiming::new(320, 320, 80, 2000),
+        (PowerProfile::Balanced, Activity::Dormant) => RadioTiming::new(800, 800, 80, 5000),
+        (PowerProfile::Saver, Activity::Active) => RadioTiming::new(320, 320, 80, 1000),
+        (PowerProfile::Saver, Activity::Idle) => RadioTiming::new(800, 800, 48, 5000),
+        (PowerProfile::Saver, Activity::Dormant) => RadioTiming::new(1600, 1600, 48, 15000),
+    }
+}
+
+/// Picks the radio timing from the power profile and recent activity
+#[derive(Debug, Clone)]
+pub struct DutyCycle {
+    profile: PowerProfile,
+    last_activity: Option<Instant>,
+}
+
+impl DutyCycle {
+    pub fn new(profile: PowerProfile) -> Self {
+        DutyCycle {
+            profile,
+            last_activity: None,
+        }
+    }
+
+    pub fn set_profile(&mut self, profile: PowerProfile) {
+        self.profile = profile;
+    }
+
+    /// Call whenever another DapUp device shows up
+    pub fn record_activity(&mut self, now: Instant) {
+        self.last_activity = Some(now);
+    }
+
+    pub fn activity(&self, now: Instant) -> Activity {
+        match self.last_activity {
+            Some(last) => {
+                let since = now.saturating_duration_since(last);
+                if since <= ACTIVE_HOLD {
+                    Activity::Active
+                } else if since <= DORMANT_AFTER {
+                    Activity::Idle
+                } else {
+                    Activity::Dormant
+                }
+            }
+            None => Activity::Dormant,
+        }
+    }
+
+    pub fn timing(&self, now: Instant) -> RadioTiming {
+        timing(self.profile, self.activity(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::peers::MAX_SIGHTING_GAP;
+
+    const PROFILES: [PowerProfile; 3] = [
+        PowerProfile::Aggressive,
+        PowerProfile::Balanced,
+        PowerProfile::Saver,
+    ];
+    const ACTIVITIES: [Activity; 3] = [Activity::Active, Activity::Idle, Activity::Dormant];
+
+    #[test]
+    fn dormant_until_someone_shows_up() {
+        let duty = DutyCycle::new(PowerProfile::Balanced);
+        assert_eq!(duty.activity(Instant::now()), Activity::Dormant);
+    }
+
+    #[test]
+    fn backs_off_while_nobody_is_around() {
+        let start = Instant::now();
+        let mut duty = DutyCycle::new(PowerProfile::Balanced);
+        duty.record_activity(start);
+
+        assert_eq!(duty.activity(start), Activity::Active);
+        assert_eq!(duty.activity(start + ACTIVE_HOLD), Activity::Active);
+        let idle = start + ACTIVE_HOLD + Duration::from_millis(1);
+        assert_eq!(duty.activity(idle), Activity::Idle);
+        assert_eq!(duty.activity(start + DORMANT_AFTER), Activity::Idle);
+        let dormant = start + DORMANT_AFTER + Duration::from_millis(1);
+        assert_eq!(duty.activity(dormant), Activity::Dormant);
+
+        // A single sighting brings the radio straight back to full speed
+        duty.record_activity(dormant);
+        assert_eq!(duty.activity(dormant), Activity::Active);
+        assert_eq!(
+            duty.timing(dormant),
+            timing(PowerProfile::Balanced, Activity::Active)
+        );
+    }
+
+    #[test]
+    fn timing_follows_the_profile() {
+        let now = Instant::now();
+        let mut duty = DutyCycle::new(PowerProfile::Aggressive);
+        duty.record_activity(now);
+        assert_eq!(duty.timing(now).scan_duty(), 1.0);
+
+        duty.set_profile(PowerProfile::Saver);
+        assert_eq!(
+            duty.timing(now),
+            timing(PowerProfile::Saver, Activity::Active)
+        );
+    }
+
+    #[test]
+    fn active_pauses_keep_peers_in_sight() {
+        for profile in PROFILES {
+            let active = timing(profile, Activity::Active);
+            assert!(
+                active.scan_pause < MAX_SIGHTING_GAP,
+                "{} pauses for {:?}",
+                profile,
+                active.scan_pause
+            );
+        }
+    }
+
+    #[test]
+    fn quieter_means_less_radio_time() {
+        for profile in PROFILES {
+            let timings = ACTIVITIES.map(|activity| timing(profile, activity));
+            for pair in timings.windows(2) {
+                assert!(pair[1].scan_duty() <= pair[0].scan_duty(), "{}", profile);
+                assert!(pair[1].adv_interval >= pair[0].adv_interval, "{}", profile);
+            }
+        }
+    }
+
+    #[test]
+    fn windows_fit_their_intervals() {
+        for profile in PROFILES {
+            for activity in ACTIVITIES {
+                let timing = timing(profile, activity);
+                assert!(timing.scan_window <= timing.scan_interval);
+                assert!((0.0..=1.0).contains(&timing.scan_duty()));
+            }
+        }
+    }
+
+    #[test]
+    fn profile_names_round_trip() {
+        for profile in PROFILES {
+            assert_eq!(
+                profile.to_string().parse::<PowerProfile>().unwrap(),
+                profile
+            );
+        }
+        assert_eq!(
+            "SAVER".parse::<PowerProfile>().unwrap(),
+            PowerProfile::Saver
+        );
+        assert!("turbo".parse::<PowerProfile>().is_err());
+    }
+}
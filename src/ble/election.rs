@@ -0,0 +1,115 @@
+//! Decides which of two badges that found each other opens the connection.
+//!
+//! Both sides see each other's advertised token, so each can evaluate the same
+//! rule locally without talking first: the lower token initiates (connects as
+//! GATT client), the higher token responds (keeps advertising as the server).
+//! Should both connect anyway, [`keep_connection`] picks the same link on
+//! both sides.
+
+use super::advertisement::TOKEN_LEN;
+use std::time::Duration;
+
+/// How long a responder waits for the initiator before connecting itself, in
+/// case the initiator never saw our advertisement
+pub const DEFAULT_RESPONDER_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Connects to the peer
+    Initiator,
+    /// Waits for the peer to connect
+    Responder,
+}
+
+/// Which way a connection was opened, from our point of view
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// We connected to the peer
+    Outgoing,
+    /// The peer connected to us
+    Incoming,
+}
+
+/// Elects our role, `None` if both tokens are equal and a new token has to be
+/// picked before trying again
+pub fn elect(own_token: &[u8; TOKEN_LEN], peer_token: &[u8; TOKEN_LEN]) -> Option<Role> {
+    match own_token.cmp(peer_token) {
+        std::cmp::Ordering::Less => Some(Role::Initiator),
+        std::cmp::Ordering::Greater => Some(Role::Responder),
+        std::cmp::Ordering::Equal => None,
+    }
+}
+
+/// Resolves the race where both sides connected anyway, e.g. the responder
+/// gave up waiting and connects itself while the initiator is about to retry.
+/// Both sides keep the connection the `elected` initiator opened and drop the
+/// other one, so they agree on which link survives without exchanging a
+/// message.
+pub fn keep_connection(elected: Role, direction: Direction) -> bool {
+    match elected {
+        Role::Initiator => direction == Direction::Outgoing,
+        Role::Responder => direction == Direction::Incoming,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lower_token_initiates() {
+        let low = [0x10, 0, 0, 0, 0, 0, 0, 0xff];
+        let high = [0x10, 0, 0, 0, 0, 0, 1, 0x00];
+
+        assert_eq!(elect(&low, &high), Some(Role::Initiator));
+        assert_eq!(elect(&high, &low), Some(Role::Responder));
+    }
+
+    #[test]
+    fn both_sides_agree() {
+        let tokens = [
+            [0u8; 8],
+            [0xff; 8],
+            [1, 2, 3, 4, 5, 6, 7, 8],
+            [8, 7, 6, 5, 4, 3, 2, 1],
+        ];
+
+        for a in &tokens {
+            for b in &tokens {
+                match (elect(a, b), elect(b, a)) {
+                    (None, None) => assert_eq!(a, b),
+                    (Some(ours), Some(theirs)) => assert_ne!(ours, theirs),
+                    roles => panic!("{:?} vs {:?} elected {:?}", a, b, roles),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn equal_tokens_elect_nobody() {
+        assert_eq!(elect(&[7; 8], &[7; 8]), None);
+    }
+
+    #[test]
+    fn both_sides_keep_the_initiators_connection() {
+        let low = [1; 8];
+        let high = [2; 8];
+        let ours = elect(&low, &high).unwrap();
+        let theirs = elect(&high, &low).unwrap();
+
+        // Our outgoing connection is their incoming one and the other way round
+        let kept: Vec<_> = [
+            (Direction::Outgoing, Direction::Incoming),
+            (Direction::Incoming, Direction::Outgoing),
+        ]
+        .into_iter()
+        .filter(|&(mine, peers)| {
+            let kept = keep_connection(ours, mine);
+            assert_eq!(kept, keep_connection(theirs, peers));
+            kept
+        })
+        .collect();
+
+        assert_eq!(kept, [(Direction::Outgoing, Direction::Incoming)]);
+    }
+}
@@ -0,0 +1,157 @@
+//! The DapUp GATT service, shared by the server every badge runs and the
+//! client that connects to a peer.
+//!
+//! | Characteristic | Properties   | Value                                      |
+//! |----------------|--------------|--------------------------------------------|
+//! | profile        | notify       | frames from the server to the client       |
+//! | inbox          | write        | frames from the client to the server       |
+//! | control point  | write        | a [`ControlOp`]                            |
+//! | status         | read, notify | a [`Status`], notified on change           |
+//!
+//! Profiles do not fit into one attribute at the default MTU, so both are
+//! split into chunks and sent as frames, see [`crate::protocol`]. Each side
+//! answers the other's data frames with ACK_OK or NACK frames through its own
+//! characteristic.
+//!
+//! An exchange from the client's side: exchange the MTU, subscribe to status
+//! and profile, write [`ControlOp::Begin`] and receive the server's profile,
+//! send ours through the inbox and wait for [`Status::Received`] (or
+//! [`Status::Rejected`]).
+//!
+//! Neither side keeps anything before both know the other holds its profile
+//! intact, a two-phase commit:
+//!
+//! 1. The client makes sure it can keep the server's profile and writes
+//!    [`ControlOp::AckOk`] with its digest. The server checks it against its
+//!    own, makes sure it can keep the client's profile and answers with
+//!    [`Status::Prepared`] and the digest of the client's profile, which the
+//!    client checks against its own. Either side that can not keep the other
+//!    aborts instead.
+//! 2. The client writes [`ControlOp::Commit`]. The server saves the client's
+//!    profile and reports [`Status::Done`], or [`Status::Aborted`] if saving
+//!    failed after all, and the client keeps the server's once Done arrives.
+//!    Both disconnect.
+//!
+//! Before the commit either side drops the exchange on any failure. Only a
+//! connection lost between the server saving and Done reaching the client, or
+//! the client failing to save what it checked it could, leaves one side
+//! without the other.
+//!
+//! The server only takes part in either phase while a responder session is
+//! there to keep the result, otherwise it rejects the writes, see
+//! [`GattServer::set_accepting`](super::server::GattServer::set_accepting).
+
+use super::advertisement::SERVICE_UUID16;
+use crate::utils::exchange::{ProfileDigest, DIGEST_LEN};
+use std::fmt;
+
+/// The primary service reuses the 16 bit UUID advertised in the service data
+pub const SERVICE_UUID: u16 = SERVICE_UUID16;
+
+pub const PROFILE_UUID: &str = "4d9c0001-5f3a-4e7b-9d2c-0fe9f0da9a00";
+pub const INBOX_UUID: &str = "4d9c0002-5f3a-4e7b-9d2c-0fe9f0da9a00";
+pub const CONTROL_POINT_UUID: &str = "4d9c0003-5f3a-4e7b-9d2c-0fe9f0da9a00";
+pub const STATUS_UUID: &str = "4d9c0004-5f3a-4e7b-9d2c-0fe9f0da9a00";
+
+/// Commands the client writes to the control point
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlOp {
+    /// Clears the inbox and makes the server send its profile from the start
+    Begin,
+    /// The client received and verified our profile with this digest (ACK_OK
+    /// in the README), the first phase of the commit
+    AckOk(ProfileDigest),
+    /// The client could not use our profile, it may Begin again
+    Nack,
+    /// The client gives up, nothing from this exchange is kept
+    Abort,
+    /// Both digests matched, keep the exchange
+    Commit,
+}
+
+impl ControlOp {
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0x01] => Some(ControlOp::Begin),
+            [0x02, digest @ ..] => digest.try_into().ok().map(ControlOp::AckOk),
+            [0x03] => Some(ControlOp::Nack),
+            [0x04] => Some(ControlOp::Abort),
+            [0x05] => Some(ControlOp::Commit),
+            _ => None,
+        }
+    }
+
+    pub fn encode(self) -> Vec<u8> {
+        match self {
+            ControlOp::Begin => vec![0x01],
+            ControlOp::AckOk(digest) => with_digest(0x02, &digest),
+            ControlOp::Nack => vec![0x03],
+            ControlOp::Abort => vec![0x04],
+            ControlOp::Commit => vec![0x05],
+        }
+    }
+}
+
+/// Server side progress of an exchange
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Nothing received yet
+    Idle,
+    /// The inbox holds a profile that decoded and validated
+    Received,
+    /// The inbox holds something that is not a valid profile
+    Rejected,
+    /// Both profiles made it across and the client committed, the server keeps
+    /// the client's profile
+    Done,
+    /// The exchange was abandoned by either side
+    Aborted,
+    /// The client acknowledged our profile, this is the digest of the client's
+    /// profile as we received it. Waiting for the commit.
+    Prepared(ProfileDigest),
+}
+
+impl Status {
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0x00] => Some(Status::Idle),
+            [0x01] => Some(Status::Received),
+            [0x02] => Some(Status::Rejected),
+            [0x03] => Some(Status::Done),
+            [0x04] => Some(Status::Aborted),
+            [0x05, digest @ ..] => digest.try_into().ok().map(Status::Prepared),
+            _ => None,
+        }
+    }
+
+    pub fn encode(self) -> Vec<u8> {
+        match self {
+            Status::Idle => vec![0x00],
+            Status::Received => vec![0x01],
+            Status::Rejected => vec![0x02],
+            Status::Done => vec![0x03],
+            Status::Aborted => vec![0x04],
+            Status::Prepared(digest) => with_digest(0x05, &digest),
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Idle => write!(f, "idle"),
+            Status::Received => write!(f, "profile received"),
+            Status::Rejected => write!(f, "profile rejected"),
+            Status::Done => write!(f, "exchange done"),
+            Status::Aborted => write!(f, "exchange aborted"),
+            Status::Prepared(_) => write!(f, "exchange prepared"),
+        }
+    }
+}
+
+fn with_digest(op: u8, digest: &ProfileDigest) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(1 + DIGEST_LEN);
+    bytes.push(op);
+    bytes.extend_from_slice(digest);
+    bytes
+}
@@ -0,0 +1,45 @@
+pub mod advertisement;
+#[cfg(target_os = "espidf")]
+pub mod advertiser;
+#[cfg(target_os = "espidf")]
+pub mod client;
+#[cfg(target_os = "espidf")]
+pub mod discovery;
+pub mod duty_cycle;
+pub mod election;
+pub mod gatt;
+pub mod outcome;
+pub mod peers;
+pub mod privacy;
+pub mod proximity;
+pub mod scan;
+#[cfg(target_os = "espidf")]
+pub mod server;
+pub mod session;
+
+/// `BLEError` only implements `Debug`/`Display`, wrap it for `?` into anyhow
+#[cfg(target_os = "espidf")]
+pub(crate) fn ble_error(error: esp32_nimble::BLEError) -> anyhow::Error {
+    anyhow::anyhow!("BLE error: {:?}", error)
+}
+
+/// ATT MTU of the connection, 23 until an MTU exchange completed
+#[cfg(target_os = "espidf")]
+pub(crate) fn att_mtu(conn_handle: u16) -> u16 {
+    unsafe { esp_idf_svc::sys::ble_att_mtu(conn_handle) }
+}
+
+/// Asks the peer for `CONFIG_BT_NIMBLE_ATT_PREFERRED_MTU`, the outcome shows up
+/// in [`att_mtu`] before the next ATT request is answered
+#[cfg(target_os = "espidf")]
+pub(crate) fn exchange_mtu(conn_handle: u16) {
+    // Fails harmlessly if an exchange already took place on this connection
+    unsafe { esp_idf_svc::sys::ble_gattc_exchange_mtu(conn_handle, None, std::ptr::null_mut()) };
+}
+
+/// Cancels the connection a client is establishing, if any
+#[cfg(target_os = "espidf")]
+pub(crate) fn cancel_connect() {
+    // Fails harmlessly if there is none pending
+    unsafe { esp_idf_svc::sys::ble_gap_conn_cancel() };
+}
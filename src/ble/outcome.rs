@@ -0,0 +1,280 @@
+//! How a client side exchange went, and when it is worth trying again.
+//!
+//! Kept apart from the NimBLE client so the retry rules build and can be
+//! reasoned about on the host.
+
+use super::gatt::Status;
+use crate::utils::exchange::ExchangeProfile;
+use std::fmt;
+use std::time::Duration;
+
+/// The steps of one exchange attempt, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Connect,
+    /// Finding the DapUp service and its characteristics
+    Discover,
+    ReadProfile,
+    WriteProfile,
+    /// Waiting for the peer to report our profile as received
+    AwaitReceived,
+    /// Waiting for the peer to acknowledge our profile's digest
+    AwaitPrepared,
+    /// Writing the commit and waiting for the peer to keep our profile, the
+    /// last step before we keep theirs
+    Commit,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Connect => write!(f, "connect"),
+            Step::Discover => write!(f, "service discovery"),
+            Step::ReadProfile => write!(f, "profile read"),
+            Step::WriteProfile => write!(f, "profile write"),
+            Step::AwaitReceived => write!(f, "waiting for receipt"),
+            Step::AwaitPrepared => write!(f, "waiting for confirmation"),
+            Step::Commit => write!(f, "commit"),
+        }
+    }
+}
+
+/// Why a step failed
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepError {
+    /// The step did not finish within its timeout
+    Timeout,
+    /// The peer does not serve the DapUp service or one of its characteristics
+    NotDapUp,
+    /// The peer's profile did not decode or validate
+    InvalidProfile(String),
+    /// The peer reported a status that ends the exchange
+    Refused(Status),
+    /// The peer acknowledged a profile other than the one we sent
+    DigestMismatch,
+    /// We have no room to keep the peer's profile
+    CannotKeep(String),
+    /// The peer connected to us while we were connecting, its connection is
+    /// the one kept, see [`keep_connection`](super::election::keep_connection)
+    GaveWay,
+    /// NimBLE reported an error, usually a dropped connection
+    Ble(String),
+}
+
+impl fmt::Display for StepError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StepError::Timeout => write!(f, "timed out"),
+            StepError::NotDapUp => write!(f, "peer does not serve the DapUp service"),
+            StepError::InvalidProfile(e) => write!(f, "invalid profile: {}", e),
+            StepError::Refused(status) => write!(f, "peer refused: {}", status),
+            StepError::DigestMismatch => write!(f, "peer acknowledged a different profile"),
+            StepError::CannotKeep(e) => write!(f, "unable to keep the peer's profile: {}", e),
+            StepError::GaveWay => write!(f, "peer connected to us instead"),
+            StepError::Ble(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl StepError {
+    /// Timeouts, radio errors and corrupted profiles may go away on another
+    /// attempt, a peer without the service, one refusing us or our own full
+    /// storage will not. Giving way ends the attempts, the peer's connection
+    /// carries on with the exchange.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            StepError::Timeout
+            | StepError::InvalidProfile(_)
+            | StepError::DigestMismatch
+            | StepError::Ble(_) => true,
+            StepError::NotDapUp
+            | StepError::Refused(_)
+            | StepError::CannotKeep(_)
+            | StepError::GaveWay => false,
+        }
+    }
+}
+
+/// The result of [`exchange`](super::client::GattClient::exchange)
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExchangeOutcome {
+    /// Both profiles made it across and the peer received our commit
+    Exchanged {
+        /// Boxed, a few hundred bytes would otherwise be carried by every
+        /// outcome including the failures
+        profile: Box<ExchangeProfile>,
+        attempts: u8,
+    },
+    /// Gave up, `step` and `error` are from the last attempt
+    Failed {
+        step: Step,
+        error: StepError,
+        attempts: u8,
+    },
+}
+
+impl fmt::Display for ExchangeOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExchangeOutcome::Exchanged { profile, attempts } => write!(
+                f,
+                "exchanged with {} after {} attempt(s)",
+                profile.serial_num, attempts
+            ),
+            ExchangeOutcome::Failed {
+                step,
+                error,
+                attempts,
+            } => write!(
+                f,
+                "{} failed after {} attempt(s): {}",
+                step, attempts, error
+            ),
+        }
+    }
+}
+
+/// Timeouts and retry limits for the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts including the first one
+    pub attempts: u8,
+    pub connect_timeout: Duration,
+    /// Applies to discovery, writes and every frame of a profile transfer
+    pub step_timeout: Duration,
+    /// How long the peer gets to process what we wrote
+    pub confirm_timeout: Duration,
+    /// Wait before the second attempt, doubled for every further one
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            connect_timeout: Duration::from_secs(5),
+            step_timeout: Duration::from_secs(3),
+            confirm_timeout: Duration::from_secs(3),
+            backoff: Duration::from_millis(250),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn timeout(&self, step: Step) -> Duration {
+        match step {
+            Step::Connect => self.connect_timeout,
+            Step::Discover | Step::ReadProfile | Step::WriteProfile | Step::Commit => {
+                self.step_timeout
+            }
+            Step::AwaitReceived | Step::AwaitPrepared => self.confirm_timeout,
+        }
+    }
+
+    /// Longest a single attempt can go without progress, every step running
+    /// into its timeout once
+    pub fn attempt_budget(&self) -> Duration {
+        [
+            Step::Connect,
+            Step::Discover,
+            Step::ReadProfile,
+            Step::WriteProfile,
+            Step::AwaitReceived,
+            Step::AwaitPrepared,
+            Step::Commit,
+        ]
+        .iter()
+        .map(|step| self.timeout(*step))
+        .sum()
+    }
+
+    /// Whether to try again after `attempt` (counting from 1) failed with
+    /// `error`
+    pub fn should_retry(&self, attempt: u8, error: &StepError) -> bool {
+        attempt < self.attempts && error.is_retryable()
+    }
+
+    /// Wait after `attempt` (counting from 1) failed
+    pub fn backoff(&self, attempt: u8) -> Duration {
+        self.backoff * 2u32.saturating_pow(attempt.saturating_sub(1) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_transient_errors_until_the_attempts_run_out() {
+        let policy = RetryPolicy::default();
+
+        for error in [
+            StepError::Timeout,
+            StepError::InvalidProfile("bad".into()),
+            StepError::DigestMismatch,
+            StepError::Ble("disconnected".into()),
+        ] {
+            assert!(policy.should_retry(1, &error), "{}", error);
+            assert!(
+                policy.should_retry(policy.attempts - 1, &error),
+                "{}",
+                error
+            );
+            assert!(!policy.should_retry(policy.attempts, &error), "{}", error);
+        }
+    }
+
+    #[test]
+    fn never_retries_final_errors() {
+        let policy = RetryPolicy::default();
+
+        for error in [
+            StepError::NotDapUp,
+            StepError::Refused(Status::Aborted),
+            StepError::CannotKeep("full".into()),
+            StepError::GaveWay,
+        ] {
+            assert!(!policy.should_retry(1, &error), "{}", error);
+        }
+    }
+
+    #[test]
+    fn a_single_attempt_is_never_retried() {
+        let policy = RetryPolicy {
+            attempts: 1,
+            ..Default::default()
+        };
+        assert!(!policy.should_retry(1, &StepError::Timeout));
+    }
+
+    #[test]
+    fn backoff_doubles_from_the_second_attempt() {
+        let policy = RetryPolicy {
+            backoff: Duration::from_millis(100),
+            ..Default::default()
+        };
+
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn attempt_budget_covers_every_step_once() {
+        let policy = RetryPolicy {
+            connect_timeout: Duration::from_secs(5),
+            step_timeout: Duration::from_secs(3),
+            confirm_timeout: Duration::from_secs(2),
+            ..Default::default()
+        };
+
+        // Connect, four steps with the step timeout and two confirmations
+        assert_eq!(
+            policy.attempt_budget(),
+            Duration::from_secs(5 + 4 * 3 + 2 * 2)
+        );
+        assert_eq!(policy.timeout(Step::Commit), Duration::from_secs(3));
+        assert_eq!(policy.timeout(Step::AwaitPrepared), Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,415 @@
+//! Per-peer state kept across scan results while discovery runs.
+//!
+//! Time is always passed in rather than read from the clock, so a whole
+//! sequence of sightings can be replayed with made up timestamps.
+
+use super::advertisement::TOKEN_LEN;
+use super::proximity::{estimate_distance, RssiFilter};
+use super::scan::PeerCandidate;
+use crate::utils::settings::Settings;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Longest gap between two advertisements that still counts as continuously
+/// seen, badges advertise every 100 ms but scan windows leave holes
+pub const MAX_SIGHTING_GAP: Duration = Duration::from_secs(2);
+
+/// Peers not seen for this long are dropped from the table
+pub const PEER_EXPIRY: Duration = Duration::from_secs(10);
+
+/// Most peers tracked at once, a crowded hall easily has more badges in range
+/// than are worth remembering
+pub const MAX_PEERS: usize = 16;
+
+/// Priority a ready peer gains per second it has been waiting, see [`priority`]
+pub const WAIT_BONUS_DB_PER_SEC: f32 = 1.0;
+
+/// When a peer is close enough, and for long enough, to exchange with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerPolicy {
+    /// Smoothed RSSI (dBm) a peer has to reach
+    pub rssi_threshold: i8,
+    /// How long the peer has to stay above `rssi_threshold`
+    pub dwell_time: Duration,
+}
+
+impl PeerPolicy {
+    pub fn from_settings(settings: &Settings) -> Self {
+        PeerPolicy {
+            rssi_threshold: settings.rssi_threshold,
+            dwell_time: settings.dwell_time,
+        }
+    }
+}
+
+/// A candidate together with what has been learned about it over several
+/// advertisements
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredPeer {
+    /// The most recent advertisement
+    pub candidate: PeerCandidate,
+    pub smoothed_rssi: f32,
+    /// Estimated from `smoothed_rssi`, see [`estimate_distance`]
+    pub distance_m: f32,
+    /// Advertisements seen so far
+    pub samples: u32,
+    /// Whether the peer is currently above the RSSI threshold
+    pub near: bool,
+    /// How long the peer has continuously been near
+    pub dwell: Duration,
+    /// Near for at least the policy's dwell time, an exchange can be attempted
+    pub ready: bool,
+}
+
+#[derive(Debug)]
+struct TrackedPeer {
+    /// Turned down by the table's filter, remembered so the filter only runs
+    /// once per token
+    excluded: bool,
+    filter: RssiFilter,
+    last_seen: Instant,
+    /// Start of the current uninterrupted stretch above the threshold
+    near_since: Option<Instant>,
+    latest: Option<DiscoveredPeer>,
+}
+
+/// Tracks the DapUp devices seen during one discovery.
+///
+/// Entries are keyed by the advertised token rather than the address, so a
+/// peer whose private address rotates mid-discovery stays one entry and
+/// repeated advertisements only update it. `accept` runs once per new token
+/// and turns down peers that should never be connected to, e.g. blocklisted.
+pub struct PeerTable<F> {
+    policy: PeerPolicy,
+    accept: F,
+    peers: HashMap<[u8; TOKEN_LEN], TrackedPeer>,
+}
+
+impl<F: Fn(&PeerCandidate) -> bool> PeerTable<F> {
+    pub fn new(policy: PeerPolicy, accept: F) -> Self {
+        PeerTable {
+            policy,
+            accept,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Records one advertisement of `candidate` seen at `now` and returns its
+    /// updated state, `None` if the peer is excluded.
+    ///
+    /// The dwell restarts whenever the smoothed RSSI drops below the threshold
+    /// or the peer went unseen for more than [`MAX_SIGHTING_GAP`]. Once the
+    /// table holds [`MAX_PEERS`] the least recently seen entry makes room.
+    pub fn observe(&mut self, candidate: PeerCandidate, now: Instant) -> Option<DiscoveredPeer> {
+        let token = candidate.payload.token;
+        if !self.peers.contains_key(&token) {
+            if self.peers.len() >= MAX_PEERS {
+                self.evict_stalest();
+            }
+
+            let excluded = !(self.accept)(&candidate);
+            self.peers.insert(
+                token,
+                TrackedPeer {
+                    excluded,
+                    filter: RssiFilter::default(),
+                    last_seen: now,
+                    near_since: None,
+                    latest: None,
+                },
+            );
+        }
+
+        let peer = self.peers.get_mut(&token)?;
+        if now.saturating_duration_since(peer.last_seen) > MAX_SIGHTING_GAP {
+            peer.near_since = None;
+        }
+        peer.last_seen = now;
+        if peer.excluded {
+            return None;
+        }
+
+        let smoothed_rssi = peer.filter.update(candidate.rssi);
+        let near = peer.filter.is_near(self.policy.rssi_threshold);
+        let dwell = if near {
+            let since = *peer.near_since.get_or_insert(now);
+            now.saturating_duration_since(since)
+        } else {
+            peer.near_since = None;
+            Duration::ZERO
+        };
+
+        let discovered = DiscoveredPeer {
+            distance_m: estimate_distance(smoothed_rssi, candidate.payload.tx_power),
+            smoothed_rssi,
+            samples: peer.filter.samples(),
+            near,
+            dwell,
+            ready: near && dwell >= self.policy.dwell_time,
+            candidate,
+        };
+        peer.latest = Some(discovered.clone());
+        Some(discovered)
+    }
+
+    /// Drops every peer not seen for [`PEER_EXPIRY`], returns how many were
+    /// dropped
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let before = self.peers.len();
+        self.peers
+            .retain(|_, peer| now.saturating_duration_since(peer.last_seen) <= PEER_EXPIRY);
+        before - self.peers.len()
+    }
+
+    /// Every peer ready for an exchange and still in sight at `now`, the one
+    /// to connect to first at the front
+    pub fn queue(&self, now: Instant) -> Vec<&DiscoveredPeer> {
+        let mut ready: Vec<&DiscoveredPeer> = self
+            .peers
+            .values()
+            .filter(|peer| now.saturating_duration_since(peer.last_seen) <= MAX_SIGHTING_GAP)
+            .filter_map(|peer| peer.latest.as_ref())
+            .filter(|peer| peer.ready)
+            .collect();
+
+        ready.sort_by(|a, b| priority(b).total_cmp(&priority(a)));
+        ready
+    }
+
+    /// The peer to connect to next, see [`PeerTable::queue`]
+    pub fn next(&self, now: Instant) -> Option<&DiscoveredPeer> {
+        self.queue(now).into_iter().next()
+    }
+
+    fn evict_stalest(&mut self) {
+        let stalest = self
+            .peers
+            .iter()
+            .min_by_key(|(_, peer)| peer.last_seen)
+            .map(|(token, _)| *token);
+
+        if let Some(token) = stalest {
+            self.peers.remove(&token);
+        }
+    }
+}
+
+/// Ranks ready peers, higher goes first.
+///
+/// The smoothed RSSI in dBm plus [`WAIT_BONUS_DB_PER_SEC`] for every second
+/// the peer has been waiting close by, so someone standing right next to us
+/// wins but nobody waits forever behind a stream of closer passers-by.
+pub fn priority(peer: &DiscoveredPeer) -> f32 {
+    peer.smoothed_rssi + WAIT_BONUS_DB_PER_SEC * peer.dwell.as_secs_f32()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::advertisement::AdvPayload;
+    use crate::ble::proximity::MIN_SAMPLES;
+    use crate::ble::scan::AddressType;
+    use std::cell::Cell;
+
+    const POLICY: PeerPolicy = PeerPolicy {
+        rssi_threshold: -70,
+        dwell_time: Duration::from_secs(3),
+    };
+
+    /// Advertising interval of the simulated peers
+    const STEP: Duration = Duration::from_millis(500);
+
+    fn candidate(id: u8, rssi: i8) -> PeerCandidate {
+        PeerCandidate {
+            address: [0xc0, 0, 0, 0, 0, id],
+            address_type: AddressType::Random,
+            rssi,
+            payload: AdvPayload::new(0, [id; TOKEN_LEN], 0, 0),
+        }
+    }
+
+    fn table() -> PeerTable<impl Fn(&PeerCandidate) -> bool> {
+        PeerTable::new(POLICY, |_: &PeerCandidate| true)
+    }
+
+    /// Sightings of peer `id` every [`STEP`] from `from` on, returns the last
+    /// state and the time of the next sighting
+    fn sight<F: Fn(&PeerCandidate) -> bool>(
+        table: &mut PeerTable<F>,
+        id: u8,
+        rssi: i8,
+        from: Instant,
+        count: u32,
+    ) -> (DiscoveredPeer, Instant) {
+        let mut now = from;
+        let mut last = None;
+        for _ in 0..count {
+            last = table.observe(candidate(id, rssi), now);
+            now += STEP;
+        }
+        (last.unwrap(), now)
+    }
+
+    #[test]
+    fn ready_after_the_dwell_time() {
+        let start = Instant::now();
+        let mut table = table();
+
+        // Near from the sample that completes the filter on
+        let (peer, now) = sight(&mut table, 1, -50, start, MIN_SAMPLES);
+        assert!(peer.near);
+        assert_eq!(peer.dwell, Duration::ZERO);
+        let near_at = now - STEP;
+
+        let (peer, now) = sight(&mut table, 1, -50, now, 5);
+        assert_eq!(peer.dwell, now - STEP - near_at);
+        assert!(!peer.ready);
+
+        let (peer, _) = sight(&mut table, 1, -50, now, 1);
+        assert_eq!(peer.dwell, POLICY.dwell_time);
+        assert!(peer.ready);
+        assert_eq!(table.next(now).map(|p| p.candidate.address[5]), Some(1));
+    }
+
+    #[test]
+    fn dwell_restarts_after_a_gap() {
+        let start = Instant::now();
+        let mut table = table();
+        let (_, now) = sight(&mut table, 1, -50, start, 10);
+
+        // Just within the gap the stretch goes on
+        let now = now - STEP + MAX_SIGHTING_GAP;
+        let (peer, now) = sight(&mut table, 1, -50, now, 1);
+        assert!(peer.ready);
+
+        let now = now - STEP + MAX_SIGHTING_GAP + Duration::from_millis(1);
+        let (peer, _) = sight(&mut table, 1, -50, now, 1);
+        assert!(peer.near);
+        assert_eq!(peer.dwell, Duration::ZERO);
+        assert!(!peer.ready);
+    }
+
+    #[test]
+    fn dwell_restarts_below_the_threshold() {
+        let start = Instant::now();
+        let mut table = table();
+        let (peer, now) = sight(&mut table, 1, -50, start, 10);
+        assert!(peer.ready);
+
+        // A single weak sample only dents the average
+        let (peer, now) = sight(&mut table, 1, -90, now, 1);
+        assert!(peer.ready);
+
+        let (peer, now) = sight(&mut table, 1, -90, now, 3);
+        assert!(!peer.near);
+        assert_eq!(peer.dwell, Duration::ZERO);
+        assert!(table.next(now).is_none());
+
+        let (peer, _) = sight(&mut table, 1, -40, now, 3);
+        assert!(peer.near);
+        assert!(peer.dwell < POLICY.dwell_time);
+        assert!(!peer.ready);
+    }
+
+    #[test]
+    fn far_peers_never_get_ready() {
+        let start = Instant::now();
+        let mut table = table();
+        let (peer, now) = sight(&mut table, 1, -80, start, 20);
+
+        assert!(!peer.near);
+        assert!(!peer.ready);
+        assert!(table.next(now).is_none());
+    }
+
+    #[test]
+    fn peers_expire() {
+        let start = Instant::now();
+        let mut table = table();
+        sight(&mut table, 1, -50, start, 1);
+        let (_, now) = sight(&mut table, 2, -50, start + PEER_EXPIRY, 1);
+
+        assert_eq!(table.expire(start + PEER_EXPIRY), 0);
+        assert_eq!(table.expire(now), 1);
+
+        // Peer 1 starts over, peer 2 kept its samples
+        let (peer, _) = sight(&mut table, 1, -50, now, 1);
+        assert_eq!(peer.samples, 1);
+        let (peer, _) = sight(&mut table, 2, -50, now, 1);
+        assert_eq!(peer.samples, 2);
+    }
+
+    #[test]
+    fn stale_ready_peers_leave_the_queue() {
+        let start = Instant::now();
+        let mut table = table();
+        let (_, now) = sight(&mut table, 1, -50, start, 10);
+        let last_seen = now - STEP;
+
+        assert!(table.next(last_seen + MAX_SIGHTING_GAP).is_some());
+        assert!(table
+            .next(last_seen + MAX_SIGHTING_GAP + Duration::from_millis(1))
+            .is_none());
+    }
+
+    #[test]
+    fn full_table_evicts_the_stalest_peer() {
+        let start = Instant::now();
+        let mut table = table();
+        let mut now = start;
+        for id in 0..MAX_PEERS as u8 {
+            (_, now) = sight(&mut table, id, -50, now, 2);
+        }
+
+        // Seeing peer 0 again makes peer 1 the stalest
+        (_, now) = sight(&mut table, 0, -50, now, 1);
+        (_, now) = sight(&mut table, MAX_PEERS as u8, -50, now, 1);
+        assert_eq!(table.peers.len(), MAX_PEERS);
+
+        let (peer, now) = sight(&mut table, 0, -50, now, 1);
+        assert_eq!(peer.samples, 4);
+        let (peer, _) = sight(&mut table, 1, -50, now, 1);
+        assert_eq!(peer.samples, 1);
+    }
+
+    #[test]
+    fn excluded_peers_are_filtered_once() {
+        let calls = Cell::new(0);
+        let mut table = PeerTable::new(POLICY, |candidate: &PeerCandidate| {
+            calls.set(calls.get() + 1);
+            candidate.address[5] != 1
+        });
+
+        let now = Instant::now();
+        for _ in 0..5 {
+            assert!(table.observe(candidate(1, -40), now).is_none());
+        }
+        assert!(table.observe(candidate(2, -40), now).is_some());
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn closest_and_longest_waiting_go_first() {
+        let start = Instant::now();
+        let mut table = table();
+        let mut now = start;
+        for _ in 0..10 {
+            table.observe(candidate(1, -60), now);
+            table.observe(candidate(2, -45), now);
+            now += STEP;
+        }
+        let now = now - STEP;
+
+        let order: Vec<u8> = table
+            .queue(now)
+            .iter()
+            .map(|peer| peer.candidate.address[5])
+            .collect();
+        assert_eq!(order, vec![2, 1]);
+
+        // Peer 1 waited 20 s longer, worth more than the 15 dB difference
+        let mut waited = table.queue(now)[1].clone();
+        waited.dwell += Duration::from_secs(20);
+        assert!(priority(&waited) > priority(table.queue(now)[0]));
+    }
+}
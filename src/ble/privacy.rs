@@ -0,0 +1,256 @@
+//! Rotating advertisement tokens that only known peers can link together.
+//!
+//! A token is 4 random bytes followed by the first 4 bytes of
+//! `HMAC-SHA256(identity key, "DapUp token" | random)`, the same construction
+//! as a BLE resolvable private address. Anyone can check a token against every
+//! identity key they hold, without the key a fresh token is indistinguishable
+//! from random. The address is a non-resolvable private one that changes with
+//! every token (see `rotate_address`), so nothing in the advertisement stays
+//! constant and no old address can be linked to a new token.
+//!
+//! The address is deliberately not a BLE resolvable private address. An RPA
+//! is resolved with an IRK that peers learn by bonding, which badges never do,
+//! and NimBLE only generates RPAs from its own IRK on its own timer, out of
+//! step with the token. Anyone able to resolve such an address could resolve
+//! the token already, so the token is the resolvable part and the address
+//! only has to change together with it.
+
+use super::advertisement::TOKEN_LEN;
+use crate::utils::blocklist::Blocklist;
+use crate::utils::contacts::Contacts;
+use crate::utils::identity::IdentityKey;
+use crate::utils::random;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Random half of the token
+const PRAND_LEN: usize = 4;
+
+/// Keeps these hashes apart from anything else keyed with the identity key
+const DOMAIN: &[u8] = b"DapUp token";
+
+fn token_hash(key: &IdentityKey, prand: &[u8]) -> [u8; TOKEN_LEN - PRAND_LEN] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(DOMAIN);
+    mac.update(prand);
+
+    let mut hash = [0u8; TOKEN_LEN - PRAND_LEN];
+    hash.copy_from_slice(&mac.finalize().into_bytes()[..TOKEN_LEN - PRAND_LEN]);
+    hash
+}
+
+/// Derives the token for the given random half
+pub fn token_from_prand(key: &IdentityKey, prand: [u8; PRAND_LEN]) -> [u8; TOKEN_LEN] {
+    let mut token = [0u8; TOKEN_LEN];
+    token[..PRAND_LEN].copy_from_slice(&prand);
+    token[PRAND_LEN..].copy_from_slice(&token_hash(key, &prand));
+    token
+}
+
+/// A fresh token for the next rotation period
+pub fn rotating_token(key: &IdentityKey) -> [u8; TOKEN_LEN] {
+    let mut prand = [0u8; PRAND_LEN];
+    random::fill(&mut prand);
+    token_from_prand(key, prand)
+}
+
+/// Whether `token` was derived from `key`
+pub fn resolves(key: &IdentityKey, token: &[u8; TOKEN_LEN]) -> bool {
+    let expected = token_hash(key, &token[..PRAND_LEN]);
+
+    // Constant time so a timing side channel can not narrow down the hash
+    expected
+        .iter()
+        .zip(&token[PRAND_LEN..])
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// A device from the blocklist or contacts whose identity key we know
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnownPeer {
+    pub serial_num: String,
+    pub identity_key: IdentityKey,
+    /// It is on the blocklist, not just a saved contact
+    pub blocked: bool,
+}
+
+/// Everyone whose rotating tokens we can resolve
+#[derive(Debug, Default, Clone)]
+pub struct KnownPeers {
+    peers: Vec<KnownPeer>,
+}
+
+impl KnownPeers {
+    /// Collects every entry that came with an identity key, blocklist entries
+    /// first so a blocked contact resolves as blocked
+    pub fn new(blocklist: &Blocklist, contacts: &Contacts) -> Self {
+        let blocked = blocklist.entries.iter().filter_map(|entry| {
+            entry.identity_key.map(|identity_key| KnownPeer {
+                serial_num: entry.serial_num.clone(),
+                identity_key,
+                blocked: true,
+            })
+        });
+        let saved = contacts.entries.iter().filter_map(|contact| {
+            contact.identity_key.map(|identity_key| KnownPeer {
+                serial_num: contact.serial_num.clone(),
+                identity_key,
+                blocked: false,
+            })
+        });
+
+        KnownPeers {
+            peers: blocked.chain(saved).collect(),
+        }
+    }
+
+    /// Finds the peer that advertised `token`, `None` for strangers
+    pub fn resolve(&self, token: &[u8; TOKEN_LEN]) -> Option<&KnownPeer> {
+        self.peers
+            .iter()
+            .find(|peer| resolves(&peer.identity_key, token))
+    }
+}
+
+/// A fresh non-resolvable private address, most significant byte first
+pub fn private_address() -> [u8; 6] {
+    loop {
+        let mut address = [0u8; 6];
+        random::fill(&mut address);
+        // The two most significant bits clear mark it as non-resolvable
+        address[0] &= 0x3f;
+
+        // The random part must not be all zeros or all ones
+        if address != [0; 6] && address != [0x3f, 0xff, 0xff, 0xff, 0xff, 0xff] {
+            return address;
+        }
+    }
+}
+
+/// Makes NimBLE advertise, scan and connect from the random address set by
+/// [`rotate_address`], starting with a fresh one
+#[cfg(target_os = "espidf")]
+pub fn enable_private_address(device: &mut esp32_nimble::BLEDevice) -> anyhow::Result<()> {
+    rotate_address()?;
+    device.set_own_addr_type(esp32_nimble::enums::OwnAddrType::Random);
+    Ok(())
+}
+
+/// Switches to a new [`private_address`]. Call it together with every token
+/// change, while neither advertising, scanning nor connected, the controller
+/// refuses a new address otherwise.
+#[cfg(target_os = "espidf")]
+pub fn rotate_address() -> anyhow::Result<()> {
+    // NimBLE keeps addresses least significant byte first
+    let mut address = private_address();
+    address.reverse();
+
+    let rc = unsafe { esp_idf_svc::sys::ble_hs_id_set_rnd(address.as_ptr()) };
+    if rc != 0 {
+        anyhow::bail!("Unable to set the private address: NimBLE error {}", rc);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::blocklist::BlocklistEntry;
+    use crate::utils::contact::ContactCard;
+    use crate::utils::contacts::Contact;
+
+    const KEY: IdentityKey = [0x11; 16];
+    const OTHER_KEY: IdentityKey = [0x22; 16];
+
+    fn blocked(address: u8, serial_num: &str, identity_key: Option<IdentityKey>) -> BlocklistEntry {
+        BlocklistEntry {
+            address: [address; 6],
+            serial_num: serial_num.into(),
+            device_name: "dap-up".into(),
+            card: ContactCard::default(),
+            added_at: 0,
+            identity_key,
+        }
+    }
+
+    fn contact(serial_num: &str, identity_key: Option<IdentityKey>) -> Contact {
+        Contact {
+            serial_num: serial_num.into(),
+            card: ContactCard::default(),
+            received_at: 0,
+            identity_key,
+        }
+    }
+
+    #[test]
+    fn a_token_resolves_with_its_key_only() {
+        for prand in [[0; 4], [1, 2, 3, 4], [0xff; 4]] {
+            let token = token_from_prand(&KEY, prand);
+
+            assert_eq!(token[..PRAND_LEN], prand);
+            assert_eq!(token, token_from_prand(&KEY, prand));
+            assert!(resolves(&KEY, &token));
+            assert!(!resolves(&OTHER_KEY, &token));
+        }
+    }
+
+    #[test]
+    fn a_tampered_token_does_not_resolve() {
+        let token = token_from_prand(&KEY, [1, 2, 3, 4]);
+
+        for i in 0..TOKEN_LEN {
+            let mut tampered = token;
+            tampered[i] ^= 0x01;
+            assert!(!resolves(&KEY, &tampered), "flip in byte {}", i);
+        }
+    }
+
+    #[test]
+    fn rotation_changes_the_token_and_the_address() {
+        let (first, second) = (rotating_token(&KEY), rotating_token(&KEY));
+        assert_ne!(first, second);
+        assert!(resolves(&KEY, &first) && resolves(&KEY, &second));
+
+        let (first, second) = (private_address(), private_address());
+        assert_ne!(first, second);
+        for address in [first, second] {
+            assert_eq!(
+                address[0] & 0xc0,
+                0,
+                "{:02x?} is not non-resolvable",
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn known_peers_resolve_to_their_entry() {
+        let mut blocklist = Blocklist::default();
+        blocklist.insert(blocked(1, "DU-1", Some(KEY)));
+        blocklist.insert(blocked(3, "DU-3", None));
+        let contacts = Contacts {
+            entries: vec![contact("DU-2", Some(OTHER_KEY)), contact("DU-4", None)],
+        };
+        let known = KnownPeers::new(&blocklist, &contacts);
+
+        let peer = known.resolve(&rotating_token(&KEY)).unwrap();
+        assert_eq!((peer.serial_num.as_str(), peer.blocked), ("DU-1", true));
+        let peer = known.resolve(&rotating_token(&OTHER_KEY)).unwrap();
+        assert_eq!((peer.serial_num.as_str(), peer.blocked), ("DU-2", false));
+        assert_eq!(known.resolve(&rotating_token(&[0x33; 16])), None);
+    }
+
+    #[test]
+    fn a_blocked_contact_resolves_as_blocked() {
+        let mut blocklist = Blocklist::default();
+        blocklist.insert(blocked(1, "DU-1", Some(KEY)));
+        let contacts = Contacts {
+            entries: vec![contact("DU-1", Some(KEY))],
+        };
+
+        let known = KnownPeers::new(&blocklist, &contacts);
+        assert!(known.resolve(&rotating_token(&KEY)).unwrap().blocked);
+    }
+}
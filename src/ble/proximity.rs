@@ -0,0 +1,151 @@
+//! Turns noisy per-advert RSSI readings into something a proximity decision
+//! can be based on.
+//!
+//! A single RSSI sample swings by 10 dB or more as people move and bodies
+//! block the antenna, so every peer gets its own exponential moving average
+//! and the threshold is only checked once a few samples went into it.
+
+/// Weight of a new sample in the moving average, lower is smoother but slower
+pub const DEFAULT_ALPHA: f32 = 0.3;
+
+/// Samples needed before a smoothed value is trusted
+pub const MIN_SAMPLES: u32 = 3;
+
+/// Typical RSSI of an ESP32 at 0 dBm measured 1 m away, used when the peer
+/// does not advertise a calibrated value
+pub const DEFAULT_TX_POWER_AT_1M: i8 = -59;
+
+/// Free space is 2.0, indoors with people around is usually worse
+pub const PATH_LOSS_EXPONENT: f32 = 2.0;
+
+/// Exponential moving average over the RSSI of one peer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RssiFilter {
+    alpha: f32,
+    value: Option<f32>,
+    samples: u32,
+}
+
+impl Default for RssiFilter {
+    fn default() -> Self {
+        RssiFilter::new(DEFAULT_ALPHA)
+    }
+}
+
+impl RssiFilter {
+    /// `alpha` is clamped to `(0, 1]`
+    pub fn new(alpha: f32) -> Self {
+        RssiFilter {
+            alpha: alpha.clamp(f32::EPSILON, 1.0),
+            value: None,
+            samples: 0,
+        }
+    }
+
+    /// Feeds one sample and returns the smoothed value, the first sample is
+    /// taken as is
+    pub fn update(&mut self, rssi: i8) -> f32 {
+        let rssi = rssi as f32;
+        let value = match self.value {
+            Some(value) => value + self.alpha * (rssi - value),
+            None => rssi,
+        };
+
+        self.value = Some(value);
+        self.samples = self.samples.saturating_add(1);
+        value
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    /// `true` once enough samples are in and the average reaches
+    /// `threshold_dbm`
+    pub fn is_near(&self, threshold_dbm: i8) -> bool {
+        match self.value {
+            Some(value) => self.samples >= MIN_SAMPLES && value >= threshold_dbm as f32,
+            None => false,
+        }
+    }
+}
+
+/// Rough distance in metres from the log-distance path loss model.
+///
+/// Only good for telling "arm's length" from "across the room", walls and
+/// bodies easily double the result.
+pub fn estimate_distance(rssi: f32, tx_power_at_1m: Option<i8>) -> f32 {
+    let tx_power = tx_power_at_1m.unwrap_or(DEFAULT_TX_POWER_AT_1M) as f32;
+    10f32.powf((tx_power - rssi) / (10.0 * PATH_LOSS_EXPONENT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-3,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn first_sample_is_taken_as_is() {
+        let mut filter = RssiFilter::new(0.5);
+        assert_close(filter.update(-70), -70.0);
+        assert_close(filter.update(-50), -60.0);
+        assert_close(filter.update(-50), -55.0);
+        assert_eq!(filter.samples(), 3);
+    }
+
+    #[test]
+    fn alpha_is_clamped() {
+        let mut filter = RssiFilter::new(3.0);
+        filter.update(-70);
+        assert_close(filter.update(-50), -50.0);
+    }
+
+    #[test]
+    fn is_near_waits_for_enough_samples() {
+        let mut filter = RssiFilter::default();
+        assert!(!filter.is_near(-70));
+
+        for _ in 1..MIN_SAMPLES {
+            filter.update(-40);
+            assert!(!filter.is_near(-70));
+        }
+
+        filter.update(-40);
+        assert!(filter.is_near(-70));
+    }
+
+    #[test]
+    fn is_near_compares_the_average() {
+        let mut filter = RssiFilter::default();
+        for _ in 0..MIN_SAMPLES {
+            filter.update(-70);
+        }
+        assert!(filter.is_near(-70));
+        assert!(!filter.is_near(-69));
+
+        // One strong sample is not enough to pull a far peer in
+        filter.update(-40);
+        assert!(!filter.is_near(-60));
+    }
+
+    #[test]
+    fn distance_is_one_metre_at_the_calibrated_rssi() {
+        assert_close(estimate_distance(-59.0, None), 1.0);
+        assert_close(estimate_distance(-65.0, Some(-65)), 1.0);
+    }
+
+    #[test]
+    fn distance_grows_tenfold_every_20_db() {
+        assert_close(estimate_distance(-79.0, None), 10.0);
+        assert_close(estimate_distance(-39.0, None), 0.1);
+        assert_close(estimate_distance(-85.0, Some(-45)), 100.0);
+    }
+}
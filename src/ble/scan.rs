@@ -0,0 +1,243 @@
+use super::advertisement::{flags, AdvError, AdvPayload, SERVICE_UUID16};
+use std::fmt;
+
+/// How the advertiser's address should be interpreted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressType {
+    Public,
+    Random,
+    PublicId,
+    RandomId,
+}
+
+/// The parts of a scan result the classifier looks at, independent of NimBLE
+/// so captured dumps (see `src/test.txt`) can be replayed on the host
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawAdvertisement {
+    /// Most significant byte first, as NimBLE prints it
+    pub address: [u8; 6],
+    pub address_type: AddressType,
+    pub rssi: i8,
+    /// Every service data entry as (16 bit UUID, data)
+    pub service_data: Vec<(u16, Vec<u8>)>,
+}
+
+/// A nearby device that advertised a valid DapUp payload
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerCandidate {
+    pub address: [u8; 6],
+    pub address_type: AddressType,
+    pub rssi: i8,
+    pub payload: AdvPayload,
+}
+
+/// Why a scan result is not a usable peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// No 0xfe9f service data, some other device
+    Foreign,
+    /// DapUp service data that does not decode
+    Malformed(AdvError),
+    /// A DapUp device at a different event
+    OtherEvent(u16),
+    /// A DapUp device that is not taking exchanges right now
+    NotAccepting,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Foreign => write!(f, "not a DapUp device"),
+            Rejection::Malformed(e) => write!(f, "malformed DapUp payload: {}", e),
+            Rejection::OtherEvent(code) => write!(f, "DapUp device of event {}", code),
+            Rejection::NotAccepting => write!(f, "DapUp device not accepting exchanges"),
+        }
+    }
+}
+
+/// Turns a raw scan result into a [`PeerCandidate`].
+///
+/// `event_code` is ours, a peer is accepted if either side is 0 ("any event")
+/// or both codes match, and only while it advertises [`flags::ACCEPTING`].
+pub fn classify(raw: &RawAdvertisement, event_code: u16) -> Result<PeerCandidate, Rejection> {
+    let data = raw
+        .service_data
+        .iter()
+        .find(|(uuid, _)| *uuid == SERVICE_UUID16)
+        .map(|(_, data)| data)
+        .ok_or(Rejection::Foreign)?;
+
+    let payload = AdvPayload::decode(data).map_err(Rejection::Malformed)?;
+
+    if event_code != 0 && payload.event_code != 0 && payload.event_code != event_code {
+        return Err(Rejection::OtherEvent(payload.event_code));
+    }
+
+    if payload.flags & flags::ACCEPTING == 0 {
+        return Err(Rejection::NotAccepting);
+    }
+
+    Ok(PeerCandidate {
+        address: raw.address,
+        address_type: raw.address_type,
+        rssi: raw.rssi,
+        payload,
+    })
+}
+
+#[cfg(target_os = "espidf")]
+impl RawAdvertisement {
+    pub fn from_nimble(
+        device: &esp32_nimble::BLEAdvertisedDevice,
+        data: &esp32_nimble::BLEAdvertisedData<&[u8]>,
+    ) -> Self {
+        use esp32_nimble::utilities::BleUuid;
+        use esp32_nimble::BLEAddressType;
+
+        let address = device.addr();
+        let address_type = match address.addr_type() {
+            BLEAddressType::Public => AddressType::Public,
+            BLEAddressType::Random => AddressType::Random,
+            BLEAddressType::PublicID => AddressType::PublicId,
+            BLEAddressType::RandomID => AddressType::RandomId,
+        };
+
+        let service_data = data
+            .service_data_list()
+            .filter_map(|entry| match entry.uuid {
+                BleUuid::Uuid16(uuid) => Some((uuid, entry.service_data.to_vec())),
+                _ => None,
+            })
+            .collect();
+
+        RawAdvertisement {
+            address: address.as_be_bytes(),
+            address_type,
+            rssi: device.rssi().clamp(i8::MIN as i32, i8::MAX as i32) as i8,
+            service_data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::address;
+
+    /// A scan result as NimBLE printed it on a badge
+    const DUMP: &str = include_str!("../test.txt");
+
+    /// What follows `label` in the dump, up to the first of `ends`
+    fn field<'a>(dump: &'a str, label: &str, ends: &[char]) -> &'a str {
+        let rest = &dump[dump.find(label).expect(label) + label.len()..];
+        rest[..rest.find(ends).unwrap_or(rest.len())].trim()
+    }
+
+    fn parse_dump(dump: &str) -> RawAdvertisement {
+        let address = field(dump, "addr: ", &['(']);
+        let address_type = match field(dump, &format!("{}(", address), &[')']) {
+            "random" => AddressType::Random,
+            _ => AddressType::Public,
+        };
+        let uuid = field(dump, "uuid: 0x", &[',']);
+        let data = field(dump, "service_data: [", &[']'])
+            .split(',')
+            .map(|byte| byte.trim().parse().unwrap())
+            .collect();
+
+        RawAdvertisement {
+            address: address::parse(address).unwrap(),
+            address_type,
+            rssi: field(dump, "rssi: ", &[',', '\n']).parse().unwrap(),
+            service_data: vec![(u16::from_str_radix(uuid, 16).unwrap(), data)],
+        }
+    }
+
+    fn raw(service_data: Vec<(u16, Vec<u8>)>) -> RawAdvertisement {
+        RawAdvertisement {
+            address: [0xc0, 1, 2, 3, 4, 5],
+            address_type: AddressType::Random,
+            rssi: -60,
+            service_data,
+        }
+    }
+
+    fn payload(event_code: u16) -> Vec<u8> {
+        AdvPayload::new(flags::ACCEPTING, [7; 8], 1, event_code).encode()
+    }
+
+    #[test]
+    fn captured_dump_is_rejected_as_version_0() {
+        let raw = parse_dump(DUMP);
+        assert_eq!(raw.address, [0x57, 0xd9, 0x1e, 0x8f, 0x8c, 0x34]);
+        assert_eq!(raw.address_type, AddressType::Random);
+        assert_eq!(raw.rssi, -56);
+        assert_eq!(raw.service_data[0].0, SERVICE_UUID16);
+        assert_eq!(raw.service_data[0].1, vec![0; 20]);
+
+        assert_eq!(
+            classify(&raw, 0),
+            Err(Rejection::Malformed(AdvError::UnsupportedVersion(0)))
+        );
+    }
+
+    #[test]
+    fn accepts_a_valid_payload() {
+        let candidate = classify(&raw(vec![(SERVICE_UUID16, payload(0))]), 0).unwrap();
+        assert_eq!(candidate.address, [0xc0, 1, 2, 3, 4, 5]);
+        assert_eq!(candidate.rssi, -60);
+        assert_eq!(candidate.payload.token, [7; 8]);
+    }
+
+    #[test]
+    fn finds_our_entry_among_others() {
+        let raw = raw(vec![(0x180f, vec![100]), (SERVICE_UUID16, payload(0))]);
+        assert!(classify(&raw, 0).is_ok());
+    }
+
+    #[test]
+    fn rejects_foreign_devices() {
+        assert_eq!(classify(&raw(vec![]), 0), Err(Rejection::Foreign));
+        assert_eq!(
+            classify(&raw(vec![(0x180f, payload(0))]), 0),
+            Err(Rejection::Foreign)
+        );
+    }
+
+    #[test]
+    fn rejects_short_payloads() {
+        let mut short = payload(0);
+        short.truncate(5);
+        assert_eq!(
+            classify(&raw(vec![(SERVICE_UUID16, short)]), 0),
+            Err(Rejection::Malformed(AdvError::TooShort(5)))
+        );
+        assert_eq!(
+            classify(&raw(vec![(SERVICE_UUID16, vec![])]), 0),
+            Err(Rejection::Malformed(AdvError::TooShort(0)))
+        );
+    }
+
+    #[test]
+    fn event_codes_must_match_unless_either_is_any() {
+        let ours = raw(vec![(SERVICE_UUID16, payload(0x1234))]);
+        let any = raw(vec![(SERVICE_UUID16, payload(0))]);
+
+        assert!(classify(&ours, 0x1234).is_ok());
+        assert!(classify(&ours, 0).is_ok());
+        assert!(classify(&any, 0x1234).is_ok());
+        assert_eq!(classify(&ours, 0x4321), Err(Rejection::OtherEvent(0x1234)));
+    }
+
+    #[test]
+    fn skips_devices_that_are_not_accepting() {
+        let unregistered = AdvPayload::new(flags::PROVISIONED, [7; 8], 1, 0).encode();
+        assert_eq!(
+            classify(&raw(vec![(SERVICE_UUID16, unregistered)]), 0),
+            Err(Rejection::NotAccepting)
+        );
+
+        let registered = AdvPayload::new(flags::ACCEPTING | flags::PROVISIONED, [7; 8], 1, 0);
+        assert!(classify(&raw(vec![(SERVICE_UUID16, registered.encode())]), 0).is_ok());
+    }
+}
@@ -0,0 +1,382 @@
+use super::gatt::{self, ControlOp, Status};
+use crate::protocol::chunk::{DEFAULT_ATT_MTU, MAX_TRANSFER_LEN};
+use crate::protocol::link::{Link, LinkError, LinkEvent};
+use crate::utils::exchange::{self, ExchangeProfile, ProfileDigest};
+use esp32_nimble::utilities::mutex::Mutex;
+use esp32_nimble::utilities::BleUuid;
+use esp32_nimble::{BLECharacteristic, BLEDevice, NimbleProperties};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+
+/// What happened on the server, in the order it happened.
+///
+/// None of these are ever dropped, a lost commit would leave the client with
+/// our profile and us without theirs. The queue is unbounded so the NimBLE
+/// host task never blocks on it, and only connections add to it while no
+/// exchange is being served. Frames only set a flag, see
+/// [`GattServer::take_progress`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerEvent {
+    /// A peer connected, address most significant byte first, `handle`
+    /// identifies the connection
+    Connected {
+        address: [u8; 6],
+        handle: u16,
+    },
+    Disconnected {
+        address: [u8; 6],
+        handle: u16,
+    },
+    /// The peer wrote a profile that decoded and validated
+    Received(ExchangeProfile),
+    /// The peer wrote an accepted control point command
+    Control(ControlOp),
+}
+
+/// Both directions of the profile transfer with the connected peer
+struct Transfer {
+    /// Our sealed profile, sent again after every [`ControlOp::Begin`]
+    profile: Vec<u8>,
+    link: Link,
+    /// Digest of the client's profile once it arrived intact
+    received: Option<ProfileDigest>,
+}
+
+/// The DapUp GATT service, see [`gatt`] for the characteristics
+pub struct GattServer {
+    transfer: Arc<std::sync::Mutex<Transfer>>,
+    status: Arc<Mutex<BLECharacteristic>>,
+    current: Arc<std::sync::Mutex<Status>>,
+    /// Whether an exchange is being served, see [`GattServer::set_accepting`]
+    accepting: Arc<AtomicBool>,
+    /// Set by every frame of either profile
+    progress: Arc<AtomicBool>,
+    /// Peers connected to us, see [`GattServer::has_peer`]
+    peers: Arc<AtomicUsize>,
+    events: Receiver<ServerEvent>,
+}
+
+impl GattServer {
+    /// Registers the service, it is served as soon as advertising starts
+    pub fn start(device: &mut BLEDevice) -> anyhow::Result<Self> {
+        let (sender, events) = mpsc::channel();
+        let current = Arc::new(std::sync::Mutex::new(Status::Idle));
+        let accepting = Arc::new(AtomicBool::new(false));
+        let progress = Arc::new(AtomicBool::new(false));
+        let peers = Arc::new(AtomicUsize::new(0));
+        let transfer = Arc::new(std::sync::Mutex::new(Transfer {
+            profile: Vec::new(),
+            link: Link::new(DEFAULT_ATT_MTU),
+            received: None,
+        }));
+
+        let server = device.get_server();
+        // Discovery decides when to advertise again
+        server.advertise_on_disconnect(false);
+        {
+            let sender = sender.clone();
+            let peers = peers.clone();
+            server.on_connect(move |_, desc| {
+                send(
+                    &sender,
+                    ServerEvent::Connected {
+                        address: desc.address().as_be_bytes(),
+                        handle: desc.conn_handle(),
+                    },
+                );
+                // Only counted once the event is queued
+                peers.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        {
+            let sender = sender.clone();
+            let peers = peers.clone();
+            server.on_disconnect(move |desc, _| {
+                peers.fetch_sub(1, Ordering::SeqCst);
+                send(
+                    &sender,
+                    ServerEvent::Disconnected {
+                        address: desc.address().as_be_bytes(),
+                        handle: desc.conn_handle(),
+                    },
+                );
+            });
+        }
+
+        let service = server.create_service(BleUuid::from_uuid16(gatt::SERVICE_UUID));
+        let mut service = service.lock();
+        let profile =
+            service.create_characteristic(uuid(gatt::PROFILE_UUID)?, NimbleProperties::NOTIFY);
+        let inbox = service.create_characteristic(uuid(gatt::INBOX_UUID)?, NimbleProperties::WRITE);
+        let control =
+            service.create_characteristic(uuid(gatt::CONTROL_POINT_UUID)?, NimbleProperties::WRITE);
+        let status = service.create_characteristic(
+            uuid(gatt::STATUS_UUID)?,
+            NimbleProperties::READ | NimbleProperties::NOTIFY,
+        );
+        status.lock().set_value(&Status::Idle.encode());
+
+        {
+            let sender = sender.clone();
+            let profile = profile.clone();
+            let status = status.clone();
+            let current = current.clone();
+            let transfer = transfer.clone();
+            let accepting = accepting.clone();
+            let progress = progress.clone();
+            inbox.lock().on_write(move |args| {
+                let action = lock(&transfer).link.on_receive(args.recv_data());
+                if let Some(bytes) = action.transmit {
+                    profile.lock().set_value(&bytes).notify();
+                }
+                progress.store(true, Ordering::Relaxed);
+
+                let next = match action.event {
+                    Some(LinkEvent::Received(bytes)) => match ExchangeProfile::decode(&bytes) {
+                        Ok(profile) => {
+                            lock(&transfer).received = Some(exchange::digest(&bytes));
+                            if accepting.load(Ordering::SeqCst) {
+                                send(&sender, ServerEvent::Received(profile));
+                            }
+                            Status::Received
+                        }
+                        Err(e) => {
+                            log::warn!("Rejected profile written by peer: {}", e);
+                            Status::Rejected
+                        }
+                    },
+                    Some(LinkEvent::Sent) => {
+                        log::debug!("Profile sent to peer");
+                        return;
+                    }
+                    Some(LinkEvent::Failed(e @ LinkError::Chunk(_))) => {
+                        log::warn!("Rejected profile written by peer: {}", e);
+                        Status::Rejected
+                    }
+                    Some(LinkEvent::Failed(e)) => {
+                        log::warn!("Profile transfer failed: {}", e);
+                        Status::Aborted
+                    }
+                    None => return,
+                };
+                set_status(&status, &mut lock(&current), next);
+            });
+        }
+
+        {
+            let status = status.clone();
+            let current = current.clone();
+            let transfer = transfer.clone();
+            let accepting = accepting.clone();
+            control.lock().on_write(move |args| {
+                let op = match ControlOp::decode(args.recv_data()) {
+                    Some(op) => op,
+                    None => {
+                        args.reject();
+                        return;
+                    }
+                };
+
+                // Held until the new status is set, `set_accepting` takes it
+                // too so the commit can not be disarmed halfway
+                let mut current = lock(&current);
+
+                // Without a session to keep the exchange we must not let the
+                // client keep it either
+                let accepting = accepting.load(Ordering::SeqCst);
+                if !accepting && matches!(op, ControlOp::AckOk(_) | ControlOp::Commit) {
+                    log::debug!("Not serving an exchange, rejecting {:?}", op);
+                    args.reject();
+                    return;
+                }
+
+                let now = *current;
+                let next = match (op, now) {
+                    (ControlOp::Begin, _) => {
+                        let mtu = super::att_mtu(args.desc().conn_handle());
+                        let first = begin(&mut lock(&transfer), mtu);
+                        match first {
+                            Ok(bytes) => profile.lock().set_value(&bytes).notify(),
+                            Err(e) => {
+                                log::warn!("Unable to send profile: {}", e);
+                                args.reject();
+                                return;
+                            }
+                        }
+                        Status::Idle
+                    }
+                    // First phase, answered by `GattServer::prepare` once the
+                    // session knows it can keep the client's profile. Repeated
+                    // after a lost write response.
+                    (ControlOp::AckOk(digest), Status::Received | Status::Prepared(_)) => {
+                        match prepare(&lock(&transfer), &digest) {
+                            Some(_) => now,
+                            None => {
+                                log::warn!("Peer acknowledged a different profile");
+                                args.reject();
+                                return;
+                            }
+                        }
+                    }
+                    // Acknowledging before we have the peer's profile
+                    (ControlOp::AckOk(_), _) => {
+                        args.reject();
+                        return;
+                    }
+                    // Second phase, the session reports Done once it kept the
+                    // client's profile. Done may be a repeat after a lost write
+                    // response.
+                    (ControlOp::Commit, Status::Prepared(_) | Status::Done) => now,
+                    (ControlOp::Commit, _) => {
+                        args.reject();
+                        return;
+                    }
+                    (ControlOp::Nack, now) => now,
+                    // A committed exchange is kept
+                    (ControlOp::Abort, Status::Done) => Status::Done,
+                    (ControlOp::Abort, _) => Status::Aborted,
+                };
+
+                set_status(&status, &mut current, next);
+                if accepting {
+                    send(&sender, ServerEvent::Control(op));
+                }
+            });
+        }
+
+        log::info!("GATT service registered");
+        Ok(GattServer {
+            transfer,
+            status,
+            current,
+            accepting,
+            progress,
+            peers,
+            events,
+        })
+    }
+
+    /// Replaces the profile sent to peers
+    pub fn set_profile(&self, profile: &ExchangeProfile) -> anyhow::Result<()> {
+        let bytes = profile.encode()?;
+        if bytes.len() > MAX_TRANSFER_LEN {
+            anyhow::bail!(
+                "Profile is {} bytes, at most {} can be transferred",
+                bytes.len(),
+                MAX_TRANSFER_LEN
+            );
+        }
+
+        lock(&self.transfer).profile = bytes;
+        Ok(())
+    }
+
+    /// Sets the status and notifies a subscribed peer
+    pub fn set_status(&self, status: Status) {
+        set_status(&self.status, &mut lock(&self.current), status);
+    }
+
+    /// Arms or disarms the commit. Only while armed are [`ControlOp::AckOk`]
+    /// and [`ControlOp::Commit`] accepted and the exchange reported as
+    /// [`ServerEvent`]s. Disarming aborts an exchange that is not done yet.
+    pub fn set_accepting(&self, accepting: bool) {
+        self.accepting.store(accepting, Ordering::SeqCst);
+        if accepting {
+            return;
+        }
+
+        // Under the status lock so a commit can not slip in between
+        let mut current = lock(&self.current);
+        if let Status::Received | Status::Prepared(_) = *current {
+            set_status(&self.status, &mut current, Status::Aborted);
+        }
+    }
+
+    /// Answers the client's [`ControlOp::AckOk`] with [`Status::Prepared`],
+    /// for once the client's profile is known to fit into our lists. Returns
+    /// `false` if the exchange moved on in the meantime.
+    pub fn prepare(&self) -> bool {
+        let mut current = lock(&self.current);
+        if *current != Status::Received {
+            return false;
+        }
+
+        match lock(&self.transfer).received {
+            Some(received) => {
+                set_status(&self.status, &mut current, Status::Prepared(received));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether a frame of either profile went across since the last call,
+    /// frames are too many to queue an event each
+    pub fn take_progress(&self) -> bool {
+        self.progress.swap(false, Ordering::Relaxed)
+    }
+
+    /// Whether a peer is connected to us, its [`ServerEvent::Connected`] is
+    /// already queued
+    pub fn has_peer(&self) -> bool {
+        self.peers.load(Ordering::SeqCst) > 0
+    }
+
+    /// Hangs up on the peer connected through `handle`, its
+    /// [`ServerEvent::Disconnected`] follows
+    pub fn disconnect(&self, handle: u16) -> anyhow::Result<()> {
+        BLEDevice::take()
+            .get_server()
+            .disconnect(handle)
+            .map_err(super::ble_error)
+    }
+
+    /// Server events, consumed by whoever runs the exchange
+    pub fn events(&self) -> &Receiver<ServerEvent> {
+        &self.events
+    }
+}
+
+fn uuid(text: &str) -> anyhow::Result<BleUuid> {
+    BleUuid::from_uuid128_string(text)
+        .map_err(|e| anyhow::anyhow!("Invalid UUID '{}': {:?}", text, e))
+}
+
+fn send(sender: &Sender<ServerEvent>, event: ServerEvent) {
+    // Only fails once the server, and with it the receiver, is gone
+    let _ = sender.send(event);
+}
+
+fn set_status(characteristic: &Mutex<BLECharacteristic>, current: &mut Status, status: Status) {
+    *current = status;
+    characteristic.lock().set_value(&status.encode()).notify();
+    log::debug!("GATT status: {}", status);
+}
+
+/// Starts both directions over on a fresh link, returns the first frame of
+/// our profile
+fn begin(transfer: &mut Transfer, mtu: u16) -> Result<Vec<u8>, LinkError> {
+    transfer.link = Link::new(mtu);
+    transfer.received = None;
+    transfer.link.send(&transfer.profile)
+}
+
+/// [`Status::Prepared`] if the client acknowledged our profile as we sent it,
+/// only set once the session agreed to keep the client's
+fn prepare(transfer: &Transfer, digest: &ProfileDigest) -> Option<Status> {
+    let received = transfer.received?;
+    if *digest != exchange::digest(&transfer.profile) {
+        return None;
+    }
+
+    Some(Status::Prepared(received))
+}
+
+/// A status byte can not be left half written by a panicking thread, and the
+/// transfer starts over on the next Begin
+fn lock<T>(mutex: &std::sync::Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -0,0 +1,763 @@
+//! The cycle of the README as a state machine: discover, stop discovery,
+//! connect, exchange, confirm, commit, blocklist, disconnect and start over.
+//!
+//! ```text
+//! Discovering --PeerFound--> Stopping --DiscoveryStopped--> Connecting
+//! Connecting --Connected--> Exchanging --ProfileReceived--> Confirming
+//! Confirming --Prepared--> Committing --Committed--> Blocklisting
+//! Blocklisting --Remembered--> Disconnecting --Disconnected--> Discovering
+//! ```
+//!
+//! Confirming and Committing are the two phases of the commit described in
+//! [`gatt`](super::gatt), both sides reach Blocklisting or neither does.
+//!
+//! A failure, a timeout, a dropped connection or an event out of order ends
+//! the cycle early: through Disconnecting while still connected, straight
+//! back to Discovering otherwise. The peer is only remembered once the
+//! session reached Blocklisting. The responder has two exceptions: if its peer
+//! does not connect in time it stays in Connecting and connects itself, and if
+//! the connection drops it goes back to Connecting for as long as the
+//! initiator's [`RetryPolicy`] has attempts left. Should the initiator connect
+//! while we connect ourselves, [`keep_connection`] decides which connection
+//! the session goes on with.
+//!
+//! Nothing here touches the radio. The caller reports what happened as
+//! [`Event`]s and calls [`Session::poll`] when it waited for
+//! [`Session::time_left`], time is read from the injected [`Clock`] only.
+//!
+//! Only the responder is driven live. The initiator's client runs a whole
+//! exchange in one blocking call and enforces the same timeouts itself through
+//! the [`RetryPolicy`], its events are reported once the call returned, so the
+//! session only records how the initiator's exchange went.
+
+use super::election::{keep_connection, Direction, Role, DEFAULT_RESPONDER_TIMEOUT};
+use super::outcome::{RetryPolicy, Step, StepError};
+use crate::utils::clock::Clock;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Longest tearing down advertising and scanning may take
+const STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Advertising and scanning for a peer
+    Discovering,
+    /// A peer was found, advertising and scanning are being torn down
+    Stopping,
+    /// Connecting to the peer, or waiting for it to connect as responder
+    Connecting,
+    /// Profiles are being transferred
+    Exchanging,
+    /// The peer's profile arrived, waiting for both sides to acknowledge the
+    /// other's profile digest
+    Confirming,
+    /// Both digests were acknowledged, waiting for the commit
+    Committing,
+    /// The exchange is committed, the peer is being blocklisted and saved
+    Blocklisting,
+    Disconnecting,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            State::Discovering => write!(f, "discovering"),
+            State::Stopping => write!(f, "stopping discovery"),
+            State::Connecting => write!(f, "connecting"),
+            State::Exchanging => write!(f, "exchanging"),
+            State::Confirming => write!(f, "confirming"),
+            State::Committing => write!(f, "committing"),
+            State::Blocklisting => write!(f, "blocklisting"),
+            State::Disconnecting => write!(f, "disconnecting"),
+        }
+    }
+}
+
+/// Why a cycle ended without remembering the peer
+#[derive(Debug, Clone, PartialEq)]
+pub enum Failure {
+    /// The session spent longer than its timeout in this state
+    Timeout(State),
+    /// The client side of the exchange gave up
+    Exchange { step: Step, error: StepError },
+    /// The peer aborted the exchange
+    Aborted,
+    /// We could not keep the peer's profile
+    Storage(String),
+    /// The connection dropped in this state
+    Dropped(State),
+    /// An event arrived that this state does not expect
+    OutOfOrder(State),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Timeout(state) => write!(f, "timed out while {}", state),
+            Failure::Exchange { step, error } => write!(f, "{} failed: {}", step, error),
+            Failure::Aborted => write!(f, "peer aborted"),
+            Failure::Storage(e) => write!(f, "unable to keep the peer: {}", e),
+            Failure::Dropped(state) => write!(f, "connection dropped while {}", state),
+            Failure::OutOfOrder(state) => write!(f, "unexpected event while {}", state),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// Discovery picked a peer and we were elected `role`
+    PeerFound {
+        role: Role,
+    },
+    DiscoveryStopped,
+    Connected(Direction),
+    /// A frame went across, the exchange is still alive
+    Progress,
+    /// The peer's profile arrived, decoded and validated
+    ProfileReceived,
+    /// Both sides acknowledged the other's profile digest
+    Prepared,
+    /// The commit went across, both sides keep the exchange
+    Committed,
+    /// The peer is in the blocklist and contacts
+    Remembered,
+    Disconnected,
+    Failed(Failure),
+}
+
+/// How long each state may last
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub stop: Duration,
+    /// How long a responder waits for the initiator before connecting itself
+    pub responder: Duration,
+    pub connect: Duration,
+    /// Longest the exchange may go without [`Event::Progress`]
+    pub exchange: Duration,
+    pub confirm: Duration,
+    pub commit: Duration,
+    pub disconnect: Duration,
+    /// How long a responder waits for the initiator to come back after the
+    /// connection dropped
+    pub reconnect: Duration,
+    /// How often the initiator comes back after the connection dropped
+    pub reconnects: u8,
+}
+
+impl Timeouts {
+    /// Timeouts that leave the client every step of the [`RetryPolicy`]
+    pub fn from_policy(policy: &RetryPolicy) -> Self {
+        Timeouts {
+            stop: STOP_TIMEOUT,
+            responder: DEFAULT_RESPONDER_TIMEOUT,
+            connect: policy.timeout(Step::Connect),
+            exchange: policy.attempt_budget(),
+            // Received and Prepared are waited for one after the other
+            confirm: policy.timeout(Step::AwaitReceived) + policy.timeout(Step::AwaitPrepared),
+            commit: policy.timeout(Step::Commit),
+            disconnect: policy.timeout(Step::Discover),
+            // The longest backoff the client sleeps before it connects again
+            reconnect: policy.backoff(policy.attempts.saturating_sub(1))
+                + policy.timeout(Step::Connect),
+            reconnects: policy.attempts.saturating_sub(1),
+        }
+    }
+}
+
+pub struct Session<C: Clock> {
+    clock: C,
+    timeouts: Timeouts,
+    state: State,
+    entered_at: Instant,
+    /// Our role in the current cycle, `None` while discovering
+    role: Option<Role>,
+    /// The role we were elected, `role` becomes initiator when a responder
+    /// connects itself
+    elected: Option<Role>,
+    connected: bool,
+    /// Dropped connections the responder waited out in the current cycle
+    reconnects: u8,
+    /// Why the current cycle is ending early, or would if the initiator does
+    /// not come back
+    failure: Option<Failure>,
+    /// How the last cycle ended
+    outcome: Option<Result<(), Failure>>,
+}
+
+impl<C: Clock> Session<C> {
+    pub fn new(clock: C, timeouts: Timeouts) -> Self {
+        Session {
+            entered_at: clock.now(),
+            clock,
+            timeouts,
+            state: State::Discovering,
+            role: None,
+            elected: None,
+            connected: false,
+            reconnects: 0,
+            failure: None,
+            outcome: None,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn role(&self) -> Option<Role> {
+        self.role
+    }
+
+    pub fn elected(&self) -> Option<Role> {
+        self.elected
+    }
+
+    /// How the last completed cycle ended, `None` before the first one
+    pub fn outcome(&self) -> Option<&Result<(), Failure>> {
+        self.outcome.as_ref()
+    }
+
+    /// Time until the current state times out, `None` if it can not
+    pub fn time_left(&self) -> Option<Duration> {
+        let timeout = match self.state {
+            State::Discovering | State::Blocklisting => return None,
+            State::Stopping => self.timeouts.stop,
+            State::Connecting if self.role == Some(Role::Responder) && self.reconnects > 0 => {
+                self.timeouts.reconnect
+            }
+            State::Connecting if self.role == Some(Role::Responder) => self.timeouts.responder,
+            State::Connecting => self.timeouts.connect,
+            State::Exchanging => self.timeouts.exchange,
+            State::Confirming => self.timeouts.confirm,
+            State::Committing => self.timeouts.commit,
+            State::Disconnecting => self.timeouts.disconnect,
+        };
+
+        Some((self.entered_at + timeout).saturating_duration_since(self.clock.now()))
+    }
+
+    /// Applies the timeout of the current state if it passed
+    pub fn poll(&mut self) -> State {
+        if self.time_left() != Some(Duration::ZERO) {
+            return self.state;
+        }
+
+        match (self.state, self.role) {
+            (State::Connecting, Some(Role::Responder)) if self.reconnects > 0 => {
+                log::warn!("Peer did not come back");
+                self.finish();
+            }
+            (State::Connecting, Some(Role::Responder)) => {
+                log::info!("Peer did not connect, connecting ourselves");
+                self.role = Some(Role::Initiator);
+                self.enter(State::Connecting);
+            }
+            (State::Disconnecting, _) => {
+                log::warn!("Peer did not disconnect, starting over");
+                self.finish();
+            }
+            (state, _) => self.fail(Failure::Timeout(state)),
+        }
+        self.state
+    }
+
+    pub fn handle(&mut self, event: Event) -> State {
+        match (self.state, event) {
+            (State::Disconnecting, Event::Disconnected) => {
+                self.connected = false;
+                self.finish();
+            }
+            // Whatever else the peer still sends, the cycle is over
+            (State::Disconnecting, _) => {}
+            (_, Event::Failed(failure)) => self.fail(failure),
+
+            (State::Discovering, Event::PeerFound { role }) => {
+                self.role = Some(role);
+                self.elected = Some(role);
+                self.enter(State::Stopping);
+            }
+            (State::Stopping, Event::DiscoveryStopped) => self.enter(State::Connecting),
+            (State::Connecting, Event::Connected(direction)) => self.connect(direction),
+            // Every frame restarts the exchange timeout
+            (State::Exchanging, Event::Progress) => self.enter(State::Exchanging),
+            (State::Exchanging, Event::ProfileReceived) => self.enter(State::Confirming),
+            // Late frames, e.g. the last ACK_OK of our profile
+            (State::Confirming | State::Committing, Event::Progress) => {}
+            (State::Confirming, Event::Prepared) => self.enter(State::Committing),
+            // A repeated ACK_OK after a lost write response
+            (State::Committing, Event::Prepared) => {}
+            (State::Committing, Event::Committed) => self.enter(State::Blocklisting),
+            // The peer may hang up as soon as it committed
+            (State::Blocklisting, Event::Disconnected) => self.connected = false,
+            (State::Blocklisting, Event::Remembered) if self.connected => {
+                self.enter(State::Disconnecting)
+            }
+            (State::Blocklisting, Event::Remembered) => self.finish(),
+
+            // The initiator retries the whole exchange on a new connection
+            (
+                state @ (State::Exchanging | State::Confirming | State::Committing),
+                Event::Disconnected,
+            ) if self.role == Some(Role::Responder)
+                && self.reconnects < self.timeouts.reconnects =>
+            {
+                log::info!(
+                    "Connection dropped while {}, waiting for the peer to retry",
+                    state
+                );
+                self.connected = false;
+                self.reconnects += 1;
+                self.failure = Some(Failure::Dropped(state));
+                self.enter(State::Connecting);
+            }
+            (
+                state @ (State::Connecting
+                | State::Exchanging
+                | State::Confirming
+                | State::Committing),
+                Event::Disconnected,
+            ) => {
+                self.connected = false;
+                self.fail(Failure::Dropped(state));
+            }
+            (state, _) => self.fail(Failure::OutOfOrder(state)),
+        }
+        self.state
+    }
+
+    fn connect(&mut self, direction: Direction) {
+        match (self.role, direction) {
+            (Some(Role::Initiator), Direction::Outgoing)
+            | (Some(Role::Responder), Direction::Incoming) => {}
+            // Both sides connected, the responder fell back to connecting
+            // itself while the initiator was about to retry
+            (Some(Role::Initiator), Direction::Incoming)
+                if self
+                    .elected
+                    .is_some_and(|elected| keep_connection(elected, direction)) =>
+            {
+                log::info!("Peer connected while we were connecting, serving it");
+                self.role = Some(Role::Responder);
+            }
+            (Some(Role::Initiator), Direction::Incoming) => {
+                log::debug!("Peer connected while we were connecting, keeping ours");
+                return;
+            }
+            _ => return self.fail(Failure::OutOfOrder(State::Connecting)),
+        }
+
+        self.connected = true;
+        self.failure = None;
+        self.enter(State::Exchanging);
+    }
+
+    fn fail(&mut self, failure: Failure) {
+        log::debug!("Session failed: {}", failure);
+        self.failure = Some(failure);
+
+        if self.connected {
+            self.enter(State::Disconnecting);
+        } else {
+            self.finish();
+        }
+    }
+
+    /// Ends the cycle and goes back to discovering
+    fn finish(&mut self) {
+        self.outcome = Some(match self.failure.take() {
+            Some(failure) => Err(failure),
+            None => Ok(()),
+        });
+        self.role = None;
+        self.elected = None;
+        self.connected = false;
+        self.reconnects = 0;
+        self.enter(State::Discovering);
+    }
+
+    fn enter(&mut self, state: State) {
+        if state != self.state {
+            log::debug!("Session: {} -> {}", self.state, state);
+        }
+        self.state = state;
+        self.entered_at = self.clock.now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::clock::ManualClock;
+
+    const STATES: [State; 8] = [
+        State::Discovering,
+        State::Stopping,
+        State::Connecting,
+        State::Exchanging,
+        State::Confirming,
+        State::Committing,
+        State::Blocklisting,
+        State::Disconnecting,
+    ];
+
+    fn session() -> (ManualClock, Session<ManualClock>) {
+        let clock = ManualClock::default();
+        let timeouts = Timeouts::from_policy(&RetryPolicy::default());
+        (clock.clone(), Session::new(clock, timeouts))
+    }
+
+    /// A fresh session in `state` as `role`, through the happy path
+    fn session_in(state: State, role: Role) -> (ManualClock, Session<ManualClock>) {
+        let (clock, mut session) = session();
+        let path = [
+            Event::PeerFound { role },
+            Event::DiscoveryStopped,
+            Event::Connected(opened_by(role)),
+            Event::ProfileReceived,
+            Event::Prepared,
+            Event::Committed,
+            Event::Remembered,
+        ];
+        for event in path {
+            if session.state() == state {
+                break;
+            }
+            session.handle(event);
+        }
+
+        assert_eq!(session.state(), state);
+        (clock, session)
+    }
+
+    /// Which way `role` opens the connection
+    fn opened_by(role: Role) -> Direction {
+        match role {
+            Role::Initiator => Direction::Outgoing,
+            Role::Responder => Direction::Incoming,
+        }
+    }
+
+    /// Every event except failures, which every state accepts
+    fn events() -> Vec<Event> {
+        vec![
+            Event::PeerFound {
+                role: Role::Initiator,
+            },
+            Event::DiscoveryStopped,
+            Event::Connected(Direction::Outgoing),
+            Event::Progress,
+            Event::ProfileReceived,
+            Event::Prepared,
+            Event::Committed,
+            Event::Remembered,
+            Event::Disconnected,
+        ]
+    }
+
+    fn is_connected(state: State) -> bool {
+        matches!(
+            state,
+            State::Exchanging | State::Confirming | State::Committing | State::Blocklisting
+        )
+    }
+
+    /// Asserts the cycle ends with `outcome`, hanging up first if connected
+    fn assert_ends(session: &mut Session<ManualClock>, outcome: Result<(), Failure>) {
+        if session.state() == State::Disconnecting {
+            session.handle(Event::Disconnected);
+        }
+        assert_eq!(session.state(), State::Discovering);
+        assert_eq!(session.role(), None);
+        assert_eq!(session.outcome(), Some(&outcome));
+    }
+
+    #[test]
+    fn happy_path() {
+        let (_, mut session) = session_in(State::Disconnecting, Role::Initiator);
+        assert_eq!(session.outcome(), None);
+
+        session.handle(Event::Disconnected);
+        assert_ends(&mut session, Ok(()));
+    }
+
+    #[test]
+    fn peer_may_hang_up_before_we_remembered_it() {
+        let (_, mut session) = session_in(State::Blocklisting, Role::Responder);
+
+        assert_eq!(session.handle(Event::Disconnected), State::Blocklisting);
+        assert_eq!(session.handle(Event::Remembered), State::Discovering);
+        assert_ends(&mut session, Ok(()));
+    }
+
+    #[test]
+    fn late_and_repeated_events_are_tolerated() {
+        let (_, mut session) = session_in(State::Exchanging, Role::Initiator);
+        assert_eq!(session.handle(Event::Progress), State::Exchanging);
+        session.handle(Event::ProfileReceived);
+        assert_eq!(session.handle(Event::Progress), State::Confirming);
+        session.handle(Event::Prepared);
+        assert_eq!(session.handle(Event::Progress), State::Committing);
+        assert_eq!(session.handle(Event::Prepared), State::Committing);
+        assert_eq!(session.handle(Event::Committed), State::Blocklisting);
+    }
+
+    #[test]
+    fn progress_restarts_the_exchange_timeout() {
+        let (clock, mut session) = session_in(State::Exchanging, Role::Initiator);
+        let timeout = session.timeouts.exchange;
+
+        clock.advance(timeout - Duration::from_millis(1));
+        session.handle(Event::Progress);
+        clock.advance(timeout - Duration::from_millis(1));
+
+        assert_eq!(session.poll(), State::Exchanging);
+        assert_eq!(session.time_left(), Some(Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn every_state_times_out() {
+        for state in [
+            State::Stopping,
+            State::Connecting,
+            State::Exchanging,
+            State::Confirming,
+            State::Committing,
+        ] {
+            let (clock, mut session) = session_in(state, Role::Initiator);
+            let timeout = session.time_left().unwrap();
+
+            clock.advance(timeout - Duration::from_millis(1));
+            assert_eq!(session.poll(), state);
+            clock.advance(Duration::from_millis(1));
+            let expected = match is_connected(state) {
+                true => State::Disconnecting,
+                false => State::Discovering,
+            };
+            assert_eq!(session.poll(), expected, "{}", state);
+            assert_ends(&mut session, Err(Failure::Timeout(state)));
+        }
+    }
+
+    #[test]
+    fn a_peer_that_does_not_hang_up_is_left_behind() {
+        let (clock, mut session) = session_in(State::Disconnecting, Role::Initiator);
+
+        clock.advance(session.timeouts.disconnect);
+        assert_eq!(session.poll(), State::Discovering);
+        assert_ends(&mut session, Ok(()));
+    }
+
+    #[test]
+    fn discovering_and_blocklisting_never_time_out() {
+        for state in [State::Discovering, State::Blocklisting] {
+            let (clock, mut session) = session_in(state, Role::Initiator);
+
+            assert_eq!(session.time_left(), None);
+            clock.advance(Duration::from_secs(3600));
+            assert_eq!(session.poll(), state);
+        }
+    }
+
+    #[test]
+    fn responder_falls_back_to_initiator() {
+        let (clock, mut session) = session_in(State::Connecting, Role::Responder);
+        assert_eq!(session.time_left(), Some(session.timeouts.responder));
+
+        clock.advance(session.timeouts.responder);
+        assert_eq!(session.poll(), State::Connecting);
+        assert_eq!(session.role(), Some(Role::Initiator));
+        assert_eq!(session.time_left(), Some(session.timeouts.connect));
+
+        // Connecting ourselves does not fall back any further
+        clock.advance(session.timeouts.connect);
+        session.poll();
+        assert_ends(&mut session, Err(Failure::Timeout(State::Connecting)));
+    }
+
+    #[test]
+    fn both_sides_go_on_with_the_initiators_connection() {
+        let (_, mut initiator) = session_in(State::Connecting, Role::Initiator);
+        let (clock, mut responder) = session_in(State::Connecting, Role::Responder);
+        clock.advance(responder.timeouts.responder);
+        responder.poll();
+        assert_eq!(responder.role(), Some(Role::Initiator));
+
+        // Both are connecting, each sees the other's connection come in first
+        assert_eq!(
+            initiator.handle(Event::Connected(Direction::Incoming)),
+            State::Connecting
+        );
+        assert_eq!(
+            responder.handle(Event::Connected(Direction::Incoming)),
+            State::Exchanging
+        );
+        assert_eq!(responder.role(), Some(Role::Responder));
+        assert_eq!(responder.elected(), Some(Role::Responder));
+
+        // The initiator's own connection is the one the responder serves
+        assert_eq!(
+            initiator.handle(Event::Connected(Direction::Outgoing)),
+            State::Exchanging
+        );
+        for event in [Event::ProfileReceived, Event::Prepared, Event::Committed] {
+            initiator.handle(event.clone());
+            responder.handle(event);
+        }
+        assert_eq!(initiator.state(), State::Blocklisting);
+        assert_eq!(responder.state(), State::Blocklisting);
+    }
+
+    #[test]
+    fn a_responder_that_connected_itself_keeps_its_connection() {
+        let (clock, mut session) = session_in(State::Connecting, Role::Responder);
+        clock.advance(session.timeouts.responder);
+        session.poll();
+
+        // The initiator never showed up, nothing to race with
+        assert_eq!(
+            session.handle(Event::Connected(Direction::Outgoing)),
+            State::Exchanging
+        );
+        assert_eq!(session.role(), Some(Role::Initiator));
+    }
+
+    #[test]
+    fn responder_waits_for_the_initiator_to_retry() {
+        let (clock, mut session) = session_in(State::Confirming, Role::Responder);
+
+        for _ in 0..session.timeouts.reconnects {
+            assert_eq!(session.handle(Event::Disconnected), State::Connecting);
+            assert_eq!(session.role(), Some(Role::Responder));
+            assert_eq!(session.time_left(), Some(session.timeouts.reconnect));
+
+            clock.advance(session.timeouts.reconnect - Duration::from_millis(1));
+            assert_eq!(session.poll(), State::Connecting);
+            session.handle(Event::Connected(Direction::Incoming));
+            session.handle(Event::ProfileReceived);
+        }
+
+        for event in [Event::Prepared, Event::Committed, Event::Remembered] {
+            session.handle(event);
+        }
+        session.handle(Event::Disconnected);
+        assert_ends(&mut session, Ok(()));
+    }
+
+    #[test]
+    fn responder_gives_up_after_the_initiators_last_attempt() {
+        let (_, mut session) = session_in(State::Exchanging, Role::Responder);
+        for _ in 0..session.timeouts.reconnects {
+            session.handle(Event::Disconnected);
+            session.handle(Event::Connected(Direction::Incoming));
+        }
+
+        assert_eq!(session.handle(Event::Disconnected), State::Discovering);
+        assert_ends(&mut session, Err(Failure::Dropped(State::Exchanging)));
+    }
+
+    #[test]
+    fn responder_gives_up_if_the_initiator_does_not_come_back() {
+        let (clock, mut session) = session_in(State::Committing, Role::Responder);
+        session.handle(Event::Disconnected);
+
+        clock.advance(session.timeouts.reconnect);
+        assert_eq!(session.poll(), State::Discovering);
+        assert_ends(&mut session, Err(Failure::Dropped(State::Committing)));
+    }
+
+    #[test]
+    fn reconnects_are_counted_per_cycle() {
+        let (_, mut session) = session_in(State::Exchanging, Role::Responder);
+        session.handle(Event::Disconnected);
+        session.handle(Event::Failed(Failure::Aborted));
+        assert_ends(&mut session, Err(Failure::Aborted));
+
+        for event in [
+            Event::PeerFound {
+                role: Role::Responder,
+            },
+            Event::DiscoveryStopped,
+            Event::Connected(Direction::Incoming),
+        ] {
+            session.handle(event);
+        }
+        assert_eq!(session.handle(Event::Disconnected), State::Connecting);
+    }
+
+    #[test]
+    fn dropped_from_every_connected_state() {
+        for state in [
+            State::Connecting,
+            State::Exchanging,
+            State::Confirming,
+            State::Committing,
+        ] {
+            let (_, mut session) = session_in(state, Role::Initiator);
+
+            assert_eq!(session.handle(Event::Disconnected), State::Discovering);
+            assert_ends(&mut session, Err(Failure::Dropped(state)));
+        }
+    }
+
+    #[test]
+    fn out_of_order_from_every_state() {
+        for state in STATES {
+            for event in events() {
+                let expected = match (state, &event) {
+                    (State::Discovering, Event::PeerFound { .. })
+                    | (State::Stopping, Event::DiscoveryStopped)
+                    | (State::Connecting, Event::Connected(_) | Event::Disconnected)
+                    | (
+                        State::Exchanging,
+                        Event::Progress | Event::ProfileReceived | Event::Disconnected,
+                    )
+                    | (
+                        State::Confirming,
+                        Event::Progress | Event::Prepared | Event::Disconnected,
+                    )
+                    | (
+                        State::Committing,
+                        Event::Progress | Event::Prepared | Event::Committed | Event::Disconnected,
+                    )
+                    | (State::Blocklisting, Event::Remembered | Event::Disconnected)
+                    | (State::Disconnecting, _) => continue,
+                    _ => Err(Failure::OutOfOrder(state)),
+                };
+
+                let (_, mut session) = session_in(state, Role::Initiator);
+                session.handle(event.clone());
+                assert_ends(&mut session, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn failures_end_the_cycle_from_every_state() {
+        for state in STATES {
+            let (_, mut session) = session_in(state, Role::Initiator);
+            session.handle(Event::Failed(Failure::Aborted));
+
+            match state {
+                // Already ending, the hang up decides
+                State::Disconnecting => {
+                    assert_eq!(session.state(), State::Disconnecting);
+                    assert_ends(&mut session, Ok(()));
+                }
+                _ => assert_ends(&mut session, Err(Failure::Aborted)),
+            }
+        }
+    }
+
+    #[test]
+    fn disconnecting_ignores_everything_but_the_hang_up() {
+        for event in events() {
+            if event == Event::Disconnected {
+                continue;
+            }
+            let (_, mut session) = session_in(State::Disconnecting, Role::Initiator);
+
+            assert_eq!(session.handle(event), State::Disconnecting);
+        }
+    }
+}
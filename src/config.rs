@@ -0,0 +1,11 @@
+/// Build time configuration, read from `cfg.toml` under `[dap-up-protocol-rs]`
+#[toml_cfg::toml_config]
+pub struct Config {
+    #[default("")]
+    pub wifi_ssid: &'static str,
+    #[default("")]
+    pub wifi_psk: &'static str,
+    /// Key required to enter factory mode, factory mode is disabled when empty
+    #[default("")]
+    pub factory_key: &'static str,
+}
@@ -0,0 +1,376 @@
+use crate::utils::address;
+use log::LevelFilter;
+use std::fmt;
+
+/// One line typed into the console
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Help,
+    Info,
+    SetName(String),
+    SetOwner(String),
+    ProvisionSerial {
+        serial: String,
+        batch: String,
+        provisioned_at: u64,
+    },
+    /// Wipes user data, with the factory key it also unlocks the serial number
+    FactoryReset {
+        factory_key: Option<String>,
+    },
+    /// Wipes the blocklist and received contacts only
+    ForgetAll,
+    BlocklistList,
+    BlocklistRemove([u8; 6]),
+    LogLevel(LevelFilter),
+    /// Lists every setting with its current value
+    Settings,
+    /// Changes one setting, the value is checked by `Settings::set`
+    Set {
+        key: String,
+        value: String,
+    },
+    /// Changes one contact card field, an empty value clears it
+    SetCard {
+        field: String,
+        value: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    Empty,
+    UnknownCommand(String),
+    MissingArgument(&'static str),
+    InvalidArgument(&'static str, String),
+    TooManyArguments,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "empty command"),
+            ParseError::UnknownCommand(name) => {
+                write!(f, "unknown command '{}', try 'help'", name)
+            }
+            ParseError::MissingArgument(name) => write!(f, "missing argument <{}>", name),
+            ParseError::InvalidArgument(name, value) => {
+                write!(f, "invalid <{}> '{}'", name, value)
+            }
+            ParseError::TooManyArguments => write!(f, "too many arguments"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub const HELP: &str = "\
+Commands:
+  help                                        show this message
+  info                                        show the device profile
+  set-name <name>                             set the device name
+  set-owner <name>                            set the device owner
+  provision-serial <serial> <batch> <unix>    write and lock the serial number
+  factory-reset [factory-key]                 wipe user data, the key also unlocks the serial
+  forget-all                                  wipe the blocklist and received contacts
+  blocklist list                              list blocked devices
+  blocklist remove <aa:bb:cc:dd:ee:ff>        unblock a device
+  log-level <off|error|warn|info|debug|trace> change and persist the log level
+  settings                                    list every setting
+  set <key> <value>                           change and persist a setting
+  set-card <field> <value>                    change a contact card field, \"\" clears it
+";
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, ParseError> {
+        let line = line.trim();
+        let (name, rest) = match line.split_once(char::is_whitespace) {
+            Some((name, rest)) => (name, rest.trim()),
+            None => (line, ""),
+        };
+
+        match name {
+            "" => Err(ParseError::Empty),
+            "help" | "?" => no_args(rest, Command::Help),
+            "info" => no_args(rest, Command::Info),
+            "set-name" => Ok(Command::SetName(text_arg(rest, "name")?)),
+            "set-owner" => Ok(Command::SetOwner(text_arg(rest, "name")?)),
+            "provision-serial" => {
+                let mut args = rest.split_whitespace();
+                let serial = args.next().ok_or(ParseError::MissingArgument("serial"))?;
+                let batch = args.next().ok_or(ParseError::MissingArgument("batch"))?;
+                let time = args.next().ok_or(ParseError::MissingArgument("unix"))?;
+                if args.next().is_some() {
+                    return Err(ParseError::TooManyArguments);
+                }
+
+                Ok(Command::ProvisionSerial {
+                    serial: serial.to_string(),
+                    batch: batch.to_string(),
+                    provisioned_at: time
+                        .parse()
+                        .map_err(|_| ParseError::InvalidArgument("unix", time.to_string()))?,
+                })
+            }
+            "factory-reset" => {
+                let mut args = rest.split_whitespace();
+                let factory_key = args.next().map(str::to_string);
+                if args.next().is_some() {
+                    return Err(ParseError::TooManyArguments);
+                }
+
+                Ok(Command::FactoryReset { factory_key })
+            }
+            "forget-all" => no_args(rest, Command::ForgetAll),
+            "blocklist" => {
+                let mut args = rest.split_whitespace();
+                let command = match args.next() {
+                    Some("list") => Command::BlocklistList,
+                    Some("remove") => {
+                        let text = args.next().ok_or(ParseError::MissingArgument("address"))?;
+                        let address = address::parse(text).ok_or_else(|| {
+                            ParseError::InvalidArgument("address", text.to_string())
+                        })?;
+                        Command::BlocklistRemove(address)
+                    }
+                    Some(other) => {
+                        return Err(ParseError::UnknownCommand(format!("blocklist {}", other)))
+                    }
+                    None => return Err(ParseError::MissingArgument("list|remove")),
+                };
+                if args.next().is_some() {
+                    return Err(ParseError::TooManyArguments);
+                }
+
+                Ok(command)
+            }
+            "log-level" => {
+                let level = text_arg(rest, "level")?;
+                level
+                    .parse()
+                    .map(Command::LogLevel)
+                    .map_err(|_| ParseError::InvalidArgument("level", level))
+            }
+            "settings" => no_args(rest, Command::Settings),
+            "set" => {
+                let (key, value) = match rest.split_once(char::is_whitespace) {
+                    Some((key, value)) => (key, value.trim()),
+                    None if rest.is_empty() => return Err(ParseError::MissingArgument("key")),
+                    None => return Err(ParseError::MissingArgument("value")),
+                };
+
+                Ok(Command::Set {
+                    key: key.to_string(),
+                    value: text_arg(value, "value")?,
+                })
+            }
+            "set-card" => {
+                let (field, value) = match rest.split_once(char::is_whitespace) {
+                    Some((field, value)) => (field, value.trim()),
+                    None if rest.is_empty() => return Err(ParseError::MissingArgument("field")),
+                    None => return Err(ParseError::MissingArgument("value")),
+                };
+
+                Ok(Command::SetCard {
+                    field: field.to_string(),
+                    // Every card field is optional, so an explicit "" is a value
+                    value: match value {
+                        "\"\"" => String::new(),
+                        value => text_arg(value, "value")?,
+                    },
+                })
+            }
+            other => Err(ParseError::UnknownCommand(other.to_string())),
+        }
+    }
+}
+
+fn no_args(rest: &str, command: Command) -> Result<Command, ParseError> {
+    if rest.is_empty() {
+        Ok(command)
+    } else {
+        Err(ParseError::TooManyArguments)
+    }
+}
+
+/// The rest of the line as one value, surrounding double quotes are stripped.
+/// Trimming and normalisation are left to the storage layer.
+fn text_arg(rest: &str, name: &'static str) -> Result<String, ParseError> {
+    let value = match rest.strip_prefix('"').and_then(|r| r.strip_suffix('"')) {
+        Some(quoted) => quoted,
+        None => rest,
+    };
+
+    if value.is_empty() {
+        return Err(ParseError::MissingArgument(name));
+    }
+
+    Ok(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_command() {
+        assert_eq!(Command::parse("help"), Ok(Command::Help));
+        assert_eq!(Command::parse("  info  "), Ok(Command::Info));
+        assert_eq!(
+            Command::parse("provision-serial SN-1 B7 1700000000"),
+            Ok(Command::ProvisionSerial {
+                serial: "SN-1".to_string(),
+                batch: "B7".to_string(),
+                provisioned_at: 1_700_000_000,
+            })
+        );
+        assert_eq!(
+            Command::parse("factory-reset"),
+            Ok(Command::FactoryReset { factory_key: None })
+        );
+        assert_eq!(
+            Command::parse("blocklist remove AA:bb:cc:dd:ee:01"),
+            Ok(Command::BlocklistRemove([
+                0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x01
+            ]))
+        );
+        assert_eq!(
+            Command::parse("log-level warn"),
+            Ok(Command::LogLevel(LevelFilter::Warn))
+        );
+        assert_eq!(
+            Command::parse("set rssi_min -65"),
+            Ok(Command::Set {
+                key: "rssi_min".to_string(),
+                value: "-65".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn text_arguments_take_the_rest_of_the_line() {
+        assert_eq!(
+            Command::parse("set-name  My   badge "),
+            Ok(Command::SetName("My   badge".to_string()))
+        );
+        assert_eq!(
+            Command::parse("set-owner \"  Alex R \""),
+            Ok(Command::SetOwner("  Alex R ".to_string()))
+        );
+        // Only a pair of quotes around the whole value is stripped
+        assert_eq!(
+            Command::parse("set-name \"My\" badge"),
+            Ok(Command::SetName("\"My\" badge".to_string()))
+        );
+        assert_eq!(
+            Command::parse("set-name \"half"),
+            Ok(Command::SetName("\"half".to_string()))
+        );
+        assert_eq!(
+            Command::parse("set-name \"\""),
+            Err(ParseError::MissingArgument("name"))
+        );
+        assert_eq!(
+            Command::parse("set-card bio \"\""),
+            Ok(Command::SetCard {
+                field: "bio".to_string(),
+                value: String::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_too_many_arguments() {
+        for line in [
+            "help me",
+            "info now",
+            "forget-all please",
+            "settings all",
+            "factory-reset key extra",
+            "provision-serial SN-1 B7 1700000000 extra",
+            "blocklist list all",
+            "blocklist remove aa:bb:cc:dd:ee:ff aa:bb:cc:dd:ee:00",
+        ] {
+            assert_eq!(
+                Command::parse(line),
+                Err(ParseError::TooManyArguments),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_blocklist_addresses() {
+        for address in [
+            "aa:bb:cc:dd:ee",
+            "aa:bb:cc:dd:ee:ff:00",
+            "aa:bb:cc:dd:ee:gg",
+            "aabbccddeeff",
+            "a:bb:cc:dd:ee:fff",
+        ] {
+            assert_eq!(
+                Command::parse(&format!("blocklist remove {}", address)),
+                Err(ParseError::InvalidArgument("address", address.to_string()))
+            );
+        }
+        assert_eq!(
+            Command::parse("blocklist remove"),
+            Err(ParseError::MissingArgument("address"))
+        );
+        assert_eq!(
+            Command::parse("blocklist"),
+            Err(ParseError::MissingArgument("list|remove"))
+        );
+        assert_eq!(
+            Command::parse("blocklist add aa:bb:cc:dd:ee:ff"),
+            Err(ParseError::UnknownCommand("blocklist add".to_string()))
+        );
+    }
+
+    #[test]
+    fn set_needs_a_key_and_a_value() {
+        assert_eq!(
+            Command::parse("set"),
+            Err(ParseError::MissingArgument("key"))
+        );
+        assert_eq!(
+            Command::parse("set rssi_min"),
+            Err(ParseError::MissingArgument("value"))
+        );
+        assert_eq!(
+            Command::parse("set rssi_min   "),
+            Err(ParseError::MissingArgument("value"))
+        );
+        assert_eq!(
+            Command::parse("set rssi_min \"\""),
+            Err(ParseError::MissingArgument("value"))
+        );
+        assert_eq!(
+            Command::parse("set-card bio"),
+            Err(ParseError::MissingArgument("value"))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert_eq!(Command::parse(""), Err(ParseError::Empty));
+        assert_eq!(Command::parse("   "), Err(ParseError::Empty));
+        assert_eq!(
+            Command::parse("reboot"),
+            Err(ParseError::UnknownCommand("reboot".to_string()))
+        );
+        assert_eq!(
+            Command::parse("log-level loud"),
+            Err(ParseError::InvalidArgument("level", "loud".to_string()))
+        );
+        assert_eq!(
+            Command::parse("provision-serial SN-1 B7 yesterday"),
+            Err(ParseError::InvalidArgument("unix", "yesterday".to_string()))
+        );
+        assert_eq!(
+            Command::parse("provision-serial SN-1"),
+            Err(ParseError::MissingArgument("batch"))
+        );
+    }
+}
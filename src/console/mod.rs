@@ -0,0 +1,231 @@
+pub mod command;
+#[cfg(target_os = "espidf")]
+pub mod uart;
+
+use crate::utils::address;
+use crate::utils::blocklist::Blocklist;
+use crate::utils::error::DeviceInfoError;
+use crate::utils::profile::ProfileRecord;
+use crate::utils::provisioning::{self, FactoryMode, ProvisioningRecord};
+use crate::utils::serial::{DeviceInfo, NVSKeyword};
+use crate::utils::settings::Settings;
+use crate::utils::storage::{DeviceStorage, KeyValueStore};
+use crate::utils::wipe;
+use command::{Command, ParseError, HELP};
+use std::io::{self, Write};
+
+/// Longest line the console accepts, anything longer is cut off
+pub const MAX_LINE_LEN: usize = 256;
+
+/// Reads commands line by line until `lines` ends, every command holds the
+/// storage lock for its whole duration so it can not interleave with BLE/web
+pub fn run<S, I, W>(lines: I, storage: DeviceStorage<S>, mut out: W) -> io::Result<()>
+where
+    S: KeyValueStore,
+    I: IntoIterator<Item = io::Result<String>>,
+    W: Write,
+{
+    write!(out, "DapUp console, type 'help' for a list of commands\n> ")?;
+    out.flush()?;
+
+    for line in lines {
+        let line = line?;
+
+        match Command::parse(&line) {
+            Ok(command) => {
+                if let Err(e) = execute(command, &mut *storage.lock(), &mut out) {
+                    writeln!(out, "error: {}", e)?;
+                }
+            }
+            Err(ParseError::Empty) => {}
+            Err(e) => writeln!(out, "error: {}", e)?,
+        }
+
+        write!(out, "> ")?;
+        out.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Runs a single parsed command against `store`, writing its output to `out`
+pub fn execute<S: KeyValueStore, W: Write>(
+    command: Command,
+    store: &mut S,
+    out: &mut W,
+) -> anyhow::Result<()> {
+    match command {
+        Command::Help => write!(out, "{}", HELP)?,
+        Command::Info => {
+            match DeviceInfo::load(store) {
+                Ok(_) => {}
+                Err(e) => writeln!(out, "warning: {}", e)?,
+            }
+
+            let info = DeviceInfo::new(store);
+            writeln!(out, "Serial Number: {}", info.serial_num)?;
+            writeln!(out, "Device Name: {}", info.device_name)?;
+            writeln!(out, "Device Owner: {}", info.device_owner)?;
+            writeln!(out, "Display Name: {}", info.card.display_name)?;
+
+            match ProvisioningRecord::load(store)? {
+                Some(record) => writeln!(
+                    out,
+                    "Provisioned: batch '{}' at {}",
+                    record.batch, record.provisioned_at
+                )?,
+                None => writeln!(out, "Provisioned: no")?,
+            }
+        }
+        Command::SetName(name) => {
+            DeviceInfo::update(store, &name, NVSKeyword::DeviceName)?;
+            writeln!(out, "Device name set to '{}'", name)?;
+        }
+        Command::SetOwner(name) => {
+            match DeviceInfo::update(store, &name, NVSKeyword::DeviceOwner) {
+                Ok(()) => writeln!(out, "Device owner set to '{}'", name)?,
+                // The console is the manual registration path the error asks for
+                Err(DeviceInfoError::OwnerNotRegistered) => {
+                    DeviceInfo::store_device_owner(store, &name)?;
+                    writeln!(out, "Device registered to '{}'", name)?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Command::ProvisionSerial {
+            serial,
+            batch,
+            provisioned_at,
+        } => {
+            provisioning::provision_serial(store, &serial, &batch, provisioned_at)?;
+            writeln!(out, "Serial number '{}' provisioned and locked", serial)?;
+        }
+        Command::FactoryReset { factory_key } => {
+            // Authenticate before touching anything so a wrong key wipes nothing
+            let factory = match factory_key {
+                Some(key) => Some(FactoryMode::unlock(&key)?),
+                None => None,
+            };
+
+            wipe::factory_reset(store)?;
+
+            if let Some(factory) = factory {
+                provisioning::reset_serial(store, &factory)?;
+                writeln!(out, "Factory reset done, serial number unlocked")?;
+            } else {
+                writeln!(out, "Factory reset done")?;
+            }
+        }
+        Command::ForgetAll => {
+            wipe::forget_received(store)?;
+            writeln!(out, "Blocklist and received contacts wiped")?;
+        }
+        Command::BlocklistList => {
+            let blocklist = Blocklist::load(store)?;
+            if blocklist.entries.is_empty() {
+                writeln!(out, "Blocklist is empty")?;
+            }
+            for entry in &blocklist.entries {
+                writeln!(
+                    out,
+                    "{}  {}  {} ({})",
+                    address::format(&entry.address),
+                    entry.serial_num,
+                    entry.device_name,
+                    entry.card.display_name
+                )?;
+            }
+        }
+        Command::BlocklistRemove(target) => {
+            let mut blocklist = Blocklist::load(store)?;
+            match blocklist.remove(&target) {
+                Some(entry) => {
+                    blocklist.save(store)?;
+                    writeln!(out, "Removed {} from the blocklist", entry.serial_num)?;
+                }
+                None => writeln!(out, "{} is not blocklisted", address::format(&target))?,
+            }
+        }
+        Command::LogLevel(level) => {
+            let mut settings = Settings::load(store);
+            settings.log_level = level;
+            settings.save(store)?;
+            settings.apply();
+            writeln!(out, "Log level set to {}", level)?;
+        }
+        Command::Settings => {
+            for (key, value) in Settings::load(store).entries() {
+                writeln!(out, "{} = {}", key, value)?;
+            }
+        }
+        Command::Set { key, value } => {
+            let mut settings = Settings::load(store);
+            settings.set(&key, &value)?;
+            settings.save(store)?;
+            settings.apply();
+            writeln!(out, "Setting [{}] set to '{}'", key, value)?;
+        }
+        Command::SetCard { field, value } => {
+            let mut card = ProfileRecord::load(store)?.unwrap_or_default().card;
+            card.set(&field, &value)?;
+            DeviceInfo::update_card(store, card)?;
+            writeln!(out, "Card field [{}] set to '{}'", field, value)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs the console on stdin in its own thread, used on the host
+#[cfg(not(target_os = "espidf"))]
+pub fn spawn<S: KeyValueStore + Send + 'static>(
+    storage: DeviceStorage<S>,
+) -> io::Result<std::thread::JoinHandle<()>> {
+    std::thread::Builder::new()
+        .name("console".to_string())
+        .spawn(move || {
+            if let Err(e) = run(io::stdin().lines(), storage, io::stdout()) {
+                log::error!("Console stopped: {}", e);
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::storage::MemoryStore;
+
+    fn execute_line(store: &mut MemoryStore, line: &str) -> anyhow::Result<String> {
+        let mut out = Vec::new();
+        execute(Command::parse(line)?, store, &mut out)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    #[test]
+    fn set_card_updates_the_stored_card() {
+        let mut store = MemoryStore::new();
+        execute_line(&mut store, "set-card display_name \"Alex R\"").unwrap();
+        execute_line(&mut store, "set-card socials mastodon:@alex@example.social").unwrap();
+
+        let card = ProfileRecord::load(&store).unwrap().unwrap().card;
+        assert_eq!(card.display_name, "Alex R");
+        assert_eq!(card.socials[0].network, "mastodon");
+
+        execute_line(&mut store, "set-card display_name \"\"").unwrap();
+        let card = ProfileRecord::load(&store).unwrap().unwrap().card;
+        assert_eq!(card.display_name, "");
+        assert_eq!(card.socials.len(), 1);
+    }
+
+    #[test]
+    fn set_card_keeps_the_card_on_invalid_values() {
+        let mut store = MemoryStore::new();
+        execute_line(&mut store, "set-card role Speaker").unwrap();
+
+        assert!(execute_line(&mut store, &format!("set-card role {}", "x".repeat(65))).is_err());
+        assert!(execute_line(&mut store, "set-card phone 123").is_err());
+
+        let card = ProfileRecord::load(&store).unwrap().unwrap().card;
+        assert_eq!(card.role, "Speaker");
+    }
+}
@@ -0,0 +1,87 @@
+use super::{run, MAX_LINE_LEN};
+use crate::utils::storage::{DeviceStorage, KeyValueStore};
+use esp_idf_hal::delay::BLOCK;
+use esp_idf_hal::gpio::{AnyIOPin, InputPin, OutputPin};
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::uart::{config::Config, Uart, UartDriver};
+use esp_idf_hal::units::Hertz;
+use std::io;
+use std::thread::JoinHandle;
+
+/// Console stack, the commands touch NVS and postcard which both like stack
+const STACK_SIZE: usize = 8 * 1024;
+
+/// Assembles lines from the UART, echoing what is typed and handling backspace
+struct UartLines<'d> {
+    uart: UartDriver<'d>,
+    line: Vec<u8>,
+}
+
+impl Iterator for UartLines<'_> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut byte = [0u8; 1];
+
+        loop {
+            match self.uart.read(&mut byte, BLOCK) {
+                Ok(0) => continue,
+                Ok(_) => {}
+                Err(e) => return Some(Err(io::Error::other(e))),
+            }
+
+            match byte[0] {
+                b'\r' | b'\n' => {
+                    let _ = self.uart.write(b"\r\n");
+                    let line = std::mem::take(&mut self.line);
+                    return Some(Ok(String::from_utf8_lossy(&line).into_owned()));
+                }
+                // Backspace / delete
+                0x08 | 0x7f => {
+                    if self.line.pop().is_some() {
+                        let _ = self.uart.write(b"\x08 \x08");
+                    }
+                }
+                b if self.line.len() < MAX_LINE_LEN => {
+                    self.line.push(b);
+                    let _ = self.uart.write(&byte);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Starts the console task on `uart`, normally UART0 on the USB serial pins
+pub fn spawn<S: KeyValueStore + Send + 'static>(
+    uart: impl Peripheral<P = impl Uart> + 'static,
+    tx: impl Peripheral<P = impl OutputPin> + 'static,
+    rx: impl Peripheral<P = impl InputPin> + 'static,
+    storage: DeviceStorage<S>,
+) -> anyhow::Result<JoinHandle<()>> {
+    let config = Config::default().baudrate(Hertz(115_200));
+    let uart = UartDriver::new(
+        uart,
+        tx,
+        rx,
+        Option::<AnyIOPin>::None,
+        Option::<AnyIOPin>::None,
+        &config,
+    )?;
+
+    let handle = std::thread::Builder::new()
+        .name("console".to_string())
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let lines = UartLines {
+                uart,
+                line: Vec::new(),
+            };
+
+            if let Err(e) = run(lines, storage, io::stdout()) {
+                log::error!("Console stopped: {}", e);
+            }
+        })?;
+
+    Ok(handle)
+}
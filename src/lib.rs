@@ -0,0 +1,8 @@
+//! Everything the firmware is made of. The platform glue is only compiled for
+//! the ESP-IDF, the rest also builds and is tested on the host.
+
+pub mod ble;
+pub mod config;
+pub mod console;
+pub mod protocol;
+pub mod utils;
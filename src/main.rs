@@ -1,14 +1,55 @@
-mod utils;
+#[cfg(target_os = "espidf")]
+use dap_up_protocol_rs::ble;
+use dap_up_protocol_rs::console;
+use dap_up_protocol_rs::utils::profile::migrate_legacy;
+use dap_up_protocol_rs::utils::serial::DeviceInfo;
+use dap_up_protocol_rs::utils::settings::Settings;
+use dap_up_protocol_rs::utils::storage::DeviceStorage;
 
 fn main() -> Result<(), anyhow::Error> {
     // -----------------------------
     // Required stuff, do not remove
     // -----------------------------
-    esp_idf_svc::sys::link_patches();
-    esp_idf_svc::log::EspLogger::initialize_default();
+    #[cfg(target_os = "espidf")]
+    {
+        esp_idf_svc::sys::link_patches();
+        esp_idf_svc::log::EspLogger::initialize_default();
+    }
     log::set_max_level(log::LevelFilter::Debug);
     // -----------------------------
 
-    
+    // Opened exactly once, every subsystem gets a clone of this handle
+    let mut storage = DeviceStorage::open_default()?;
+    migrate_legacy(&mut storage)?;
+    Settings::load(&storage).apply();
+
+    DeviceInfo::new(&storage).print();
+
+    #[cfg(target_os = "espidf")]
+    let console = {
+        let peripherals = esp_idf_hal::peripherals::Peripherals::take()?;
+        console::uart::spawn(
+            peripherals.uart0,
+            peripherals.pins.gpio1,
+            peripherals.pins.gpio3,
+            storage.clone(),
+        )?
+    };
+    #[cfg(not(target_os = "espidf"))]
+    let console = console::spawn(storage.clone())?;
+
+    // Discovers peers forever, only returns on error
+    #[cfg(target_os = "espidf")]
+    {
+        let device = esp32_nimble::BLEDevice::take();
+        ble::privacy::enable_private_address(device)?;
+        let server = ble::server::GattServer::start(device)?;
+        ble::discovery::run(device, &server, storage.clone())?;
+    }
+
+    console
+        .join()
+        .map_err(|_| anyhow::anyhow!("Console thread panicked"))?;
+
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,325 @@
+//! Splitting payloads that do not fit into one frame.
+//!
+//! | Offset | Size | Field                                  |
+//! |--------|------|----------------------------------------|
+//! | 0      | 2    | total payload length, little endian    |
+//! | 2      | 2    | offset of this chunk, little endian    |
+//! | 4      | rest | chunk data                             |
+//!
+//! Every chunk travels as the payload of one data frame, sized so the frame
+//! fits into a single ATT write or notification at the negotiated MTU. The
+//! transfer layer delivers frames in order and exactly once, so
+//! [`Reassembler`] only accepts the chunk that continues where the previous
+//! one ended and checks it against the total before copying anything.
+
+use super::frame::{CRC_LEN, HEADER_LEN, MAX_PAYLOAD_LEN};
+use std::fmt;
+
+pub const CHUNK_HEADER_LEN: usize = 4;
+
+/// Largest payload that can be transferred, a few KB for rich profiles
+pub const MAX_TRANSFER_LEN: usize = 4096;
+
+/// MTU every ATT bearer starts with, until a larger one is exchanged
+pub const DEFAULT_ATT_MTU: u16 = 23;
+
+/// Opcode and attribute handle in front of every write and notification
+const ATT_HEADER_LEN: usize = 3;
+
+/// Chunk data that fits into one ATT write or notification at `mtu`
+pub fn chunk_len(mtu: u16) -> usize {
+    let value_len = (mtu.max(DEFAULT_ATT_MTU) as usize - ATT_HEADER_LEN)
+        .min(HEADER_LEN + MAX_PAYLOAD_LEN + CRC_LEN);
+    value_len - HEADER_LEN - CRC_LEN - CHUNK_HEADER_LEN
+}
+
+/// Why a payload could not be split or reassembled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkError {
+    /// Fewer bytes than a chunk header
+    TooShort,
+    /// The payload is longer than [`MAX_TRANSFER_LEN`]
+    TooLong(usize),
+    /// The total length changed in the middle of a payload
+    TotalMismatch { expected: usize, total: usize },
+    /// The chunk does not continue where the previous one ended
+    OutOfOrder { expected: usize, offset: usize },
+    /// The chunk reaches past the total length
+    Overflow,
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkError::TooShort => write!(f, "chunk is too short"),
+            ChunkError::TooLong(len) => write!(
+                f,
+                "payload is {} bytes, at most {} can be transferred",
+                len, MAX_TRANSFER_LEN
+            ),
+            ChunkError::TotalMismatch { expected, total } => write!(
+                f,
+                "chunk claims a total of {} bytes instead of {}",
+                total, expected
+            ),
+            ChunkError::OutOfOrder { expected, offset } => {
+                write!(f, "chunk at offset {} while expecting {}", offset, expected)
+            }
+            ChunkError::Overflow => write!(f, "chunk reaches past the end of the payload"),
+        }
+    }
+}
+
+impl std::error::Error for ChunkError {}
+
+/// Splits `payload` into chunks of at most `chunk_len` data bytes. An empty
+/// payload still becomes one chunk so the receiver learns about it.
+pub fn split(payload: &[u8], chunk_len: usize) -> Result<Vec<Vec<u8>>, ChunkError> {
+    if payload.len() > MAX_TRANSFER_LEN {
+        return Err(ChunkError::TooLong(payload.len()));
+    }
+
+    let total = payload.len() as u16;
+    if payload.is_empty() {
+        return Ok(vec![chunk(total, 0, &[])]);
+    }
+
+    let chunk_len = chunk_len.max(1);
+    Ok(payload
+        .chunks(chunk_len)
+        .enumerate()
+        .map(|(i, data)| chunk(total, (i * chunk_len) as u16, data))
+        .collect())
+}
+
+fn chunk(total: u16, offset: u16, data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(CHUNK_HEADER_LEN + data.len());
+    chunk.extend_from_slice(&total.to_le_bytes());
+    chunk.extend_from_slice(&offset.to_le_bytes());
+    chunk.extend_from_slice(data);
+    chunk
+}
+
+/// Puts chunks back together, one payload after the other
+#[derive(Debug, Clone, Default)]
+pub struct Reassembler {
+    /// Total length of the payload being reassembled, `None` between payloads
+    total: Option<usize>,
+    data: Vec<u8>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Reassembler::default()
+    }
+
+    /// Adds the next chunk, returns the payload once it is complete. Nothing
+    /// is copied from a chunk that fails a check.
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Option<Vec<u8>>, ChunkError> {
+        if chunk.len() < CHUNK_HEADER_LEN {
+            return Err(ChunkError::TooShort);
+        }
+
+        let total = u16::from_le_bytes([chunk[0], chunk[1]]) as usize;
+        let offset = u16::from_le_bytes([chunk[2], chunk[3]]) as usize;
+        let data = &chunk[CHUNK_HEADER_LEN..];
+
+        if total > MAX_TRANSFER_LEN {
+            return Err(ChunkError::TooLong(total));
+        }
+        match self.total {
+            Some(expected) if expected != total => {
+                return Err(ChunkError::TotalMismatch { expected, total })
+            }
+            _ => {}
+        }
+        if offset != self.data.len() {
+            return Err(ChunkError::OutOfOrder {
+                expected: self.data.len(),
+                offset,
+            });
+        }
+        if offset + data.len() > total {
+            return Err(ChunkError::Overflow);
+        }
+
+        self.total = Some(total);
+        self.data.extend_from_slice(data);
+        if self.data.len() < total {
+            return Ok(None);
+        }
+
+        self.total = None;
+        Ok(Some(std::mem::take(&mut self.data)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pushes every chunk, returns what came out after the last one
+    fn reassemble(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut reassembler = Reassembler::new();
+        let (last, rest) = chunks.split_last().unwrap();
+        for chunk in rest {
+            assert_eq!(reassembler.push(chunk), Ok(None));
+        }
+        reassembler.push(last).unwrap().unwrap()
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn chunks_fit_into_one_att_value() {
+        // 20 byte writes at the default MTU
+        assert_eq!(chunk_len(DEFAULT_ATT_MTU), 7);
+        assert_eq!(chunk_len(0), chunk_len(DEFAULT_ATT_MTU));
+        // Capped by the largest frame, not the 514 byte ATT value
+        assert_eq!(chunk_len(517), 499);
+        assert_eq!(chunk_len(u16::MAX), chunk_len(517));
+
+        for mtu in [23, 24, 185, 247, 512, 515, 517] {
+            let frame_len = HEADER_LEN + CHUNK_HEADER_LEN + chunk_len(mtu) + CRC_LEN;
+            assert!(frame_len <= mtu as usize - ATT_HEADER_LEN, "MTU {}", mtu);
+        }
+    }
+
+    #[test]
+    fn split_and_reassemble() {
+        for len in [1, 6, 7, 8, 100, 499, 500, 1000] {
+            for mtu in [23, 185, 517] {
+                let payload = payload(len);
+                let chunks = split(&payload, chunk_len(mtu)).unwrap();
+
+                assert_eq!(chunks.len(), len.div_ceil(chunk_len(mtu)));
+                assert_eq!(reassemble(&chunks), payload, "{} bytes at MTU {}", len, mtu);
+            }
+        }
+    }
+
+    #[test]
+    fn an_empty_payload_is_one_chunk() {
+        let chunks = split(&[], chunk_len(DEFAULT_ATT_MTU)).unwrap();
+
+        assert_eq!(chunks, vec![vec![0, 0, 0, 0]]);
+        assert_eq!(reassemble(&chunks), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn the_largest_payload_goes_through() {
+        let payload = payload(MAX_TRANSFER_LEN);
+        let chunks = split(&payload, chunk_len(517)).unwrap();
+
+        assert_eq!(reassemble(&chunks), payload);
+        assert_eq!(
+            split(&[0; MAX_TRANSFER_LEN + 1], chunk_len(517)),
+            Err(ChunkError::TooLong(MAX_TRANSFER_LEN + 1))
+        );
+    }
+
+    #[test]
+    fn rejects_a_chunk_shorter_than_its_header() {
+        let mut reassembler = Reassembler::new();
+
+        assert_eq!(reassembler.push(&[1, 0, 0]), Err(ChunkError::TooShort));
+        assert_eq!(reassembler.push(&[]), Err(ChunkError::TooShort));
+    }
+
+    #[test]
+    fn rejects_a_total_beyond_the_limit() {
+        let total = (MAX_TRANSFER_LEN as u16 + 1).to_le_bytes();
+
+        assert_eq!(
+            Reassembler::new().push(&[total[0], total[1], 0, 0, 0xaa]),
+            Err(ChunkError::TooLong(MAX_TRANSFER_LEN + 1))
+        );
+    }
+
+    #[test]
+    fn rejects_a_total_that_changes() {
+        let mut reassembler = Reassembler::new();
+        reassembler.push(&chunk(10, 0, &[1; 4])).unwrap();
+
+        assert_eq!(
+            reassembler.push(&chunk(12, 4, &[2; 4])),
+            Err(ChunkError::TotalMismatch {
+                expected: 10,
+                total: 12
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_chunks_out_of_order() {
+        let chunks = split(&payload(20), 7).unwrap();
+        let mut reassembler = Reassembler::new();
+
+        assert_eq!(
+            reassembler.push(&chunks[1]),
+            Err(ChunkError::OutOfOrder {
+                expected: 0,
+                offset: 7
+            })
+        );
+        reassembler.push(&chunks[0]).unwrap();
+        assert_eq!(
+            reassembler.push(&chunks[0]),
+            Err(ChunkError::OutOfOrder {
+                expected: 7,
+                offset: 0
+            })
+        );
+        assert_eq!(
+            reassembler.push(&chunks[2]),
+            Err(ChunkError::OutOfOrder {
+                expected: 7,
+                offset: 14
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_a_chunk_past_the_total() {
+        let mut reassembler = Reassembler::new();
+        reassembler.push(&chunk(10, 0, &[1; 7])).unwrap();
+
+        assert_eq!(
+            reassembler.push(&chunk(10, 7, &[2; 4])),
+            Err(ChunkError::Overflow)
+        );
+        assert_eq!(
+            Reassembler::new().push(&chunk(3, 0, &[1; 4])),
+            Err(ChunkError::Overflow)
+        );
+    }
+
+    #[test]
+    fn a_rejected_chunk_leaves_the_payload_intact() {
+        let payload = payload(20);
+        let chunks = split(&payload, 7).unwrap();
+        let mut reassembler = Reassembler::new();
+        reassembler.push(&chunks[0]).unwrap();
+
+        assert!(reassembler.push(&chunk(20, 7, &[0xff; 14])).is_err());
+        assert!(reassembler.push(&chunks[2]).is_err());
+        assert_eq!(reassembler.push(&chunks[1]), Ok(None));
+        assert_eq!(reassembler.push(&chunks[2]), Ok(Some(payload)));
+    }
+
+    #[test]
+    fn payloads_follow_one_another() {
+        let mut reassembler = Reassembler::new();
+
+        for payload in [payload(10), Vec::new(), payload(3)] {
+            let chunks = split(&payload, 7).unwrap();
+            let mut out = None;
+            for chunk in &chunks {
+                out = reassembler.push(chunk).unwrap();
+            }
+            assert_eq!(out, Some(payload));
+        }
+    }
+}
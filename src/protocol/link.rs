@@ -0,0 +1,388 @@
+//! Both directions of a chunked transfer between two peers.
+//!
+//! Each peer sends data frames through its own pipe and answers the other
+//! peer's data frames with ACK_OK or NACK through the same pipe, so one pipe
+//! carries data one way and replies to the other direction. [`Link`] sorts
+//! incoming frames into the two, splits outgoing payloads into chunks and
+//! reassembles incoming ones.
+
+use super::chunk::{self, ChunkError, Reassembler};
+use super::frame::{Frame, FrameType};
+use super::transfer::{Receiver, SendAction, Sender, TransferError, DEFAULT_MAX_RETRANSMITS};
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Why a transfer was given up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkError {
+    Transfer(TransferError),
+    /// The peer's chunks do not add up to a payload
+    Chunk(ChunkError),
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::Transfer(e) => write!(f, "{}", e),
+            LinkError::Chunk(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+impl From<TransferError> for LinkError {
+    fn from(error: TransferError) -> Self {
+        LinkError::Transfer(error)
+    }
+}
+
+impl From<ChunkError> for LinkError {
+    fn from(error: ChunkError) -> Self {
+        LinkError::Chunk(error)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkEvent {
+    /// The peer's payload is complete
+    Received(Vec<u8>),
+    /// The peer acknowledged the last chunk of our payload
+    Sent,
+    Failed(LinkError),
+}
+
+/// What to do after an incoming frame or a timeout
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Action {
+    /// Bytes for our pipe, a reply or a (repeated) data frame
+    pub transmit: Option<Vec<u8>>,
+    pub event: Option<LinkEvent>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Link {
+    chunk_len: usize,
+    max_retransmits: u8,
+    sender: Sender,
+    /// Chunks of the outgoing payload not handed to the sender yet
+    pending: VecDeque<Vec<u8>>,
+    receiver: Receiver,
+    reassembler: Reassembler,
+    /// NACKs sent in a row while waiting for a data frame
+    nacks: u8,
+}
+
+impl Link {
+    /// A link sized for one ATT write or notification at `mtu`
+    pub fn new(mtu: u16) -> Self {
+        Link {
+            chunk_len: chunk::chunk_len(mtu),
+            max_retransmits: DEFAULT_MAX_RETRANSMITS,
+            sender: Sender::new(DEFAULT_MAX_RETRANSMITS),
+            pending: VecDeque::new(),
+            receiver: Receiver::new(),
+            reassembler: Reassembler::new(),
+            nacks: 0,
+        }
+    }
+
+    /// Starts sending `payload`, returns the first frame to transmit
+    pub fn send(&mut self, payload: &[u8]) -> Result<Vec<u8>, LinkError> {
+        if !self.sender.is_idle() || !self.pending.is_empty() {
+            return Err(TransferError::Busy.into());
+        }
+
+        self.pending = chunk::split(payload, self.chunk_len)?.into();
+        let first = self.pending.pop_front().unwrap_or_default();
+        Ok(self.sender.send(first)?)
+    }
+
+    pub fn on_receive(&mut self, bytes: &[u8]) -> Action {
+        match Frame::decode(bytes) {
+            Ok(frame) if frame.frame_type != FrameType::Data => {
+                let action = self.sender.on_receive(bytes);
+                self.on_send_action(action)
+            }
+            // Corrupted frames get a NACK as if they were data, a lost reply
+            // is recovered by the sender's timeout instead
+            _ => self.on_data(bytes),
+        }
+    }
+
+    /// Nothing arrived in time. Repeats our data frame if one is in flight,
+    /// asks for the peer's next one otherwise.
+    pub fn on_timeout(&mut self) -> Action {
+        if !self.sender.is_idle() {
+            let action = self.sender.on_timeout();
+            return self.on_send_action(action);
+        }
+
+        if self.nacks >= self.max_retransmits {
+            return Action {
+                transmit: None,
+                event: Some(LinkEvent::Failed(
+                    TransferError::RetransmitsExhausted {
+                        seq: self.receiver.expected_seq(),
+                    }
+                    .into(),
+                )),
+            };
+        }
+
+        self.nacks += 1;
+        Action {
+            transmit: Some(self.receiver.nack()),
+            event: None,
+        }
+    }
+
+    fn on_data(&mut self, bytes: &[u8]) -> Action {
+        let received = self.receiver.on_receive(bytes);
+        let event = match received.payload {
+            Some(chunk) => {
+                self.nacks = 0;
+                match self.reassembler.push(&chunk) {
+                    Ok(Some(payload)) => Some(LinkEvent::Received(payload)),
+                    Ok(None) => None,
+                    Err(e) => Some(LinkEvent::Failed(e.into())),
+                }
+            }
+            None => None,
+        };
+
+        Action {
+            transmit: received.reply,
+            event,
+        }
+    }
+
+    fn on_send_action(&mut self, action: SendAction) -> Action {
+        match action {
+            SendAction::Delivered { .. } => match self.pending.pop_front() {
+                Some(chunk) => match self.sender.send(chunk) {
+                    Ok(bytes) => Action {
+                        transmit: Some(bytes),
+                        event: None,
+                    },
+                    Err(e) => self.fail(e),
+                },
+                None => Action {
+                    transmit: None,
+                    event: Some(LinkEvent::Sent),
+                },
+            },
+            SendAction::Transmit(bytes) => Action {
+                transmit: Some(bytes),
+                event: None,
+            },
+            SendAction::Ignore => Action::default(),
+            SendAction::Failed(e) => self.fail(e),
+        }
+    }
+
+    fn fail(&mut self, error: TransferError) -> Action {
+        self.pending.clear();
+        Action {
+            transmit: None,
+            event: Some(LinkEvent::Failed(error.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::chunk::{DEFAULT_ATT_MTU, MAX_TRANSFER_LEN};
+
+    /// Never loses a frame
+    const RELIABLE: u64 = u64::MAX;
+
+    /// Carries frames between two links, dropping one in `drop_one_in` and
+    /// corrupting one in `corrupt_one_in` at random. Each side's events are
+    /// collected in order.
+    struct Channel {
+        links: [Link; 2],
+        /// Frames on their way to `links[i]`
+        inbound: [VecDeque<Vec<u8>>; 2],
+        events: [Vec<LinkEvent>; 2],
+        drop_one_in: u64,
+        corrupt_one_in: u64,
+        /// xorshift, deterministic so a failure can be replayed
+        state: u64,
+    }
+
+    impl Channel {
+        fn new(links: [Link; 2], drop_one_in: u64, corrupt_one_in: u64) -> Self {
+            Channel {
+                links,
+                inbound: Default::default(),
+                events: Default::default(),
+                drop_one_in,
+                corrupt_one_in,
+                state: 0x2545_f491_4f6c_dd1d,
+            }
+        }
+
+        fn random(&mut self) -> u64 {
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 7;
+            self.state ^= self.state << 17;
+            self.state
+        }
+
+        fn send(&mut self, from: usize, payload: &[u8]) {
+            let first = self.links[from].send(payload).unwrap();
+            self.inbound[1 - from].push_back(first);
+        }
+
+        /// Whether `side` has nothing left to wait for
+        fn done(&self, side: usize) -> bool {
+            self.events[side].len() == 2
+                || self.events[side]
+                    .iter()
+                    .any(|event| matches!(event, LinkEvent::Failed(_)))
+        }
+
+        fn apply(&mut self, side: usize, action: Action) {
+            if let Some(bytes) = action.transmit {
+                self.inbound[1 - side].push_back(bytes);
+            }
+            self.events[side].extend(action.event);
+        }
+
+        /// Runs until both sides sent and received or one gave up
+        fn run(&mut self) {
+            for _ in 0..100_000 {
+                if self.done(0) && self.done(1) {
+                    return;
+                }
+
+                let mut delivered = false;
+                for side in 0..2 {
+                    let Some(mut bytes) = self.inbound[side].pop_front() else {
+                        continue;
+                    };
+                    delivered = true;
+                    if self.random() % self.drop_one_in == 0 {
+                        continue;
+                    }
+                    if self.random() % self.corrupt_one_in == 0 {
+                        let at = self.random() as usize % bytes.len();
+                        bytes[at] ^= 0x5a;
+                    }
+                    let action = self.links[side].on_receive(&bytes);
+                    self.apply(side, action);
+                }
+
+                // Silence, both sides time out
+                if !delivered {
+                    for side in 0..2 {
+                        if !self.done(side) {
+                            let action = self.links[side].on_timeout();
+                            self.apply(side, action);
+                        }
+                    }
+                }
+            }
+            panic!("transfer did not finish: {:?}", self.events);
+        }
+    }
+
+    /// A link that survives a run of losses long enough to cover thousands
+    /// of frames
+    fn patient(mtu: u16) -> Link {
+        Link {
+            max_retransmits: 12,
+            sender: Sender::new(12),
+            ..Link::new(mtu)
+        }
+    }
+
+    fn payload(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+            .collect()
+    }
+
+    /// Asserts both sides sent their payload and received the other's
+    fn assert_exchanged(channel: &Channel, payloads: [&[u8]; 2]) {
+        for side in 0..2 {
+            let events = &channel.events[side];
+            assert!(events.contains(&LinkEvent::Sent), "{:?}", events);
+            assert!(
+                events.contains(&LinkEvent::Received(payloads[1 - side].to_vec())),
+                "{:?}",
+                events
+            );
+        }
+    }
+
+    #[test]
+    fn exchanges_both_ways() {
+        let a = payload(300, 1);
+        let b = payload(40, 2);
+        let links = [Link::new(DEFAULT_ATT_MTU), Link::new(DEFAULT_ATT_MTU)];
+        let mut channel = Channel::new(links, RELIABLE, RELIABLE);
+        channel.send(0, &a);
+        channel.send(1, &b);
+        channel.run();
+
+        assert_exchanged(&channel, [&a, &b]);
+    }
+
+    #[test]
+    fn exchanges_both_ways_over_a_lossy_channel() {
+        for (mtu, drop_one_in, corrupt_one_in) in [(23, 10, 16), (185, 5, 8), (517, 3, 4)] {
+            let a = payload(MAX_TRANSFER_LEN, 3);
+            let b = payload(700, 4);
+            let mut channel =
+                Channel::new([patient(mtu), patient(mtu)], drop_one_in, corrupt_one_in);
+            channel.send(0, &a);
+            channel.send(1, &b);
+            channel.run();
+
+            assert_exchanged(&channel, [&a, &b]);
+        }
+    }
+
+    #[test]
+    fn an_empty_payload_goes_across() {
+        let links = [patient(DEFAULT_ATT_MTU), patient(DEFAULT_ATT_MTU)];
+        let mut channel = Channel::new(links, 4, 4);
+        channel.send(0, &[]);
+        channel.send(1, b"card");
+        channel.run();
+
+        assert_exchanged(&channel, [&[], b"card"]);
+    }
+
+    #[test]
+    fn gives_up_on_a_silent_peer() {
+        let mut link = Link::new(DEFAULT_ATT_MTU);
+        link.send(b"card").unwrap();
+
+        let mut actions = Vec::new();
+        for _ in 0..=DEFAULT_MAX_RETRANSMITS {
+            actions.push(link.on_timeout());
+        }
+        assert!(actions[..DEFAULT_MAX_RETRANSMITS as usize]
+            .iter()
+            .all(|action| action.transmit.is_some()));
+        assert!(matches!(
+            actions.last().unwrap().event,
+            Some(LinkEvent::Failed(LinkError::Transfer(_)))
+        ));
+    }
+
+    #[test]
+    fn a_second_payload_waits_for_the_first() {
+        let mut link = Link::new(DEFAULT_ATT_MTU);
+        link.send(&[0; 100]).unwrap();
+
+        assert_eq!(
+            link.send(b"card"),
+            Err(LinkError::Transfer(TransferError::Busy))
+        );
+    }
+}
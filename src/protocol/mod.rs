@@ -0,0 +1,4 @@
+pub mod chunk;
+pub mod frame;
+pub mod link;
+pub mod transfer;
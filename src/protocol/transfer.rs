@@ -0,0 +1,423 @@
+//! Stop-and-wait delivery on top of [`Frame`]s.
+//!
+//! The sender keeps one data frame in flight until the receiver answers with
+//! ACK_OK for its sequence number. A NACK or a timeout resends it, up to
+//! `max_retransmits` times. The receiver acknowledges every intact frame, and
+//! re-acknowledges a duplicate of the previous one (our ACK_OK got lost)
+//! without delivering it twice.
+//!
+//! Neither side does I/O or keeps time, the caller moves bytes and reports
+//! timeouts, so both can be driven from a test or a fuzzer.
+
+use super::frame::{Frame, FrameError, FrameType, MAX_PAYLOAD_LEN};
+use std::fmt;
+
+/// Resends after which the sender gives up on a frame
+pub const DEFAULT_MAX_RETRANSMITS: u8 = 3;
+
+/// Why a transfer ended without delivery
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferError {
+    /// The frame was resent `max_retransmits` times without an ACK_OK
+    RetransmitsExhausted { seq: u16 },
+    /// The payload does not fit into one frame
+    TooLong(usize),
+    /// A new payload was queued while the previous one is still in flight
+    Busy,
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::RetransmitsExhausted { seq } => {
+                write!(f, "frame {} was never acknowledged", seq)
+            }
+            TransferError::TooLong(len) => write!(
+                f,
+                "payload is {} bytes, at most {} fit into a frame",
+                len, MAX_PAYLOAD_LEN
+            ),
+            TransferError::Busy => write!(f, "a frame is still in flight"),
+        }
+    }
+}
+
+impl std::error::Error for TransferError {}
+
+/// What the sender wants done after an incoming frame or a timeout
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendAction {
+    /// The frame in flight was acknowledged, the next one can be sent
+    Delivered {
+        seq: u16,
+    },
+    /// Send these bytes (again)
+    Transmit(Vec<u8>),
+    /// Nothing to do, e.g. a stale or corrupted answer
+    Ignore,
+    Failed(TransferError),
+}
+
+#[derive(Debug, Clone)]
+struct InFlight {
+    seq: u16,
+    bytes: Vec<u8>,
+    retransmits: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct Sender {
+    next_seq: u16,
+    max_retransmits: u8,
+    in_flight: Option<InFlight>,
+}
+
+impl Default for Sender {
+    fn default() -> Self {
+        Sender::new(DEFAULT_MAX_RETRANSMITS)
+    }
+}
+
+impl Sender {
+    pub fn new(max_retransmits: u8) -> Self {
+        Sender {
+            next_seq: 0,
+            max_retransmits,
+            in_flight: None,
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.in_flight.is_none()
+    }
+
+    /// Frames `payload` and returns the bytes to transmit
+    pub fn send(&mut self, payload: Vec<u8>) -> Result<Vec<u8>, TransferError> {
+        if self.in_flight.is_some() {
+            return Err(TransferError::Busy);
+        }
+
+        let seq = self.next_seq;
+        let bytes = Frame::data(seq, payload).encode().map_err(|e| match e {
+            FrameError::TooLong(len) => TransferError::TooLong(len),
+            // Encoding fails on nothing but the length
+            e => unreachable!("{}", e),
+        })?;
+
+        self.next_seq = seq.wrapping_add(1);
+        self.in_flight = Some(InFlight {
+            seq,
+            bytes: bytes.clone(),
+            retransmits: 0,
+        });
+        Ok(bytes)
+    }
+
+    /// Handles an answer from the receiver
+    pub fn on_receive(&mut self, bytes: &[u8]) -> SendAction {
+        let in_flight = match &self.in_flight {
+            Some(in_flight) => in_flight,
+            None => return SendAction::Ignore,
+        };
+
+        match Frame::decode(bytes) {
+            // The receiver already waits for the next frame, its ACK_OK got lost
+            Ok(frame)
+                if frame.frame_type == FrameType::Nack
+                    && frame.seq == in_flight.seq.wrapping_add(1) =>
+            {
+                let seq = in_flight.seq;
+                self.in_flight = None;
+                SendAction::Delivered { seq }
+            }
+            Ok(frame) if frame.seq != in_flight.seq => SendAction::Ignore,
+            Ok(frame) => match frame.frame_type {
+                FrameType::AckOk => {
+                    let seq = in_flight.seq;
+                    self.in_flight = None;
+                    SendAction::Delivered { seq }
+                }
+                FrameType::Nack => self.retransmit(),
+                FrameType::Data => SendAction::Ignore,
+            },
+            // A corrupted answer could have been either, the timeout decides
+            Err(_) => SendAction::Ignore,
+        }
+    }
+
+    /// No answer arrived in time
+    pub fn on_timeout(&mut self) -> SendAction {
+        match self.in_flight {
+            Some(_) => self.retransmit(),
+            None => SendAction::Ignore,
+        }
+    }
+
+    fn retransmit(&mut self) -> SendAction {
+        let in_flight = match &mut self.in_flight {
+            Some(in_flight) => in_flight,
+            None => return SendAction::Ignore,
+        };
+
+        if in_flight.retransmits >= self.max_retransmits {
+            let seq = in_flight.seq;
+            self.in_flight = None;
+            return SendAction::Failed(TransferError::RetransmitsExhausted { seq });
+        }
+
+        in_flight.retransmits += 1;
+        SendAction::Transmit(in_flight.bytes.clone())
+    }
+}
+
+/// What the receiver hands back for one incoming frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Received {
+    /// ACK_OK or NACK to send back, `None` for frames that need no answer
+    pub reply: Option<Vec<u8>>,
+    /// A new payload, delivered exactly once
+    pub payload: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Receiver {
+    expected_seq: u16,
+}
+
+impl Receiver {
+    pub fn new() -> Self {
+        Receiver::default()
+    }
+
+    /// Sequence number of the next data frame to be delivered
+    pub fn expected_seq(&self) -> u16 {
+        self.expected_seq
+    }
+
+    /// Asks for the expected frame again, for when it does not arrive in time
+    pub fn nack(&self) -> Vec<u8> {
+        Frame::nack(self.expected_seq).encode().unwrap_or_default()
+    }
+
+    pub fn on_receive(&mut self, bytes: &[u8]) -> Received {
+        let frame = match Frame::decode(bytes) {
+            Ok(frame) => frame,
+            // The sequence number is as untrustworthy as the rest
+            Err(_) => return self.reply(Frame::nack(self.expected_seq), None),
+        };
+
+        if frame.frame_type != FrameType::Data {
+            return Received {
+                reply: None,
+                payload: None,
+            };
+        }
+
+        if frame.seq == self.expected_seq {
+            self.expected_seq = frame.seq.wrapping_add(1);
+            self.reply(Frame::ack_ok(frame.seq), Some(frame.payload))
+        } else if frame.seq == self.expected_seq.wrapping_sub(1) {
+            // Our ACK_OK was lost and the sender retransmitted
+            self.reply(Frame::ack_ok(frame.seq), None)
+        } else {
+            self.reply(Frame::nack(self.expected_seq), None)
+        }
+    }
+
+    fn reply(&self, frame: Frame, payload: Option<Vec<u8>>) -> Received {
+        Received {
+            // Answers never carry a payload, so encoding can not fail
+            reply: frame.encode().ok(),
+            payload,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::frame::HEADER_LEN;
+
+    fn encode(frame: Frame) -> Vec<u8> {
+        frame.encode().unwrap()
+    }
+
+    /// Passes `payload` from a sender to a receiver, returns what the
+    /// receiver delivered
+    fn deliver(sender: &mut Sender, receiver: &mut Receiver, payload: &[u8]) -> Vec<u8> {
+        let bytes = sender.send(payload.to_vec()).unwrap();
+        let received = receiver.on_receive(&bytes);
+        let seq = Frame::decode(&bytes).unwrap().seq;
+        assert_eq!(
+            sender.on_receive(&received.reply.unwrap()),
+            SendAction::Delivered { seq }
+        );
+        received.payload.unwrap()
+    }
+
+    #[test]
+    fn delivers_frames_in_order() {
+        let mut sender = Sender::default();
+        let mut receiver = Receiver::new();
+
+        for i in 0..3u8 {
+            assert_eq!(deliver(&mut sender, &mut receiver, &[i]), vec![i]);
+        }
+        assert!(sender.is_idle());
+        assert_eq!(receiver.expected_seq(), 3);
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        let mut sender = Sender {
+            next_seq: u16::MAX,
+            ..Sender::default()
+        };
+        let mut receiver = Receiver {
+            expected_seq: u16::MAX,
+        };
+
+        assert_eq!(deliver(&mut sender, &mut receiver, b"a"), b"a");
+        assert_eq!(deliver(&mut sender, &mut receiver, b"b"), b"b");
+        assert_eq!(receiver.expected_seq(), 1);
+    }
+
+    #[test]
+    fn send_refuses_a_second_frame_in_flight() {
+        let mut sender = Sender::default();
+        sender.send(b"a".to_vec()).unwrap();
+
+        assert_eq!(sender.send(b"b".to_vec()), Err(TransferError::Busy));
+    }
+
+    #[test]
+    fn send_rejects_oversized_payloads() {
+        let mut sender = Sender::default();
+
+        assert_eq!(
+            sender.send(vec![0; MAX_PAYLOAD_LEN + 1]),
+            Err(TransferError::TooLong(MAX_PAYLOAD_LEN + 1))
+        );
+        assert!(sender.is_idle());
+    }
+
+    #[test]
+    fn corrupted_data_is_nacked_and_resent() {
+        let mut sender = Sender::default();
+        let mut receiver = Receiver::new();
+        let bytes = sender.send(b"card".to_vec()).unwrap();
+
+        let mut corrupted = bytes.clone();
+        corrupted[HEADER_LEN] ^= 0xff;
+        let received = receiver.on_receive(&corrupted);
+        assert_eq!(received.payload, None);
+        assert_eq!(received.reply, Some(encode(Frame::nack(0))));
+
+        assert_eq!(
+            sender.on_receive(&received.reply.unwrap()),
+            SendAction::Transmit(bytes.clone())
+        );
+        assert_eq!(receiver.on_receive(&bytes).payload, Some(b"card".to_vec()));
+    }
+
+    #[test]
+    fn a_lost_ack_is_reacknowledged_without_delivering_twice() {
+        let mut sender = Sender::default();
+        let mut receiver = Receiver::new();
+        let bytes = sender.send(b"card".to_vec()).unwrap();
+
+        // The ACK_OK never makes it back
+        assert_eq!(receiver.on_receive(&bytes).payload, Some(b"card".to_vec()));
+        let resent = match sender.on_timeout() {
+            SendAction::Transmit(resent) => resent,
+            action => panic!("expected a retransmit, got {:?}", action),
+        };
+
+        let received = receiver.on_receive(&resent);
+        assert_eq!(received.payload, None);
+        assert_eq!(received.reply, Some(encode(Frame::ack_ok(0))));
+        assert_eq!(
+            sender.on_receive(&received.reply.unwrap()),
+            SendAction::Delivered { seq: 0 }
+        );
+    }
+
+    #[test]
+    fn a_nack_for_the_next_frame_counts_as_delivery() {
+        let mut sender = Sender::default();
+        let mut receiver = Receiver::new();
+        let bytes = sender.send(b"card".to_vec()).unwrap();
+
+        // The ACK_OK got lost and the receiver timed out waiting for frame 1
+        receiver.on_receive(&bytes);
+        assert_eq!(
+            sender.on_receive(&receiver.nack()),
+            SendAction::Delivered { seq: 0 }
+        );
+        assert!(sender.is_idle());
+    }
+
+    #[test]
+    fn stale_and_corrupted_answers_are_ignored() {
+        let mut sender = Sender::default();
+        assert_eq!(
+            sender.on_receive(&encode(Frame::ack_ok(0))),
+            SendAction::Ignore
+        );
+
+        sender.send(b"card".to_vec()).unwrap();
+        let mut corrupted = encode(Frame::ack_ok(0));
+        corrupted[1] ^= 1;
+        for bytes in [
+            encode(Frame::ack_ok(5)),
+            encode(Frame::nack(5)),
+            encode(Frame::data(0, Vec::new())),
+            corrupted,
+        ] {
+            assert_eq!(sender.on_receive(&bytes), SendAction::Ignore);
+        }
+        assert!(!sender.is_idle());
+    }
+
+    #[test]
+    fn gives_up_after_max_retransmits() {
+        let mut sender = Sender::new(2);
+        let bytes = sender.send(b"card".to_vec()).unwrap();
+
+        assert_eq!(sender.on_timeout(), SendAction::Transmit(bytes.clone()));
+        assert_eq!(
+            sender.on_receive(&encode(Frame::nack(0))),
+            SendAction::Transmit(bytes)
+        );
+        assert_eq!(
+            sender.on_timeout(),
+            SendAction::Failed(TransferError::RetransmitsExhausted { seq: 0 })
+        );
+        assert!(sender.is_idle());
+        assert_eq!(sender.on_timeout(), SendAction::Ignore);
+    }
+
+    #[test]
+    fn out_of_order_data_is_nacked_with_the_expected_seq() {
+        let mut receiver = Receiver::new();
+
+        let received = receiver.on_receive(&encode(Frame::data(2, b"x".to_vec())));
+        assert_eq!(received.payload, None);
+        assert_eq!(received.reply, Some(encode(Frame::nack(0))));
+        assert_eq!(receiver.expected_seq(), 0);
+    }
+
+    #[test]
+    fn answers_arriving_at_the_receiver_are_ignored() {
+        let mut receiver = Receiver::new();
+
+        let received = receiver.on_receive(&encode(Frame::ack_ok(0)));
+        assert_eq!(
+            received,
+            Received {
+                reply: None,
+                payload: None
+            }
+        );
+    }
+}
@@ -0,0 +1,29 @@
+/// Formats a Bluetooth/MAC address as `aa:bb:cc:dd:ee:ff`
+pub fn format(address: &[u8; 6]) -> String {
+    address
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Parses `aa:bb:cc:dd:ee:ff` (either case), `None` if it is not exactly six
+/// colon separated hex bytes
+pub fn parse(text: &str) -> Option<[u8; 6]> {
+    let mut address = [0u8; 6];
+    let mut parts = text.trim().split(':');
+
+    for byte in address.iter_mut() {
+        let part = parts.next()?;
+        if part.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+
+    if parts.next().is_some() {
+        return None;
+    }
+
+    Some(address)
+}
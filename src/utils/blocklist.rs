@@ -0,0 +1,185 @@
+use super::contact::ContactCard;
+use super::error::DeviceInfoError;
+use super::identity::IdentityKey;
+use super::record::{self, RecordError};
+use super::storage::{KeyValueStore, MAX_LIST_LEN};
+use serde::{Deserialize, Serialize};
+
+/// Key holding every blocklist entry as one sealed record
+pub const BLOCKLIST_KEY: &str = "blocklist";
+
+const BLOCKLIST_VERSION: u8 = 2;
+
+/// Oldest entries are dropped once the list is full, a blocklist entry only
+/// stops reconnecting so losing one is harmless. The whole list is one blob
+/// that also has to stay within [`MAX_LIST_LEN`], with full ~1.3 KB cards that
+/// is reached after 3 entries, with typical ones after the 16 here.
+pub const MAX_ENTRIES: usize = 16;
+
+/// A device we have already exchanged with, together with what it sent us
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlocklistEntry {
+    pub address: [u8; 6],
+    pub serial_num: String,
+    pub device_name: String,
+    pub card: ContactCard,
+    /// Seconds since the unix epoch, or since boot if the clock was never set
+    pub added_at: u64,
+    /// Resolves the device's rotating tokens, `None` for entries written
+    /// before tokens rotated
+    pub identity_key: Option<IdentityKey>,
+}
+
+/// Devices we will not connect to again, see the README
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Blocklist {
+    pub entries: Vec<BlocklistEntry>,
+}
+
+/// Schema version 1, before identity keys were exchanged
+#[derive(Deserialize)]
+struct BlocklistEntryV1 {
+    address: [u8; 6],
+    serial_num: String,
+    device_name: String,
+    card: ContactCard,
+    added_at: u64,
+}
+
+impl From<BlocklistEntryV1> for BlocklistEntry {
+    fn from(v1: BlocklistEntryV1) -> Self {
+        BlocklistEntry {
+            address: v1.address,
+            serial_num: v1.serial_num,
+            device_name: v1.device_name,
+            card: v1.card,
+            added_at: v1.added_at,
+            identity_key: None,
+        }
+    }
+}
+
+impl Blocklist {
+    pub fn load<S: KeyValueStore>(store: &S) -> Result<Self, DeviceInfoError> {
+        let blocklist =
+            record::load_versioned(store, BLOCKLIST_KEY, |version, body| match version {
+                1 => postcard::from_bytes::<Vec<BlocklistEntryV1>>(body)
+                    .map(|entries| Blocklist {
+                        entries: entries.into_iter().map(BlocklistEntry::from).collect(),
+                    })
+                    .map_err(|_| RecordError::Encoding),
+                2 => postcard::from_bytes(body).map_err(|_| RecordError::Encoding),
+                version => Err(RecordError::UnsupportedVersion(version)),
+            })?;
+
+        Ok(blocklist.unwrap_or_default())
+    }
+
+    pub fn save<S: KeyValueStore>(&self, store: &mut S) -> Result<(), DeviceInfoError> {
+        record::save(store, BLOCKLIST_KEY, BLOCKLIST_VERSION, self)
+    }
+
+    pub fn contains(&self, address: &[u8; 6]) -> bool {
+        self.entries.iter().any(|entry| &entry.address == address)
+    }
+
+    /// Adds or refreshes the entry for `entry.address`, dropping the oldest
+    /// entries until the list fits [`MAX_ENTRIES`] and [`MAX_LIST_LEN`]
+    pub fn insert(&mut self, entry: BlocklistEntry) {
+        self.entries
+            .retain(|existing| existing.address != entry.address);
+        self.entries.push(entry);
+
+        // The new entry alone always fits, so this ends with it still in
+        while self.entries.len() > 1
+            && (self.entries.len() > MAX_ENTRIES || record::encoded_len(self) > MAX_LIST_LEN)
+        {
+            let oldest = self
+                .entries
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, existing)| existing.added_at)
+                .map(|(i, _)| i);
+            if let Some(i) = oldest {
+                let dropped = self.entries.remove(i);
+                log::warn!("Blocklist full, dropping {}", dropped.serial_num);
+            }
+        }
+    }
+
+    /// Returns the removed entry, if there was one
+    pub fn remove(&mut self, address: &[u8; 6]) -> Option<BlocklistEntry> {
+        let i = self
+            .entries
+            .iter()
+            .position(|entry| &entry.address == address)?;
+        Some(self.entries.remove(i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::fixtures::full_card;
+    use crate::utils::storage::MemoryStore;
+
+    fn entry(i: u8, card: ContactCard) -> BlocklistEntry {
+        BlocklistEntry {
+            address: [i; 6],
+            serial_num: format!("DU-{:010}-X", i),
+            device_name: "dap-up".into(),
+            card,
+            added_at: i as u64,
+            identity_key: Some([i; 16]),
+        }
+    }
+
+    #[test]
+    fn insert_refreshes_an_existing_address() {
+        let mut blocklist = Blocklist::default();
+        blocklist.insert(entry(1, ContactCard::default()));
+        let mut refreshed = entry(1, ContactCard::default());
+        refreshed.added_at = 100;
+        blocklist.insert(refreshed.clone());
+
+        assert_eq!(blocklist.entries, vec![refreshed]);
+    }
+
+    #[test]
+    fn insert_drops_the_oldest_entry_when_full() {
+        let mut blocklist = Blocklist::default();
+        for i in 1..=MAX_ENTRIES as u8 + 1 {
+            blocklist.insert(entry(i, ContactCard::default()));
+        }
+
+        assert_eq!(blocklist.entries.len(), MAX_ENTRIES);
+        assert!(!blocklist.contains(&[1; 6]));
+        assert!(blocklist.contains(&[MAX_ENTRIES as u8 + 1; 6]));
+    }
+
+    #[test]
+    fn round_trips_through_the_store() {
+        let mut store = MemoryStore::default();
+        assert_eq!(Blocklist::load(&store).unwrap(), Blocklist::default());
+
+        let mut blocklist = Blocklist::default();
+        blocklist.insert(entry(1, full_card()));
+        blocklist.insert(entry(2, ContactCard::default()));
+        blocklist.save(&mut store).unwrap();
+
+        assert_eq!(Blocklist::load(&store).unwrap(), blocklist);
+    }
+
+    #[test]
+    fn remove_returns_the_entry() {
+        let mut blocklist = Blocklist::default();
+        blocklist.insert(entry(1, ContactCard::default()));
+
+        assert_eq!(blocklist.remove(&[2; 6]), None);
+        assert_eq!(
+            blocklist.remove(&[1; 6]),
+            Some(entry(1, ContactCard::default()))
+        );
+        assert!(blocklist.entries.is_empty());
+    }
+}
@@ -0,0 +1,48 @@
+//! Where the current time comes from. Logic that only reads the time through
+//! a [`Clock`] can be run against a simulated one.
+
+use std::time::Instant;
+
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// The monotonic system clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to, clones share the same time
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: std::rc::Rc<std::cell::Cell<Instant>>,
+}
+
+#[cfg(test)]
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock {
+            now: std::rc::Rc::new(std::cell::Cell::new(Instant::now())),
+        }
+    }
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn advance(&self, by: std::time::Duration) {
+        self.now.set(self.now.get() + by);
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
@@ -0,0 +1,265 @@
+use super::error::DeviceInfoError;
+use super::record;
+use super::validate;
+use serde::{Deserialize, Serialize};
+
+/// Schema version of the card as it is sent to other devices
+pub const CARD_VERSION: u8 = 1;
+
+/// Names the card in errors, it is stored as part of the profile
+const CARD_KEY: &str = "card";
+
+pub const MAX_DISPLAY_NAME_LEN: usize = 64;
+pub const MAX_PRONOUNS_LEN: usize = 24;
+pub const MAX_ORGANISATION_LEN: usize = 64;
+pub const MAX_ROLE_LEN: usize = 64;
+pub const MAX_EMAIL_LEN: usize = 96;
+pub const MAX_BIO_LEN: usize = 160;
+pub const MAX_NETWORK_LEN: usize = 16;
+pub const MAX_HANDLE_LEN: usize = 64;
+pub const MAX_LINK_LEN: usize = 128;
+pub const MAX_SOCIALS: usize = 4;
+pub const MAX_LINKS: usize = 4;
+
+/// Card fields as named by [`ContactCard::set`]
+pub const CARD_FIELDS: &[&str] = &[
+    "display_name",
+    "pronouns",
+    "organisation",
+    "role",
+    "email",
+    "bio",
+    "socials",
+    "links",
+];
+
+/// A handle on some social network, e.g. `("mastodon", "@tk@example.social")`
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SocialHandle {
+    pub network: String,
+    pub handle: String,
+}
+
+/// The user info exchanged between two badges.
+///
+/// Empty strings mean "not shared". Every field has a byte limit so a full card
+/// stays around 1 KB, see [`ContactCard::validate`].
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContactCard {
+    pub display_name: String,
+    pub pronouns: String,
+    pub organisation: String,
+    pub role: String,
+    pub email: String,
+    pub socials: Vec<SocialHandle>,
+    pub bio: String,
+    pub links: Vec<String>,
+}
+
+impl ContactCard {
+    /// Checks every field against its limit and for control characters,
+    /// reporting the first one that is off
+    pub fn validate(&self) -> Result<(), DeviceInfoError> {
+        validate::check("display_name", &self.display_name, MAX_DISPLAY_NAME_LEN)?;
+        validate::check("pronouns", &self.pronouns, MAX_PRONOUNS_LEN)?;
+        validate::check("organisation", &self.organisation, MAX_ORGANISATION_LEN)?;
+        validate::check("role", &self.role, MAX_ROLE_LEN)?;
+        validate::check("email", &self.email, MAX_EMAIL_LEN)?;
+        validate::check("bio", &self.bio, MAX_BIO_LEN)?;
+
+        check_count("socials", self.socials.len(), MAX_SOCIALS)?;
+        for social in &self.socials {
+            validate::check("social_network", &social.network, MAX_NETWORK_LEN)?;
+            validate::check("social_handle", &social.handle, MAX_HANDLE_LEN)?;
+        }
+
+        check_count("links", self.links.len(), MAX_LINKS)?;
+        for link in &self.links {
+            validate::check("link", link, MAX_LINK_LEN)?;
+        }
+
+        Ok(())
+    }
+
+    /// Trims and NFC normalises every field, then checks the limits.
+    /// Empty socials and links are dropped.
+    pub fn normalize(&self) -> Result<Self, DeviceInfoError> {
+        let mut socials = Vec::new();
+        for social in &self.socials {
+            let social = SocialHandle {
+                network: validate::normalize("social_network", &social.network, MAX_NETWORK_LEN)?,
+                handle: validate::normalize("social_handle", &social.handle, MAX_HANDLE_LEN)?,
+            };
+            if !social.handle.is_empty() {
+                socials.push(social);
+            }
+        }
+
+        let mut links = Vec::new();
+        for link in &self.links {
+            let link = validate::normalize("link", link, MAX_LINK_LEN)?;
+            if !link.is_empty() {
+                links.push(link);
+            }
+        }
+
+        let card = ContactCard {
+            display_name: validate::normalize(
+                "display_name",
+                &self.display_name,
+                MAX_DISPLAY_NAME_LEN,
+            )?,
+            pronouns: validate::normalize("pronouns", &self.pronouns, MAX_PRONOUNS_LEN)?,
+            organisation: validate::normalize(
+                "organisation",
+                &self.organisation,
+                MAX_ORGANISATION_LEN,
+            )?,
+            role: validate::normalize("role", &self.role, MAX_ROLE_LEN)?,
+            email: validate::normalize("email", &self.email, MAX_EMAIL_LEN)?,
+            socials,
+            bio: validate::normalize("bio", &self.bio, MAX_BIO_LEN)?,
+            links,
+        };
+
+        card.validate()?;
+        Ok(card)
+    }
+
+    /// Changes one field from its text form, as typed into the console.
+    ///
+    /// `socials` takes space separated `network:handle` pairs and `links`
+    /// space separated URLs, both replace the whole list. Limits are checked
+    /// by [`ContactCard::normalize`] when the card is stored.
+    pub fn set(&mut self, field: &str, value: &str) -> anyhow::Result<()> {
+        match field {
+            "display_name" => self.display_name = value.to_string(),
+            "pronouns" => self.pronouns = value.to_string(),
+            "organisation" => self.organisation = value.to_string(),
+            "role" => self.role = value.to_string(),
+            "email" => self.email = value.to_string(),
+            "bio" => self.bio = value.to_string(),
+            "socials" => {
+                self.socials = value
+                    .split_whitespace()
+                    .map(|pair| match pair.split_once(':') {
+                        Some((network, handle)) => Ok(SocialHandle {
+                            network: network.to_string(),
+                            handle: handle.to_string(),
+                        }),
+                        None => Err(anyhow::anyhow!("'{}' is not network:handle", pair)),
+                    })
+                    .collect::<anyhow::Result<_>>()?
+            }
+            "links" => self.links = value.split_whitespace().map(str::to_string).collect(),
+            _ => anyhow::bail!(
+                "Unknown card field '{}', expected one of {}",
+                field,
+                CARD_FIELDS.join(", ")
+            ),
+        }
+
+        Ok(())
+    }
+
+    /// Encodes the card into the sealed form sent over the air
+    pub fn encode(&self) -> Result<Vec<u8>, DeviceInfoError> {
+        self.validate()?;
+        record::encode(self, CARD_KEY, CARD_VERSION)
+    }
+
+    /// Decodes a card received from another device, rejecting anything over the
+    /// limits since the sender can not be trusted to have checked them
+    pub fn decode(bytes: &[u8]) -> Result<Self, DeviceInfoError> {
+        let card: ContactCard = record::decode(bytes, CARD_KEY, CARD_VERSION)?;
+
+        card.validate()?;
+        Ok(card)
+    }
+
+    pub fn print(&self) {
+        println!(
+            "Contact card: \nName: {} ({})\nOrganisation: {}\nRole: {}\nEmail: {}\nBio: {}",
+            self.display_name, self.pronouns, self.organisation, self.role, self.email, self.bio
+        );
+        for social in &self.socials {
+            println!("{}: {}", social.network, social.handle);
+        }
+        for link in &self.links {
+            println!("Link: {}", link);
+        }
+        println!();
+    }
+}
+
+fn check_count(key: &'static str, count: usize, max: usize) -> Result<(), DeviceInfoError> {
+    if count > max {
+        return Err(DeviceInfoError::TooManyEntries { key, count, max });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_changes_one_field() {
+        let mut card = ContactCard::default();
+        card.set("display_name", "Alex").unwrap();
+        card.set("bio", "Likes badges").unwrap();
+
+        assert_eq!(card.display_name, "Alex");
+        assert_eq!(card.bio, "Likes badges");
+        assert_eq!(card.email, "");
+
+        card.set("bio", "").unwrap();
+        assert_eq!(card.bio, "");
+    }
+
+    #[test]
+    fn set_replaces_lists() {
+        let mut card = ContactCard::default();
+        card.set("socials", "mastodon:@alex@example.social github:alex")
+            .unwrap();
+        card.set("links", "https://example.com https://example.org")
+            .unwrap();
+
+        assert_eq!(
+            card.socials,
+            vec![
+                SocialHandle {
+                    network: "mastodon".to_string(),
+                    handle: "@alex@example.social".to_string(),
+                },
+                SocialHandle {
+                    network: "github".to_string(),
+                    handle: "alex".to_string(),
+                },
+            ]
+        );
+        assert_eq!(card.links.len(), 2);
+
+        card.set("socials", "").unwrap();
+        assert!(card.socials.is_empty());
+    }
+
+    #[test]
+    fn set_rejects_unknown_fields_and_malformed_socials() {
+        let mut card = ContactCard::default();
+        assert!(card.set("phone", "123").is_err());
+        assert!(card.set("socials", "alex").is_err());
+        assert_eq!(card, ContactCard::default());
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let mut card = ContactCard::default();
+        card.set("display_name", "Alex").unwrap();
+        card.set("links", "https://example.com").unwrap();
+
+        let bytes = card.encode().unwrap();
+        assert_eq!(ContactCard::decode(&bytes).unwrap(), card);
+    }
+}
@@ -0,0 +1,173 @@
+use super::contact::ContactCard;
+use super::error::DeviceInfoError;
+use super::identity::IdentityKey;
+use super::record::{self, RecordError};
+use super::storage::{KeyValueStore, MAX_LIST_LEN};
+use serde::{Deserialize, Serialize};
+
+/// Key holding every received contact as one sealed record
+pub const CONTACTS_KEY: &str = "contacts";
+
+const CONTACTS_VERSION: u8 = 2;
+
+/// Same reasoning as the blocklist, the whole list is one NVS blob that also
+/// has to stay within [`MAX_LIST_LEN`]
+pub const MAX_CONTACTS: usize = 16;
+
+/// A card received from another badge, kept after its blocklist entry expires
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Contact {
+    pub serial_num: String,
+    pub card: ContactCard,
+    /// Seconds since the unix epoch, or since boot if the clock was never set
+    pub received_at: u64,
+    /// Resolves the sender's rotating tokens, `None` for contacts received
+    /// before tokens rotated
+    pub identity_key: Option<IdentityKey>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Contacts {
+    pub entries: Vec<Contact>,
+}
+
+/// Schema version 1, before identity keys were exchanged
+#[derive(Deserialize)]
+struct ContactV1 {
+    serial_num: String,
+    card: ContactCard,
+    received_at: u64,
+}
+
+impl From<ContactV1> for Contact {
+    fn from(v1: ContactV1) -> Self {
+        Contact {
+            serial_num: v1.serial_num,
+            card: v1.card,
+            received_at: v1.received_at,
+            identity_key: None,
+        }
+    }
+}
+
+impl Contacts {
+    pub fn load<S: KeyValueStore>(store: &S) -> Result<Self, DeviceInfoError> {
+        let contacts =
+            record::load_versioned(store, CONTACTS_KEY, |version, body| match version {
+                1 => postcard::from_bytes::<Vec<ContactV1>>(body)
+                    .map(|entries| Contacts {
+                        entries: entries.into_iter().map(Contact::from).collect(),
+                    })
+                    .map_err(|_| RecordError::Encoding),
+                2 => postcard::from_bytes(body).map_err(|_| RecordError::Encoding),
+                version => Err(RecordError::UnsupportedVersion(version)),
+            })?;
+
+        Ok(contacts.unwrap_or_default())
+    }
+
+    pub fn save<S: KeyValueStore>(&self, store: &mut S) -> Result<(), DeviceInfoError> {
+        record::save(store, CONTACTS_KEY, CONTACTS_VERSION, self)
+    }
+
+    /// Adds the contact or replaces the card we already had for that serial.
+    /// Returns `false` if the list is full and the contact was not saved,
+    /// received contacts are never dropped to make room.
+    pub fn upsert(&mut self, contact: Contact) -> bool {
+        let existing = self
+            .entries
+            .iter()
+            .position(|existing| existing.serial_num == contact.serial_num);
+
+        let previous = match existing {
+            Some(i) => Some((i, std::mem::replace(&mut self.entries[i], contact))),
+            None if self.entries.len() >= MAX_CONTACTS => {
+                log::warn!("Contact list full, not saving {}", contact.serial_num);
+                return false;
+            }
+            None => {
+                self.entries.push(contact);
+                None
+            }
+        };
+
+        if record::encoded_len(self) <= MAX_LIST_LEN {
+            return true;
+        }
+
+        let rejected = match previous {
+            Some((i, previous)) => std::mem::replace(&mut self.entries[i], previous),
+            None => self.entries.pop().expect("just pushed"),
+        };
+        log::warn!("Contact list full, not saving {}", rejected.serial_num);
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::fixtures::full_card;
+    use crate::utils::storage::MemoryStore;
+
+    fn contact(i: u8, card: ContactCard) -> Contact {
+        Contact {
+            serial_num: format!("DU-{:010}-X", i),
+            card,
+            received_at: i as u64,
+            identity_key: Some([i; 16]),
+        }
+    }
+
+    #[test]
+    fn upsert_replaces_the_card_for_a_serial() {
+        let mut contacts = Contacts::default();
+        assert!(contacts.upsert(contact(1, ContactCard::default())));
+        assert!(contacts.upsert(contact(1, full_card())));
+
+        assert_eq!(contacts.entries, vec![contact(1, full_card())]);
+    }
+
+    #[test]
+    fn upsert_rejects_new_contacts_when_full() {
+        let mut contacts = Contacts::default();
+        for i in 1..=MAX_CONTACTS as u8 {
+            assert!(contacts.upsert(contact(i, ContactCard::default())));
+        }
+
+        assert!(!contacts.upsert(contact(100, ContactCard::default())));
+        assert_eq!(contacts.entries.len(), MAX_CONTACTS);
+        // Known serials can still be updated
+        assert!(contacts.upsert(contact(1, full_card())));
+    }
+
+    #[test]
+    fn a_rejected_update_keeps_the_previous_card() {
+        let mut contacts = Contacts::default();
+        let mut i = 1;
+        while contacts.upsert(contact(i, full_card())) {
+            i += 1;
+        }
+        // Room for a small card, but not for growing it to a full one
+        assert!(contacts.upsert(contact(i, ContactCard::default())));
+        assert!(!contacts.upsert(contact(i, full_card())));
+
+        assert_eq!(
+            contacts.entries.last(),
+            Some(&contact(i, ContactCard::default()))
+        );
+    }
+
+    #[test]
+    fn round_trips_through_the_store() {
+        let mut store = MemoryStore::default();
+        assert_eq!(Contacts::load(&store).unwrap(), Contacts::default());
+
+        let mut contacts = Contacts::default();
+        contacts.upsert(contact(1, full_card()));
+        contacts.upsert(contact(2, ContactCard::default()));
+        contacts.save(&mut store).unwrap();
+
+        assert_eq!(Contacts::load(&store).unwrap(), contacts);
+    }
+}
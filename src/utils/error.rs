@@ -0,0 +1,124 @@
+use super::record::RecordError;
+use super::storage::InvalidUtf8;
+use std::fmt;
+
+/// Why loading or updating the device profile failed
+#[derive(Debug)]
+pub enum DeviceInfoError {
+    /// The key has never been written
+    MissingKey(&'static str),
+    /// The value is longer than the storage layout allows
+    ValueTooLong {
+        key: &'static str,
+        len: usize,
+        max: usize,
+    },
+    /// A required value is empty once trimmed
+    EmptyValue(&'static str),
+    /// The value contains a control character
+    InvalidCharacter { key: &'static str, character: char },
+    /// A list holds more entries than the storage layout allows
+    TooManyEntries {
+        key: &'static str,
+        count: usize,
+        max: usize,
+    },
+    /// A list that grows with every exchange has no room for another entry
+    ListFull(&'static str),
+    /// The storage has less space left than a write needs
+    StorageFull { needed: usize, free: usize },
+    /// The stored bytes are not valid UTF-8, most likely corrupted
+    InvalidUtf8(&'static str),
+    /// A sealed record failed its integrity checks or could not be decoded
+    CorruptRecord {
+        key: &'static str,
+        reason: RecordError,
+    },
+    /// The storage backend itself failed
+    Storage(anyhow::Error),
+    /// The serial number can not be changed through `DeviceInfo::update`
+    SerialNumberImmutable,
+    /// The serial number has been provisioned and locked
+    SerialNumberLocked,
+    /// The factory key was wrong or factory mode is not configured
+    FactoryAuthFailed,
+    /// The owner can only be changed once the device has been registered
+    OwnerNotRegistered,
+}
+
+impl DeviceInfoError {
+    /// Converts a backend error for `key`, keeping UTF-8 failures distinguishable
+    pub(crate) fn from_storage(key: &'static str, error: anyhow::Error) -> Self {
+        if error.is::<InvalidUtf8>() {
+            DeviceInfoError::InvalidUtf8(key)
+        } else {
+            DeviceInfoError::Storage(error)
+        }
+    }
+}
+
+impl fmt::Display for DeviceInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceInfoError::MissingKey(key) => write!(f, "Keyword [{}] not found", key),
+            DeviceInfoError::ValueTooLong { key, len, max } => write!(
+                f,
+                "Value for [{}] is {} bytes, at most {} bytes are allowed",
+                key, len, max
+            ),
+            DeviceInfoError::EmptyValue(key) => write!(f, "Value for [{}] can not be empty", key),
+            DeviceInfoError::InvalidCharacter { key, character } => write!(
+                f,
+                "Value for [{}] contains the control character {:?}",
+                key, character
+            ),
+            DeviceInfoError::TooManyEntries { key, count, max } => write!(
+                f,
+                "[{}] has {} entries, at most {} are allowed",
+                key, count, max
+            ),
+            DeviceInfoError::ListFull(key) => write!(f, "[{}] is full", key),
+            DeviceInfoError::StorageFull { needed, free } => write!(
+                f,
+                "Storage has {} bytes free, {} bytes are needed",
+                free, needed
+            ),
+            DeviceInfoError::InvalidUtf8(key) => {
+                write!(f, "Value for [{}] is not valid UTF-8", key)
+            }
+            DeviceInfoError::CorruptRecord { key, reason } => {
+                write!(f, "Record [{}] is unreadable: {}", key, reason)
+            }
+            DeviceInfoError::Storage(e) => write!(f, "Storage failure: {}", e),
+            DeviceInfoError::SerialNumberImmutable => write!(
+                f,
+                "Unable to rewrite over serial number, manual code change required"
+            ),
+            DeviceInfoError::SerialNumberLocked => write!(
+                f,
+                "Serial number is provisioned and locked, factory mode is required to change it"
+            ),
+            DeviceInfoError::FactoryAuthFailed => write!(f, "Factory mode authentication failed"),
+            DeviceInfoError::OwnerNotRegistered => write!(
+                f,
+                "Device has not been registered to a user, please manually create the user or register to someone"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DeviceInfoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DeviceInfoError::CorruptRecord { reason, .. } => Some(reason),
+            DeviceInfoError::Storage(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for DeviceInfoError {
+    fn from(error: anyhow::Error) -> Self {
+        DeviceInfoError::Storage(error)
+    }
+}
@@ -0,0 +1,136 @@
+use super::contact::ContactCard;
+use super::error::DeviceInfoError;
+use super::identity::{self, IdentityKey};
+use super::record;
+use super::serial::{DeviceInfo, MAX_VALUE_LEN};
+use super::storage::KeyValueStore;
+use super::validate;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Schema version of the profile as it is sent to other devices
+pub const EXCHANGE_VERSION: u8 = 1;
+
+/// Names the profile in errors, it is never stored under this key
+const EXCHANGE_KEY: &str = "exchange";
+
+/// Bytes of SHA-256 kept, short enough for one notification at the default MTU
+pub const DIGEST_LEN: usize = 16;
+
+/// Fingerprint of a sealed profile, acknowledged by the peer before either
+/// side commits to the exchange
+pub type ProfileDigest = [u8; DIGEST_LEN];
+
+/// Digest of the sealed bytes exactly as they went over the air
+pub fn digest(sealed: &[u8]) -> ProfileDigest {
+    let hash = Sha256::digest(sealed);
+    let mut digest = [0; DIGEST_LEN];
+    digest.copy_from_slice(&hash[..DIGEST_LEN]);
+    digest
+}
+
+/// Everything one badge hands the other during an exchange
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExchangeProfile {
+    pub serial_num: String,
+    pub device_name: String,
+    pub card: ContactCard,
+    /// Lets the receiver recognise our rotating tokens later on. It travels
+    /// over an unencrypted link, so anyone sniffing the exchange can do the
+    /// same, see the README.
+    pub identity_key: IdentityKey,
+}
+
+impl ExchangeProfile {
+    /// Builds our own profile from what is stored on this device
+    pub fn local<S: KeyValueStore>(store: &mut S) -> Result<Self, DeviceInfoError> {
+        let info = DeviceInfo::new(store);
+
+        Ok(ExchangeProfile {
+            serial_num: info.serial_num,
+            device_name: info.device_name,
+            card: info.card,
+            identity_key: identity::identity_key(store)?,
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), DeviceInfoError> {
+        validate::check("serial_num", &self.serial_num, MAX_VALUE_LEN)?;
+        validate::check("device_name", &self.device_name, MAX_VALUE_LEN)?;
+        self.card.validate()
+    }
+
+    /// Encodes the profile into the sealed form sent over the air
+    pub fn encode(&self) -> Result<Vec<u8>, DeviceInfoError> {
+        self.validate()?;
+        record::encode(self, EXCHANGE_KEY, EXCHANGE_VERSION)
+    }
+
+    /// Decodes a profile received from another device, the sender can not be
+    /// trusted to have checked the limits
+    pub fn decode(bytes: &[u8]) -> Result<Self, DeviceInfoError> {
+        let profile: ExchangeProfile = record::decode(bytes, EXCHANGE_KEY, EXCHANGE_VERSION)?;
+
+        profile.validate()?;
+        Ok(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile() -> ExchangeProfile {
+        ExchangeProfile {
+            serial_num: "SN-0042".to_string(),
+            device_name: "Badge".to_string(),
+            card: ContactCard {
+                display_name: "Alex".to_string(),
+                ..ContactCard::default()
+            },
+            identity_key: [9; identity::IDENTITY_KEY_LEN],
+        }
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let bytes = profile().encode().unwrap();
+        assert_eq!(ExchangeProfile::decode(&bytes).unwrap(), profile());
+    }
+
+    #[test]
+    fn decode_checks_the_limits() {
+        let mut long = profile();
+        long.device_name = "x".repeat(MAX_VALUE_LEN + 1);
+        assert!(long.encode().is_err());
+
+        // A sender that skipped the checks
+        let bytes = record::encode(&long, EXCHANGE_KEY, EXCHANGE_VERSION).unwrap();
+        assert!(matches!(
+            ExchangeProfile::decode(&bytes),
+            Err(DeviceInfoError::ValueTooLong { .. })
+        ));
+    }
+
+    #[test]
+    fn decode_rejects_other_versions() {
+        let bytes = record::encode(&profile(), EXCHANGE_KEY, EXCHANGE_VERSION + 1).unwrap();
+        assert!(matches!(
+            ExchangeProfile::decode(&bytes),
+            Err(DeviceInfoError::CorruptRecord {
+                reason: record::RecordError::UnsupportedVersion(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn digest_covers_every_byte() {
+        let bytes = profile().encode().unwrap();
+        let mut flipped = bytes.clone();
+        flipped[bytes.len() / 2] ^= 1;
+
+        assert_eq!(digest(&bytes), digest(&bytes.clone()));
+        assert_ne!(digest(&bytes), digest(&flipped));
+    }
+}
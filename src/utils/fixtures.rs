@@ -0,0 +1,71 @@
+//! Test data shared by the stored lists, which all have to fit the same NVS
+//! budget.
+
+use super::blocklist::{Blocklist, BlocklistEntry, MAX_ENTRIES};
+use super::contact::{ContactCard, SocialHandle, MAX_LINKS, MAX_SOCIALS};
+use super::contacts::{Contact, Contacts, MAX_CONTACTS};
+use super::record;
+use super::storage::MAX_LIST_LEN;
+
+/// A card with every field at its limit
+pub fn full_card() -> ContactCard {
+    ContactCard {
+        display_name: "n".repeat(64),
+        pronouns: "p".repeat(24),
+        organisation: "o".repeat(64),
+        role: "r".repeat(64),
+        email: "e".repeat(96),
+        socials: vec![
+            SocialHandle {
+                network: "s".repeat(16),
+                handle: "h".repeat(64),
+            };
+            MAX_SOCIALS
+        ],
+        bio: "b".repeat(160),
+        links: vec!["l".repeat(128); MAX_LINKS],
+    }
+}
+
+#[test]
+fn full_cards_stay_within_the_nvs_budget() {
+    let mut contacts = Contacts::default();
+    let mut blocklist = Blocklist::default();
+    for i in 1..=MAX_CONTACTS.max(MAX_ENTRIES) as u8 {
+        contacts.upsert(Contact {
+            serial_num: format!("DU-{:010}-X", i),
+            card: full_card(),
+            received_at: i as u64,
+            identity_key: Some([i; 16]),
+        });
+        blocklist.insert(BlocklistEntry {
+            address: [i; 6],
+            serial_num: format!("DU-{:010}-X", i),
+            device_name: "dap-up".into(),
+            card: full_card(),
+            added_at: i as u64,
+            identity_key: Some([i; 16]),
+        });
+
+        assert!(record::encoded_len(&contacts) <= MAX_LIST_LEN);
+        assert!(record::encoded_len(&blocklist) <= MAX_LIST_LEN);
+        assert!(blocklist.contains(&[i; 6]));
+    }
+
+    // Both lists run out of room before they run out of entries
+    for len in [contacts.entries.len(), blocklist.entries.len()] {
+        assert!(len >= 3);
+        assert!(len < MAX_CONTACTS.min(MAX_ENTRIES));
+    }
+
+    // The contacts keep the earliest ones, the blocklist the newest
+    let kept = contacts.entries.len() as u8;
+    for (i, contact) in (1..=kept).zip(&contacts.entries) {
+        assert_eq!(contact.received_at, i as u64);
+    }
+    let last = MAX_CONTACTS.max(MAX_ENTRIES) as u8;
+    let kept = blocklist.entries.len() as u8;
+    for i in last - kept + 1..=last {
+        assert!(blocklist.contains(&[i; 6]));
+    }
+}
@@ -0,0 +1,256 @@
+#[cfg(not(target_os = "espidf"))]
+use super::address;
+use super::random;
+use super::storage::KeyValueStore;
+use anyhow::Result;
+
+/// Crockford base32, no I, L, O or U so it reads back unambiguously
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// Prefix marking a serial derived from the MAC rather than provisioned
+pub const DEFAULT_SERIAL_PREFIX: &str = "DU-";
+
+/// Derives the identifier used until a real serial number is provisioned.
+///
+/// The 48 bit MAC is written as 10 base32 characters followed by a Luhn mod 32
+/// check character, e.g. `24:0a:c4:12:34:56`
+/// becomes `DU-141B214D2P-F`.
+pub fn default_serial_from_mac(mac: [u8; 6]) -> String {
+    let mut value = mac.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+
+    let mut digits = [0u8; 10];
+    for digit in digits.iter_mut().rev() {
+        *digit = (value & 0x1f) as u8;
+        value >>= 5;
+    }
+
+    let mut serial = String::from(DEFAULT_SERIAL_PREFIX);
+    serial.extend(digits.iter().map(|d| ALPHABET[*d as usize] as char));
+    serial.push('-');
+    serial.push(ALPHABET[luhn_check(&digits) as usize] as char);
+
+    serial
+}
+
+/// Returns `true` if `serial` looks like [`default_serial_from_mac`] output
+/// with a matching check character
+pub fn is_default_serial(serial: &str) -> bool {
+    let body = match serial.strip_prefix(DEFAULT_SERIAL_PREFIX) {
+        Some(body) => body,
+        None => return false,
+    };
+
+    let (digits, check) = match body.split_once('-') {
+        Some((digits, check)) if digits.len() == 10 && check.len() == 1 => (digits, check),
+        _ => return false,
+    };
+
+    let mut values = [0u8; 10];
+    for (value, c) in values.iter_mut().zip(digits.bytes()) {
+        match decode_char(c) {
+            Some(v) => *value = v,
+            None => return false,
+        }
+    }
+
+    decode_char(check.as_bytes()[0]) == Some(luhn_check(&values))
+}
+
+fn decode_char(c: u8) -> Option<u8> {
+    ALPHABET
+        .iter()
+        .position(|a| *a == c.to_ascii_uppercase())
+        .map(|p| p as u8)
+}
+
+/// Luhn mod N check character over base32 digits, catches every single
+/// character error and every swap of two neighbours except `0` and `Z`
+fn luhn_check(digits: &[u8]) -> u8 {
+    const N: u32 = 32;
+
+    let sum = digits.iter().rev().enumerate().fold(0u32, |sum, (i, d)| {
+        let mut addend = *d as u32;
+        if i % 2 == 0 {
+            addend *= 2;
+            addend = addend / N + addend % N;
+        }
+        sum + addend
+    });
+
+    ((N - sum % N) % N) as u8
+}
+
+/// Reads the factory programmed base MAC from eFuse
+#[cfg(target_os = "espidf")]
+pub fn factory_mac() -> Result<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    esp_idf_svc::sys::esp!(unsafe {
+        esp_idf_svc::sys::esp_efuse_mac_get_default(mac.as_mut_ptr())
+    })?;
+    Ok(mac)
+}
+
+/// Host stand-in for the eFuse MAC, taken from `DAPUP_MAC` (`aa:bb:cc:dd:ee:ff`)
+#[cfg(not(target_os = "espidf"))]
+pub fn factory_mac() -> Result<[u8; 6]> {
+    let text = match std::env::var("DAPUP_MAC") {
+        Ok(text) => text,
+        // Locally administered address, never handed out by a vendor
+        Err(_) => return Ok([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]),
+    };
+
+    address::parse(&text)
+        .ok_or_else(|| anyhow::anyhow!("DAPUP_MAC '{}' is not a MAC address", text))
+}
+
+/// Serial number to fall back to while none is provisioned
+pub fn default_serial() -> Result<String> {
+    Ok(default_serial_from_mac(factory_mac()?))
+}
+
+/// Key holding the secret behind our rotating advertisement tokens
+pub const IDENTITY_KEY_KEY: &str = "identity_key";
+
+pub const IDENTITY_KEY_LEN: usize = 16;
+
+/// Secret shared with every badge we exchanged with, lets them recognise our
+/// rotating tokens (see `ble::privacy`) while nobody else can link them.
+///
+/// Like the serial number it is never wiped, a factory reset keeps the badge
+/// recognisable to the people who already know it.
+pub type IdentityKey = [u8; IDENTITY_KEY_LEN];
+
+/// Reads our identity key, generating and storing one on first use
+pub fn identity_key<S: KeyValueStore>(store: &mut S) -> Result<IdentityKey> {
+    if let Some(bytes) = store.get_blob(IDENTITY_KEY_KEY)? {
+        match IdentityKey::try_from(bytes.as_slice()) {
+            Ok(key) => return Ok(key),
+            Err(_) => log::warn!(
+                "Identity key has {} bytes instead of {}, generating a new one",
+                bytes.len(),
+                IDENTITY_KEY_LEN
+            ),
+        }
+    }
+
+    let mut key = [0u8; IDENTITY_KEY_LEN];
+    random::fill(&mut key);
+    store.set_blob(IDENTITY_KEY_KEY, &key)?;
+
+    log::info!("Generated a new identity key");
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::storage::MemoryStore;
+
+    const MACS: &[[u8; 6]] = &[
+        [0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56],
+        [0x02, 0x00, 0x00, 0x00, 0x00, 0x01],
+        [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff],
+        [0xff; 6],
+        [0x00; 6],
+    ];
+
+    #[test]
+    fn known_mac_to_serial() {
+        assert_eq!(
+            default_serial_from_mac([0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56]),
+            "DU-141B214D2P-F"
+        );
+        assert_eq!(
+            default_serial_from_mac([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]),
+            "DU-0200000001-T"
+        );
+    }
+
+    #[test]
+    fn derived_serials_pass_the_check() {
+        for mac in MACS {
+            let serial = default_serial_from_mac(*mac);
+            assert!(is_default_serial(&serial), "{}", serial);
+            // Typed back in by hand the digits may come out in lower case
+            let (prefix, body) = serial.split_at(DEFAULT_SERIAL_PREFIX.len());
+            let typed = format!("{}{}", prefix, body.to_lowercase());
+            assert!(is_default_serial(&typed), "{}", typed);
+        }
+    }
+
+    #[test]
+    fn every_single_character_error_is_caught() {
+        for mac in MACS {
+            let serial = default_serial_from_mac(*mac);
+            let start = DEFAULT_SERIAL_PREFIX.len();
+
+            // The ten digits and the check character, skipping the dash
+            for i in (start..start + 10).chain([serial.len() - 1]) {
+                for &c in ALPHABET {
+                    if c == serial.as_bytes()[i] {
+                        continue;
+                    }
+
+                    let mut wrong = serial.clone().into_bytes();
+                    wrong[i] = c;
+                    let wrong = String::from_utf8(wrong).unwrap();
+                    assert!(!is_default_serial(&wrong), "{} passed", wrong);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn neighbour_swaps_are_caught() {
+        let mut checked = 0;
+        for mac in MACS {
+            let serial = default_serial_from_mac(*mac);
+            let start = DEFAULT_SERIAL_PREFIX.len();
+
+            for i in start..start + 9 {
+                let (a, b) = (serial.as_bytes()[i], serial.as_bytes()[i + 1]);
+                // The one pair Luhn mod 32 can not tell apart
+                if a == b || (a.min(b), a.max(b)) == (b'0', b'Z') {
+                    continue;
+                }
+
+                let mut swapped = serial.clone().into_bytes();
+                swapped.swap(i, i + 1);
+                let swapped = String::from_utf8(swapped).unwrap();
+                assert!(!is_default_serial(&swapped), "{} passed", swapped);
+                checked += 1;
+            }
+        }
+        assert!(checked > 0);
+    }
+
+    #[test]
+    fn malformed_serials_are_rejected() {
+        for serial in [
+            "",
+            "DU-141B214D2P",
+            "XX-141B214D2P-F",
+            "DU-141B214D2-F",
+            "DU-141B214D2PP-F",
+            "DU-141B214D2P-FF",
+            "DU-141B214DUP-F",
+            "SN-000123",
+        ] {
+            assert!(!is_default_serial(serial), "{} passed", serial);
+        }
+    }
+
+    #[test]
+    fn identity_key_is_generated_once() {
+        let mut store = MemoryStore::new();
+        let key = identity_key(&mut store).unwrap();
+        assert_eq!(identity_key(&mut store).unwrap(), key);
+
+        store.set_blob(IDENTITY_KEY_KEY, &[1, 2, 3]).unwrap();
+        let replaced = identity_key(&mut store).unwrap();
+        assert_eq!(
+            store.get_blob(IDENTITY_KEY_KEY).unwrap(),
+            Some(replaced.to_vec())
+        );
+    }
+}
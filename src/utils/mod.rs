@@ -1 +1,20 @@
+pub mod address;
+pub mod blocklist;
+pub mod clock;
+pub mod contact;
+pub mod contacts;
+pub mod error;
+pub mod exchange;
+#[cfg(test)]
+mod fixtures;
+pub mod identity;
+pub mod profile;
+pub mod provisioning;
+pub mod random;
+pub mod record;
+pub mod remember;
 pub mod serial;
+pub mod settings;
+pub mod storage;
+pub mod validate;
+pub mod wipe;
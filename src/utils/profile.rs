@@ -0,0 +1,279 @@
+use super::contact::ContactCard;
+use super::error::DeviceInfoError;
+use super::record::{self, RecordError};
+use super::serial::{NVSKeyword, MAX_VALUE_LEN};
+use super::storage::KeyValueStore;
+use serde::{Deserialize, Serialize};
+
+/// Key holding the whole profile as a single sealed record
+pub const PROFILE_KEY: &str = "profile";
+
+/// Schema version written by this firmware, bump it together with a new
+/// migration in [`ProfileRecord::load`]
+pub const PROFILE_VERSION: u8 = 2;
+
+/// Persistent form of `DeviceInfo`.
+///
+/// Fields are optional so an unprovisioned or unregistered device can still be
+/// stored, `DeviceInfo::load` decides which absences are errors.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProfileRecord {
+    pub serial_num: Option<String>,
+    pub device_name: Option<String>,
+    pub device_owner: Option<String>,
+    pub card: ContactCard,
+}
+
+/// Schema version 1, before the contact card was added
+#[derive(Deserialize)]
+struct ProfileRecordV1 {
+    serial_num: Option<String>,
+    device_name: Option<String>,
+    device_owner: Option<String>,
+}
+
+impl From<ProfileRecordV1> for ProfileRecord {
+    fn from(v1: ProfileRecordV1) -> Self {
+        ProfileRecord {
+            serial_num: v1.serial_num,
+            device_name: v1.device_name,
+            device_owner: v1.device_owner,
+            card: ContactCard::default(),
+        }
+    }
+}
+
+impl ProfileRecord {
+    /// Reads the profile, `Ok(None)` if none has been written yet. Records of
+    /// any known schema version come back in the current layout.
+    pub fn load<S: KeyValueStore>(store: &S) -> Result<Option<Self>, DeviceInfoError> {
+        record::load_versioned(store, PROFILE_KEY, |version, body| match version {
+            1 => postcard::from_bytes::<ProfileRecordV1>(body)
+                .map(ProfileRecord::from)
+                .map_err(|_| RecordError::Encoding),
+            2 => postcard::from_bytes(body).map_err(|_| RecordError::Encoding),
+            version => Err(RecordError::UnsupportedVersion(version)),
+        })
+    }
+
+    pub fn save<S: KeyValueStore>(&self, store: &mut S) -> Result<(), DeviceInfoError> {
+        record::save(store, PROFILE_KEY, PROFILE_VERSION, self)
+    }
+}
+
+/// Upgrades the legacy layout (one NVS string per field) to a [`ProfileRecord`].
+///
+/// Meant to run on every boot: it is a no-op once the record exists, and the
+/// legacy keys are only removed after the record has been written, so a reset
+/// halfway through is picked up again on the next boot. Returns `true` if a
+/// migration happened.
+pub fn migrate_legacy<S: KeyValueStore>(store: &mut S) -> Result<bool, DeviceInfoError> {
+    let legacy_keys = [
+        NVSKeyword::SerialNumber.key(),
+        NVSKeyword::DeviceName.key(),
+        NVSKeyword::DeviceOwner.key(),
+    ];
+
+    if store.contains(PROFILE_KEY)? {
+        for key in legacy_keys {
+            store.remove(key)?;
+        }
+        return Ok(false);
+    }
+
+    let mut found = false;
+    let mut values = legacy_keys.map(|key| match store.get_str(key) {
+        Ok(Some(value)) if value.len() <= MAX_VALUE_LEN => {
+            found = true;
+            Some(value)
+        }
+        Ok(Some(_)) => {
+            found = true;
+            log::warn!("Dropping over-long legacy value for [{}]", key);
+            None
+        }
+        Err(e) => {
+            found = true;
+            log::warn!(
+                "Dropping legacy value: {}",
+                DeviceInfoError::from_storage(key, e)
+            );
+            None
+        }
+        Ok(None) => None,
+    });
+
+    if !found {
+        return Ok(false);
+    }
+
+    let record = ProfileRecord {
+        serial_num: values[0].take(),
+        device_name: values[1].take(),
+        device_owner: values[2].take(),
+        card: ContactCard::default(),
+    };
+    record.save(store)?;
+
+    for key in legacy_keys {
+        store.remove(key)?;
+    }
+
+    log::info!(
+        "Migrated legacy device profile to record v{}",
+        PROFILE_VERSION
+    );
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::storage::MemoryStore;
+
+    /// A store with the legacy layout, one string per field
+    fn legacy_store() -> MemoryStore {
+        let mut store = MemoryStore::new();
+        store
+            .set_str(NVSKeyword::SerialNumber.key(), "DU-0000000001-A")
+            .unwrap();
+        store
+            .set_str(NVSKeyword::DeviceName.key(), "dap-up")
+            .unwrap();
+        store
+    }
+
+    fn legacy_keys(store: &MemoryStore) -> Vec<&'static str> {
+        [
+            NVSKeyword::SerialNumber,
+            NVSKeyword::DeviceName,
+            NVSKeyword::DeviceOwner,
+        ]
+        .into_iter()
+        .map(|keyword| keyword.key())
+        .filter(|key| store.contains(key).unwrap())
+        .collect()
+    }
+
+    #[test]
+    fn migrates_legacy_keys_into_the_record() {
+        let mut store = legacy_store();
+
+        assert!(migrate_legacy(&mut store).unwrap());
+        assert_eq!(
+            ProfileRecord::load(&store).unwrap(),
+            Some(ProfileRecord {
+                serial_num: Some("DU-0000000001-A".into()),
+                device_name: Some("dap-up".into()),
+                device_owner: None,
+                card: ContactCard::default(),
+            })
+        );
+        assert!(legacy_keys(&store).is_empty());
+    }
+
+    #[test]
+    fn migrating_twice_changes_nothing() {
+        let mut store = legacy_store();
+        migrate_legacy(&mut store).unwrap();
+        let migrated = ProfileRecord::load(&store).unwrap();
+
+        assert!(!migrate_legacy(&mut store).unwrap());
+        assert_eq!(ProfileRecord::load(&store).unwrap(), migrated);
+    }
+
+    #[test]
+    fn a_store_without_a_profile_is_left_alone() {
+        let mut store = MemoryStore::new();
+
+        assert!(!migrate_legacy(&mut store).unwrap());
+        assert!(!store.contains(PROFILE_KEY).unwrap());
+    }
+
+    #[test]
+    fn finishes_a_migration_that_was_cut_short() {
+        // Reset after the record was written but before the keys were removed
+        let mut store = legacy_store();
+        let record = ProfileRecord {
+            serial_num: Some("DU-0000000002-B".into()),
+            ..Default::default()
+        };
+        record.save(&mut store).unwrap();
+
+        assert!(!migrate_legacy(&mut store).unwrap());
+        assert!(legacy_keys(&store).is_empty());
+        assert_eq!(ProfileRecord::load(&store).unwrap(), Some(record));
+    }
+
+    #[test]
+    fn over_long_legacy_values_are_dropped() {
+        let mut store = legacy_store();
+        store
+            .set_str(
+                NVSKeyword::DeviceOwner.key(),
+                &"x".repeat(MAX_VALUE_LEN + 1),
+            )
+            .unwrap();
+
+        assert!(migrate_legacy(&mut store).unwrap());
+        let record = ProfileRecord::load(&store).unwrap().unwrap();
+        assert_eq!(record.device_owner, None);
+        assert_eq!(record.device_name.as_deref(), Some("dap-up"));
+        assert!(legacy_keys(&store).is_empty());
+    }
+
+    #[test]
+    fn a_v1_record_loads_as_v2() {
+        let mut store = MemoryStore::new();
+        // Postcard lays out a tuple like the struct with the same fields
+        let v1 = (
+            Some("DU-0000000001-A".to_string()),
+            Some("dap-up".to_string()),
+            Some("Sam".to_string()),
+        );
+        record::save(&mut store, PROFILE_KEY, 1, &v1).unwrap();
+
+        assert_eq!(
+            ProfileRecord::load(&store).unwrap(),
+            Some(ProfileRecord {
+                serial_num: v1.0,
+                device_name: v1.1,
+                device_owner: v1.2,
+                card: ContactCard::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let mut store = MemoryStore::new();
+        assert_eq!(ProfileRecord::load(&store).unwrap(), None);
+
+        let record = ProfileRecord {
+            serial_num: Some("DU-0000000001-A".into()),
+            device_name: Some("dap-up".into()),
+            device_owner: Some("Sam".into()),
+            card: ContactCard {
+                display_name: "Sam".into(),
+                ..Default::default()
+            },
+        };
+        record.save(&mut store).unwrap();
+        assert_eq!(ProfileRecord::load(&store).unwrap(), Some(record));
+    }
+
+    #[test]
+    fn a_newer_record_is_refused() {
+        let mut store = MemoryStore::new();
+        let record = ProfileRecord::default();
+        record::save(&mut store, PROFILE_KEY, PROFILE_VERSION + 1, &record).unwrap();
+
+        assert!(matches!(
+            ProfileRecord::load(&store),
+            Err(DeviceInfoError::CorruptRecord {
+                key: PROFILE_KEY,
+                reason: RecordError::UnsupportedVersion(_),
+            })
+        ));
+    }
+}
@@ -0,0 +1,226 @@
+use super::error::DeviceInfoError;
+use super::record;
+use super::serial::{DeviceInfo, MAX_VALUE_LEN};
+use super::storage::KeyValueStore;
+use super::validate;
+use crate::config::CONFIG;
+use serde::{Deserialize, Serialize};
+
+/// Key of the lock record, its presence means the serial number is locked
+pub const SERIAL_LOCK_KEY: &str = "serial_lock";
+
+const PROVISIONING_VERSION: u8 = 1;
+
+pub const MAX_BATCH_LEN: usize = 32;
+
+/// Written once at the factory together with the serial number
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProvisioningRecord {
+    pub serial_num: String,
+    /// Unix time in seconds, supplied by the provisioning host since the badge
+    /// has no RTC
+    pub provisioned_at: u64,
+    pub batch: String,
+}
+
+impl ProvisioningRecord {
+    /// Reads the lock record, `Ok(None)` if the device has not been provisioned
+    pub fn load<S: KeyValueStore>(store: &S) -> Result<Option<Self>, DeviceInfoError> {
+        record::load(store, SERIAL_LOCK_KEY, PROVISIONING_VERSION)
+    }
+
+    fn save<S: KeyValueStore>(&self, store: &mut S) -> Result<(), DeviceInfoError> {
+        record::save(store, SERIAL_LOCK_KEY, PROVISIONING_VERSION, self)
+    }
+}
+
+/// Proof that the factory key was presented, required to undo provisioning
+pub struct FactoryMode {
+    _private: (),
+}
+
+impl FactoryMode {
+    /// Enters factory mode if `key` matches the `factory_key` in `cfg.toml`
+    pub fn unlock(key: &str) -> Result<Self, DeviceInfoError> {
+        let expected = CONFIG.factory_key;
+
+        if expected.is_empty() || !constant_time_eq(key.as_bytes(), expected.as_bytes()) {
+            log::warn!("Rejected factory mode attempt");
+            return Err(DeviceInfoError::FactoryAuthFailed);
+        }
+
+        log::warn!("Factory mode unlocked");
+        Ok(FactoryMode { _private: () })
+    }
+}
+
+pub fn is_locked<S: KeyValueStore>(store: &S) -> Result<bool, DeviceInfoError> {
+    store
+        .contains(SERIAL_LOCK_KEY)
+        .map_err(|e| DeviceInfoError::from_storage(SERIAL_LOCK_KEY, e))
+}
+
+/// Writes the serial number and locks it, this only ever succeeds once unless
+/// [`reset_serial`] is used in factory mode
+pub fn provision_serial<S: KeyValueStore>(
+    store: &mut S,
+    serial: &str,
+    batch: &str,
+    provisioned_at: u64,
+) -> Result<(), DeviceInfoError> {
+    if let Some(existing) = ProvisioningRecord::load(store)? {
+        log::error!(
+            "Serial number is already provisioned as '{}' (batch '{}')",
+            existing.serial_num,
+            existing.batch
+        );
+        return Err(DeviceInfoError::SerialNumberLocked);
+    }
+
+    let serial = validate::normalize_required("serial_num", serial, MAX_VALUE_LEN)?;
+    let batch = validate::normalize("batch", batch, MAX_BATCH_LEN)?;
+
+    // The lock goes first so a reset in between can not leave an unlocked
+    // serial number behind, `DeviceInfo` reads the serial number from the
+    // lock until it made it into the profile
+    ProvisioningRecord {
+        serial_num: serial.clone(),
+        provisioned_at,
+        batch,
+    }
+    .save(store)?;
+    DeviceInfo::store_serial_number(store, &serial)?;
+
+    log::info!("Serial number '{}' provisioned and locked", serial);
+    Ok(())
+}
+
+/// Removes the lock so the device can be provisioned again
+pub fn reset_serial<S: KeyValueStore>(
+    store: &mut S,
+    _factory: &FactoryMode,
+) -> Result<(), DeviceInfoError> {
+    store.remove(SERIAL_LOCK_KEY)?;
+    DeviceInfo::clear_serial_number(store)?;
+
+    log::warn!("Serial number lock removed in factory mode");
+    Ok(())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::profile::ProfileRecord;
+    use crate::utils::storage::MemoryStore;
+
+    fn factory() -> FactoryMode {
+        FactoryMode { _private: () }
+    }
+
+    fn provisioned() -> MemoryStore {
+        let mut store = MemoryStore::new();
+        provision_serial(&mut store, " DU-0000000042-A ", "B1", 1_700_000_000).unwrap();
+        store
+    }
+
+    fn stored_serial(store: &MemoryStore) -> Option<String> {
+        ProfileRecord::load(store)
+            .unwrap()
+            .unwrap_or_default()
+            .serial_num
+    }
+
+    #[test]
+    fn provisioning_writes_the_serial_number_and_the_lock() {
+        let store = provisioned();
+
+        assert!(is_locked(&store).unwrap());
+        assert_eq!(
+            ProvisioningRecord::load(&store).unwrap(),
+            Some(ProvisioningRecord {
+                serial_num: "DU-0000000042-A".into(),
+                provisioned_at: 1_700_000_000,
+                batch: "B1".into(),
+            })
+        );
+        assert_eq!(stored_serial(&store).as_deref(), Some("DU-0000000042-A"));
+    }
+
+    #[test]
+    fn provisioning_only_succeeds_once() {
+        let mut store = provisioned();
+
+        assert!(matches!(
+            provision_serial(&mut store, "DU-0000000043-B", "B2", 1_700_000_001),
+            Err(DeviceInfoError::SerialNumberLocked)
+        ));
+        assert!(matches!(
+            DeviceInfo::store_serial_number(&mut store, "DU-0000000043-B"),
+            Err(DeviceInfoError::SerialNumberLocked)
+        ));
+        assert_eq!(stored_serial(&store).as_deref(), Some("DU-0000000042-A"));
+    }
+
+    #[test]
+    fn a_reset_between_lock_and_serial_keeps_the_locked_serial() {
+        let mut store = provisioned();
+        DeviceInfo::clear_serial_number(&mut store).unwrap();
+
+        assert_eq!(
+            DeviceInfo::new(&store).serial_num,
+            "DU-0000000042-A".to_string()
+        );
+    }
+
+    #[test]
+    fn invalid_values_are_not_provisioned() {
+        let mut store = MemoryStore::new();
+
+        assert!(provision_serial(&mut store, "  ", "B1", 0).is_err());
+        assert!(provision_serial(&mut store, "DU-1", &"b".repeat(MAX_BATCH_LEN + 1), 0).is_err());
+        assert!(!is_locked(&store).unwrap());
+        assert_eq!(stored_serial(&store), None);
+    }
+
+    #[test]
+    fn factory_mode_needs_the_factory_key() {
+        // Refused whether or not a key is configured
+        assert!(matches!(
+            FactoryMode::unlock(""),
+            Err(DeviceInfoError::FactoryAuthFailed)
+        ));
+        assert!(matches!(
+            FactoryMode::unlock("not the factory key"),
+            Err(DeviceInfoError::FactoryAuthFailed)
+        ));
+    }
+
+    #[test]
+    fn reset_clears_the_serial_number_and_the_lock() {
+        let mut store = provisioned();
+        reset_serial(&mut store, &factory()).unwrap();
+
+        assert!(!is_locked(&store).unwrap());
+        assert_eq!(ProvisioningRecord::load(&store).unwrap(), None);
+        assert_eq!(stored_serial(&store), None);
+
+        provision_serial(&mut store, "DU-0000000043-B", "B2", 1_700_000_001).unwrap();
+        assert_eq!(stored_serial(&store).as_deref(), Some("DU-0000000043-B"));
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_slices() {
+        assert!(constant_time_eq(b"key", b"key"));
+        assert!(!constant_time_eq(b"key", b"kez"));
+        assert!(!constant_time_eq(b"key", b"keys"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
@@ -0,0 +1,21 @@
+/// Fills `buffer` from the hardware RNG
+#[cfg(target_os = "espidf")]
+pub fn fill(buffer: &mut [u8]) {
+    // Random as long as the radio is on, which it is whenever we advertise
+    unsafe { esp_idf_svc::sys::esp_fill_random(buffer.as_mut_ptr() as *mut _, buffer.len()) };
+}
+
+/// Host stand-in, good enough for tokens and nonces in simulations but not for
+/// key material
+#[cfg(not(target_os = "espidf"))]
+pub fn fill(buffer: &mut [u8]) {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    for (i, chunk) in buffer.chunks_mut(8).enumerate() {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(i);
+        let bytes = hasher.finish().to_le_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
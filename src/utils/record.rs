@@ -0,0 +1,286 @@
+use super::error::DeviceInfoError;
+use super::storage::KeyValueStore;
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt;
+
+/// Marks the start of every record written by this firmware
+const MAGIC: [u8; 2] = *b"DU";
+/// Magic, schema version and body length
+const HEADER_LEN: usize = 5;
+/// CRC-32 of header and body, appended after the body
+const CRC_LEN: usize = 4;
+
+const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+/// Why a stored record could not be opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordError {
+    /// Fewer bytes than a header and checksum
+    TooShort,
+    /// Not written by this firmware
+    BadMagic,
+    /// The length in the header does not match the stored size
+    BadLength,
+    /// The CRC does not match, the record is corrupted
+    BadChecksum,
+    /// Written by a newer firmware than this one
+    UnsupportedVersion(u8),
+    /// Body could not be (de)serialized
+    Encoding,
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordError::TooShort => write!(f, "record is truncated"),
+            RecordError::BadMagic => write!(f, "record has an unknown magic"),
+            RecordError::BadLength => write!(f, "record length does not match its header"),
+            RecordError::BadChecksum => write!(f, "record checksum mismatch"),
+            RecordError::UnsupportedVersion(version) => {
+                write!(f, "record schema version {} is not supported", version)
+            }
+            RecordError::Encoding => write!(f, "record body could not be encoded/decoded"),
+        }
+    }
+}
+
+impl std::error::Error for RecordError {}
+
+/// Wraps an encoded body as `magic | version | len (u16 LE) | body | crc32 (LE)`
+pub fn seal(version: u8, body: &[u8]) -> Result<Vec<u8>, RecordError> {
+    let len = u16::try_from(body.len()).map_err(|_| RecordError::Encoding)?;
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + body.len() + CRC_LEN);
+    bytes.extend_from_slice(&MAGIC);
+    bytes.push(version);
+    bytes.extend_from_slice(&len.to_le_bytes());
+    bytes.extend_from_slice(body);
+
+    let crc = CRC32.checksum(&bytes);
+    bytes.extend_from_slice(&crc.to_le_bytes());
+
+    Ok(bytes)
+}
+
+/// Verifies a sealed record and returns its schema version and body
+pub fn open(bytes: &[u8]) -> Result<(u8, &[u8]), RecordError> {
+    if bytes.len() < HEADER_LEN + CRC_LEN {
+        return Err(RecordError::TooShort);
+    }
+
+    if bytes[..2] != MAGIC {
+        return Err(RecordError::BadMagic);
+    }
+
+    let version = bytes[2];
+    let len = u16::from_le_bytes([bytes[3], bytes[4]]) as usize;
+    if bytes.len() != HEADER_LEN + len + CRC_LEN {
+        return Err(RecordError::BadLength);
+    }
+
+    let (data, crc) = bytes.split_at(HEADER_LEN + len);
+    let crc = u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]);
+    if CRC32.checksum(data) != crc {
+        return Err(RecordError::BadChecksum);
+    }
+
+    Ok((version, &data[HEADER_LEN..]))
+}
+
+/// Reads a sealed postcard record that only has a single schema version so far
+pub fn load<T: DeserializeOwned, S: KeyValueStore>(
+    store: &S,
+    key: &'static str,
+    version: u8,
+) -> Result<Option<T>, DeviceInfoError> {
+    load_versioned(store, key, |stored_version, body| {
+        decode_body(version, stored_version, body)
+    })
+}
+
+/// Reads a sealed record, handing its version and body to `decode` so older
+/// schema versions can be migrated on the way in
+pub fn load_versioned<T, S, F>(
+    store: &S,
+    key: &'static str,
+    decode: F,
+) -> Result<Option<T>, DeviceInfoError>
+where
+    S: KeyValueStore,
+    F: FnOnce(u8, &[u8]) -> Result<T, RecordError>,
+{
+    let bytes = match store
+        .get_blob(key)
+        .map_err(|e| DeviceInfoError::from_storage(key, e))?
+    {
+        Some(bytes) => bytes,
+        None => return Ok(None),
+    };
+
+    decode_versioned(&bytes, key, decode).map(Some)
+}
+
+/// Writes `value` as a sealed postcard record
+pub fn save<T: Serialize, S: KeyValueStore>(
+    store: &mut S,
+    key: &'static str,
+    version: u8,
+    value: &T,
+) -> Result<(), DeviceInfoError> {
+    let bytes = encode(value, key, version)?;
+
+    store.set_blob(key, &bytes)?;
+    Ok(())
+}
+
+/// Bytes `value` takes up once sealed, for keeping records within a size
+pub fn encoded_len<T: Serialize>(value: &T) -> usize {
+    // Only fails for types postcard can not represent, which are never stored
+    let body = postcard::to_allocvec(value).map_or(usize::MAX, |body| body.len());
+    body.saturating_add(HEADER_LEN + CRC_LEN)
+}
+
+/// Seals `value` as a postcard record, for records that are sent rather than
+/// stored. `key` names the record in errors.
+pub fn encode<T: Serialize>(
+    value: &T,
+    key: &'static str,
+    version: u8,
+) -> Result<Vec<u8>, DeviceInfoError> {
+    postcard::to_allocvec(value)
+        .map_err(|_| RecordError::Encoding)
+        .and_then(|body| seal(version, &body))
+        .map_err(|reason| DeviceInfoError::CorruptRecord { key, reason })
+}
+
+/// Opens a sealed postcard record that only has a single schema version so
+/// far, the counterpart of [`encode`]
+pub fn decode<T: DeserializeOwned>(
+    bytes: &[u8],
+    key: &'static str,
+    version: u8,
+) -> Result<T, DeviceInfoError> {
+    decode_versioned(bytes, key, |stored_version, body| {
+        decode_body(version, stored_version, body)
+    })
+}
+
+/// Opens a sealed record, handing its version and body to `decode` like
+/// [`load_versioned`] does
+pub fn decode_versioned<T, F>(
+    bytes: &[u8],
+    key: &'static str,
+    decode: F,
+) -> Result<T, DeviceInfoError>
+where
+    F: FnOnce(u8, &[u8]) -> Result<T, RecordError>,
+{
+    let corrupt = |reason| DeviceInfoError::CorruptRecord { key, reason };
+
+    let (version, body) = open(bytes).map_err(corrupt)?;
+    decode(version, body).map_err(corrupt)
+}
+
+fn decode_body<T: DeserializeOwned>(
+    version: u8,
+    stored_version: u8,
+    body: &[u8],
+) -> Result<T, RecordError> {
+    if stored_version != version {
+        return Err(RecordError::UnsupportedVersion(stored_version));
+    }
+
+    postcard::from_bytes(body).map_err(|_| RecordError::Encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::storage::MemoryStore;
+
+    fn corrupt_reason<T: fmt::Debug>(result: Result<T, DeviceInfoError>) -> RecordError {
+        match result {
+            Err(DeviceInfoError::CorruptRecord { reason, .. }) => reason,
+            other => panic!("expected a corrupt record, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn seal_open_round_trip() {
+        let sealed = seal(3, b"body").unwrap();
+        assert_eq!(&sealed[..HEADER_LEN], &[b'D', b'U', 3, 4, 0]);
+        assert_eq!(open(&sealed), Ok((3, &b"body"[..])));
+
+        let empty = seal(1, &[]).unwrap();
+        assert_eq!(open(&empty), Ok((1, &[][..])));
+    }
+
+    #[test]
+    fn open_rejects_damaged_records() {
+        let sealed = seal(1, b"body").unwrap();
+
+        assert_eq!(
+            open(&sealed[..HEADER_LEN + CRC_LEN - 1]),
+            Err(RecordError::TooShort)
+        );
+        assert_eq!(
+            open(&sealed[..sealed.len() - 1]),
+            Err(RecordError::BadLength)
+        );
+
+        let mut bad_magic = sealed.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(open(&bad_magic), Err(RecordError::BadMagic));
+
+        for i in 2..sealed.len() {
+            let mut flipped = sealed.clone();
+            flipped[i] ^= 0x01;
+            assert!(open(&flipped).is_err(), "bit flip in byte {} passed", i);
+        }
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let value = (7u32, "seven".to_string());
+        let bytes = encode(&value, "test", 2).unwrap();
+
+        assert_eq!(encoded_len(&value), bytes.len());
+        assert_eq!(decode::<(u32, String)>(&bytes, "test", 2).unwrap(), value);
+        assert_eq!(
+            corrupt_reason(decode::<(u32, String)>(&bytes, "test", 1)),
+            RecordError::UnsupportedVersion(2)
+        );
+        assert_eq!(
+            corrupt_reason(decode::<(u32, String)>(&bytes[1..], "test", 2)),
+            RecordError::BadMagic
+        );
+    }
+
+    #[test]
+    fn load_save_round_trip() {
+        let mut store = MemoryStore::new();
+        assert_eq!(load::<u32, _>(&store, "test", 1).unwrap(), None);
+
+        save(&mut store, "test", 1, &42u32).unwrap();
+        assert_eq!(load::<u32, _>(&store, "test", 1).unwrap(), Some(42));
+
+        let migrated = load_versioned(&store, "test", |version, body| {
+            assert_eq!(version, 1);
+            postcard::from_bytes::<u32>(body)
+                .map(|value| value * 2)
+                .map_err(|_| RecordError::Encoding)
+        });
+        assert_eq!(migrated.unwrap(), Some(84));
+    }
+
+    #[test]
+    fn load_reports_undecodable_bodies() {
+        let mut store = MemoryStore::new();
+        store.set_blob("test", &seal(1, &[]).unwrap()).unwrap();
+
+        assert_eq!(
+            corrupt_reason(load::<u32, _>(&store, "test", 1)),
+            RecordError::Encoding
+        );
+    }
+}
@@ -0,0 +1,222 @@
+//! Keeping a peer once the exchange is committed: its address goes into the
+//! blocklist and its card into the contacts.
+//!
+//! A side that answers the first phase of the commit promises to keep the
+//! peer, so [`check`] runs before it does and [`remember`] fails rather than
+//! keeping one list without the other.
+
+use super::blocklist::{Blocklist, BlocklistEntry};
+use super::contacts::{Contact, Contacts, CONTACTS_KEY};
+use super::error::DeviceInfoError;
+use super::exchange::ExchangeProfile;
+use super::record;
+use super::storage::KeyValueStore;
+
+/// Whether the peer at `address` could be kept right now: both lists load,
+/// the contact fits and there is room to rewrite both
+pub fn check<S: KeyValueStore>(
+    store: &S,
+    address: [u8; 6],
+    profile: &ExchangeProfile,
+) -> Result<(), DeviceInfoError> {
+    let (blocklist, contacts) = updated(store, address, profile, 0)?;
+
+    if let Some(free) = store.free_space()? {
+        // The old blobs are only released once the new ones are written
+        let needed = record::encoded_len(&blocklist) + record::encoded_len(&contacts);
+        if needed > free {
+            return Err(DeviceInfoError::StorageFull { needed, free });
+        }
+    }
+
+    Ok(())
+}
+
+/// Blocklists the peer at `address` and saves its card, see the README.
+/// `now` is in seconds since the unix epoch, or since boot.
+pub fn remember<S: KeyValueStore>(
+    store: &mut S,
+    address: [u8; 6],
+    profile: &ExchangeProfile,
+    now: u64,
+) -> Result<(), DeviceInfoError> {
+    let (blocklist, contacts) = updated(store, address, profile, now)?;
+
+    // The card first, a blocklist entry alone would make sure we never get it
+    contacts.save(store)?;
+    blocklist.save(store)?;
+
+    log::info!("Exchanged with {}", profile.serial_num);
+    Ok(())
+}
+
+/// Both lists as they are once the peer is kept, nothing is written
+fn updated<S: KeyValueStore>(
+    store: &S,
+    address: [u8; 6],
+    profile: &ExchangeProfile,
+    now: u64,
+) -> Result<(Blocklist, Contacts), DeviceInfoError> {
+    let mut contacts = Contacts::load(store)?;
+    let contact = Contact {
+        serial_num: profile.serial_num.clone(),
+        card: profile.card.clone(),
+        received_at: now,
+        identity_key: Some(profile.identity_key),
+    };
+    if !contacts.upsert(contact) {
+        return Err(DeviceInfoError::ListFull(CONTACTS_KEY));
+    }
+
+    // Always fits, the oldest entries make room
+    let mut blocklist = Blocklist::load(store)?;
+    blocklist.insert(BlocklistEntry {
+        address,
+        serial_num: profile.serial_num.clone(),
+        device_name: profile.device_name.clone(),
+        card: profile.card.clone(),
+        added_at: now,
+        identity_key: Some(profile.identity_key),
+    });
+
+    Ok((blocklist, contacts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::blocklist::BLOCKLIST_KEY;
+    use crate::utils::contact::ContactCard;
+    use crate::utils::contacts::MAX_CONTACTS;
+    use crate::utils::storage::MemoryStore;
+
+    /// A [`MemoryStore`] that reports a fixed amount of free space
+    struct SmallStore {
+        inner: MemoryStore,
+        free: usize,
+    }
+
+    impl KeyValueStore for SmallStore {
+        fn get_str(&self, key: &str) -> anyhow::Result<Option<String>> {
+            self.inner.get_str(key)
+        }
+
+        fn set_str(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+            self.inner.set_str(key, value)
+        }
+
+        fn get_blob(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+            self.inner.get_blob(key)
+        }
+
+        fn set_blob(&mut self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+            self.inner.set_blob(key, value)
+        }
+
+        fn remove(&mut self, key: &str) -> anyhow::Result<bool> {
+            self.inner.remove(key)
+        }
+
+        fn free_space(&self) -> anyhow::Result<Option<usize>> {
+            Ok(Some(self.free))
+        }
+    }
+
+    fn profile(serial_num: &str) -> ExchangeProfile {
+        ExchangeProfile {
+            serial_num: serial_num.into(),
+            device_name: "dap-up".into(),
+            card: ContactCard {
+                display_name: "Sam".into(),
+                ..Default::default()
+            },
+            identity_key: [7; 16],
+        }
+    }
+
+    /// A store whose contact list is full
+    fn full_contacts() -> MemoryStore {
+        let mut store = MemoryStore::new();
+        let mut contacts = Contacts::default();
+        for i in 0..MAX_CONTACTS {
+            assert!(contacts.upsert(Contact {
+                serial_num: format!("DU-{:010}-X", i),
+                card: ContactCard::default(),
+                received_at: 0,
+                identity_key: None,
+            }));
+        }
+        contacts.save(&mut store).unwrap();
+        store
+    }
+
+    #[test]
+    fn keeps_both_the_blocklist_entry_and_the_contact() {
+        let mut store = MemoryStore::new();
+        check(&store, [1; 6], &profile("DU-1")).unwrap();
+        remember(&mut store, [1; 6], &profile("DU-1"), 42).unwrap();
+
+        let blocklist = Blocklist::load(&store).unwrap();
+        assert!(blocklist.contains(&[1; 6]));
+        assert_eq!(blocklist.entries[0].identity_key, Some([7; 16]));
+        let contacts = Contacts::load(&store).unwrap();
+        assert_eq!(contacts.entries[0].serial_num, "DU-1");
+        assert_eq!(contacts.entries[0].received_at, 42);
+    }
+
+    #[test]
+    fn a_full_contact_list_keeps_neither() {
+        let mut store = full_contacts();
+
+        assert!(matches!(
+            check(&store, [1; 6], &profile("DU-1")),
+            Err(DeviceInfoError::ListFull(CONTACTS_KEY))
+        ));
+        assert!(matches!(
+            remember(&mut store, [1; 6], &profile("DU-1"), 42),
+            Err(DeviceInfoError::ListFull(CONTACTS_KEY))
+        ));
+        assert!(!store.contains(BLOCKLIST_KEY).unwrap());
+        assert_eq!(Contacts::load(&store).unwrap().entries.len(), MAX_CONTACTS);
+    }
+
+    #[test]
+    fn a_known_contact_is_updated_even_when_the_list_is_full() {
+        let mut store = full_contacts();
+        let known = profile("DU-0000000003-X");
+
+        check(&store, [1; 6], &known).unwrap();
+        remember(&mut store, [1; 6], &known, 42).unwrap();
+        assert!(Blocklist::load(&store).unwrap().contains(&[1; 6]));
+    }
+
+    #[test]
+    fn refuses_when_storage_has_no_room() {
+        let store = SmallStore {
+            inner: MemoryStore::new(),
+            free: 16,
+        };
+
+        assert!(matches!(
+            check(&store, [1; 6], &profile("DU-1")),
+            Err(DeviceInfoError::StorageFull { free: 16, .. })
+        ));
+
+        let store = SmallStore {
+            free: 4096,
+            ..store
+        };
+        check(&store, [1; 6], &profile("DU-1")).unwrap();
+    }
+
+    #[test]
+    fn a_corrupt_list_refuses_before_the_commit() {
+        let mut store = MemoryStore::new();
+        store.set_blob(CONTACTS_KEY, b"garbage").unwrap();
+
+        assert!(matches!(
+            check(&store, [1; 6], &profile("DU-1")),
+            Err(DeviceInfoError::CorruptRecord { .. })
+        ));
+    }
+}
@@ -1,125 +1,209 @@
-use anyhow::Result;
-use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use super::contact::ContactCard;
+use super::error::DeviceInfoError;
+use super::identity;
+use super::profile::ProfileRecord;
+use super::provisioning::ProvisioningRecord;
+use super::storage::KeyValueStore;
+use super::validate;
 
+/// Longest value (in bytes) any of the profile fields may hold
+pub const MAX_VALUE_LEN: usize = 64;
+
+/// Profile fields, named after the NVS keys they were stored under before the
+/// profile became a single record
 pub enum NVSKeyword {
     SerialNumber,
     DeviceName,
     DeviceOwner,
 }
 
+impl NVSKeyword {
+    pub fn key(&self) -> &'static str {
+        match self {
+            NVSKeyword::SerialNumber => "serial_num",
+            NVSKeyword::DeviceName => "device_name",
+            NVSKeyword::DeviceOwner => "device_owner",
+        }
+    }
+}
+
 pub struct DeviceInfo {
     pub serial_num: String,
     pub device_name: String,
     pub device_owner: String,
+    /// What gets exchanged with other badges
+    pub card: ContactCard,
 }
 
 impl DeviceInfo {
-    pub fn new() -> Self {
-        let nvs_partition = EspDefaultNvsPartition::take().unwrap();
-        let nvs = EspNvs::<NvsDefault>::new(nvs_partition, "storage", true).unwrap();
-
-        let mut serial_num = String::new();
-        let mut device_name = String::new();
-        let mut device_owner = String::new();
-
-        {
-            let mut buffer = [0u8; 64];
-            match nvs.get_str("serial_num", &mut buffer) {
-                Ok(Some(serial)) => {
-                    serial_num = serial.to_string();
-                }
-                Ok(None) | Err(_) => {
-                    log::warn!("Keyword [{}] not found", "serial_num");
-                    serial_num = "Unknown".to_string();
-                }
+    /// Loads the profile, failing on the first missing or unreadable value
+    pub fn load<S: KeyValueStore>(store: &S) -> Result<Self, DeviceInfoError> {
+        let record = ProfileRecord::load(store)?.unwrap_or_default();
+
+        let serial_num = Self::field(
+            record
+                .serial_num
+                .or_else(|| Self::provisioned_serial(store))
+                .or_else(Self::default_serial),
+            NVSKeyword::SerialNumber,
+        )?;
+        let device_name = Self::field(record.device_name, NVSKeyword::DeviceName)?;
+        let device_owner = match Self::field(record.device_owner, NVSKeyword::DeviceOwner) {
+            Err(DeviceInfoError::MissingKey(_)) => return Err(DeviceInfoError::OwnerNotRegistered),
+            result => result?,
+        };
+
+        Ok(DeviceInfo {
+            serial_num,
+            device_name,
+            device_owner,
+            card: record.card,
+        })
+    }
+
+    /// Loads the profile, substituting "Unknown" for anything that can not be read
+    pub fn new<S: KeyValueStore>(store: &S) -> Self {
+        let record = match ProfileRecord::load(store) {
+            Ok(record) => record.unwrap_or_default(),
+            Err(e) => {
+                log::warn!("{}", e);
+                ProfileRecord::default()
             }
+        };
+
+        let serial_num = Self::field_or_unknown(
+            record
+                .serial_num
+                .or_else(|| Self::provisioned_serial(store))
+                .or_else(Self::default_serial),
+            NVSKeyword::SerialNumber,
+        );
+        let device_owner = Self::field_or_unknown(record.device_owner, NVSKeyword::DeviceOwner);
+        let device_name = Self::field_or_unknown(record.device_name, NVSKeyword::DeviceName);
+
+        DeviceInfo {
+            serial_num,
+            device_name,
+            device_owner,
+            card: record.card,
         }
+    }
 
-        {
-            let mut buffer = [0u8; 64];
-            match nvs.get_str("device_owner", &mut buffer) {
-                Ok(Some(value)) => {
-                    device_owner = value.to_string();
-                }
-                Ok(None) | Err(_) => {
-                    log::warn!("Keyword [{}] not found", "device_owner");
-                    device_owner = "Unknown".to_string();
-                }
+    /// The serial number of the lock, for a device reset after it was locked
+    /// but before the serial number made it into the profile
+    fn provisioned_serial<S: KeyValueStore>(store: &S) -> Option<String> {
+        match ProvisioningRecord::load(store) {
+            Ok(lock) => lock.map(|lock| lock.serial_num),
+            Err(e) => {
+                log::warn!("{}", e);
+                None
             }
         }
+    }
 
-        {
-            let mut buffer = [0u8; 64];
-            match nvs.get_str("device_name", &mut buffer) {
-                Ok(Some(value)) => {
-                    device_name = value.to_string();
-                }
-                Ok(None) | Err(_) => {
-                    log::warn!("Keyword [{}] not found", "device_name");
-                    device_name = "Unknown".to_string();
-                }
+    /// MAC derived stand-in used until a serial number is provisioned
+    fn default_serial() -> Option<String> {
+        match identity::default_serial() {
+            Ok(serial) => {
+                log::info!("No serial number provisioned, using '{}'", serial);
+                Some(serial)
+            }
+            Err(e) => {
+                log::warn!("Unable to derive a default serial number: {}", e);
+                None
             }
         }
+    }
 
-        return DeviceInfo {
-            serial_num,
-            device_name,
-            device_owner,
-        };
+    fn field(value: Option<String>, keyword: NVSKeyword) -> Result<String, DeviceInfoError> {
+        let key = keyword.key();
+
+        match value {
+            Some(value) => {
+                validate::check(key, &value, MAX_VALUE_LEN)?;
+                Ok(value)
+            }
+            None => Err(DeviceInfoError::MissingKey(key)),
+        }
     }
 
-    pub fn update(new_data: &str, keyword: NVSKeyword) -> anyhow::Result<Option<()>> {
-        // Open NVS namespace (same as when storing)
-        let nvs_partition = EspDefaultNvsPartition::take()?;
-        let mut nvs = EspNvs::<NvsDefault>::new(nvs_partition, "storage", true)?;
+    fn field_or_unknown(value: Option<String>, keyword: NVSKeyword) -> String {
+        Self::field(value, keyword).unwrap_or_else(|e| {
+            log::warn!("{}", e);
+            "Unknown".to_string()
+        })
+    }
+
+    pub fn update<S: KeyValueStore>(
+        store: &mut S,
+        new_data: &str,
+        keyword: NVSKeyword,
+    ) -> Result<(), DeviceInfoError> {
+        let key = keyword.key();
+        let mut record = ProfileRecord::load(store)?.unwrap_or_default();
 
         match keyword {
             NVSKeyword::SerialNumber => {
-                log::error!("Unable to rewrite over serial number, manual code change required");
+                return Err(DeviceInfoError::SerialNumberImmutable);
             }
             NVSKeyword::DeviceName => {
-                let mut buffer = [0u8; 64];
-                match nvs.get_str("device_name", &mut buffer) {
-                    Ok(Some(old_device_name)) => {
+                let new_data = validate::normalize_required(key, new_data, MAX_VALUE_LEN)?;
+
+                match &record.device_name {
+                    Some(old_device_name) => {
                         log::info!(
                             "Updating device name from '{}' to '{}'...",
                             old_device_name,
                             new_data
                         );
                     }
-                    _ => {
+                    None => {
                         log::warn!("No existing device name found, creating new data!");
                     }
                 }
 
-                nvs.set_str("device_name", new_data)?;
+                record.device_name = Some(new_data.clone());
+                record.save(store)?;
 
                 log::info!("Device name has been updated successfully!");
-                return Ok(Some(()));
             }
             NVSKeyword::DeviceOwner => {
-                let mut buffer = [0u8; 64];
-                match nvs.get_str("device_owner", &mut buffer) {
-                    Ok(Some(old_device_owner)) => {
+                let new_data = validate::normalize_required(key, new_data, MAX_VALUE_LEN)?;
+
+                match &record.device_owner {
+                    Some(old_device_owner) => {
                         log::info!(
-                            "Updating device name from '{}' to '{}'...",
+                            "Updating device owner from '{}' to '{}'...",
                             old_device_owner,
                             new_data
                         );
                     }
-                    _ => {
-                        log::error!("Device has not been registered to a user, please manually create the user or register to someone");
-                        return Ok(None);
-                    }
+                    None => return Err(DeviceInfoError::OwnerNotRegistered),
                 }
 
-                nvs.set_str("device_owner", new_data)?;
+                record.device_owner = Some(new_data.clone());
+                record.save(store)?;
 
-                log::info!("Device name has been updated successfully!");
-                return Ok(Some(()));
+                log::info!("Device owner has been updated successfully!");
             }
         }
-        Ok(None)
+
+        Ok(())
+    }
+
+    /// Replaces the contact card shared with other devices
+    pub fn update_card<S: KeyValueStore>(
+        store: &mut S,
+        card: ContactCard,
+    ) -> Result<(), DeviceInfoError> {
+        let card = card.normalize()?;
+
+        let mut record = ProfileRecord::load(store)?.unwrap_or_default();
+        record.card = card;
+        record.save(store)?;
+
+        log::info!("Contact card has been updated successfully!");
+        Ok(())
     }
 
     pub fn print(self) {
@@ -127,41 +211,137 @@ impl DeviceInfo {
             "Information about device: \nSerial Number: {}\nDevice Name: {}\nDevice Owner: {}\n",
             self.serial_num, self.device_name, self.device_owner
         );
+        self.card.print();
     }
 
-    pub(crate) fn store_serial_number(serial: &str) -> Result<()> {
-        // Open or create NVS namespace
-        let nvs_partition = EspDefaultNvsPartition::take()?;
-        let mut nvs = EspNvs::<NvsDefault>::new(nvs_partition, "storage", true)?;
+    pub(crate) fn store_serial_number<S: KeyValueStore>(
+        store: &mut S,
+        serial: &str,
+    ) -> Result<(), DeviceInfoError> {
+        // Store the serial number, once locked only the locked one may be
+        // written and only factory mode may remove it
+        let serial = validate::normalize_required("serial_num", serial, MAX_VALUE_LEN)?;
+        if let Some(lock) = ProvisioningRecord::load(store)? {
+            if lock.serial_num != serial {
+                return Err(DeviceInfoError::SerialNumberLocked);
+            }
+        }
 
-        // Store the serial number
-        nvs.set_str("serial_num", serial)?;
+        log::info!("Saving serial number: {}", serial);
+        let mut record = ProfileRecord::load(store)?.unwrap_or_default();
+        record.serial_num = Some(serial);
+        record.save(store)
+    }
+
+    pub(crate) fn clear_serial_number<S: KeyValueStore>(
+        store: &mut S,
+    ) -> Result<(), DeviceInfoError> {
+        let mut record = ProfileRecord::load(store)?.unwrap_or_default();
+        record.serial_num = None;
+        record.save(store)
+    }
 
-        log::info!("Serial number saved: {}", serial);
+    pub(crate) fn store_device_owner<S: KeyValueStore>(
+        store: &mut S,
+        name: &str,
+    ) -> Result<(), DeviceInfoError> {
+        // Store the device owner
+        let name = validate::normalize_required("device_owner", name, MAX_VALUE_LEN)?;
+        let mut record = ProfileRecord::load(store)?.unwrap_or_default();
+        record.device_owner = Some(name.to_string());
+        record.save(store)?;
+
+        log::info!("Device owner saved: {}", name);
         Ok(())
     }
+}
 
-    pub(crate) fn store_device_name(name: &str) -> Result<()> {
-        // Open or create NVS namespace
-        let nvs_partition = EspDefaultNvsPartition::take()?;
-        let mut nvs = EspNvs::<NvsDefault>::new(nvs_partition, "storage", true)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::contact::SocialHandle;
+    use crate::utils::storage::MemoryStore;
 
-        // Store the device name
-        nvs.set_str("device_name", name)?;
+    fn registered() -> MemoryStore {
+        let mut store = MemoryStore::new();
+        DeviceInfo::store_serial_number(&mut store, "DU-0001").unwrap();
+        DeviceInfo::update(&mut store, "Badge", NVSKeyword::DeviceName).unwrap();
+        DeviceInfo::store_device_owner(&mut store, "Alex").unwrap();
+        store
+    }
 
-        log::info!("Device name saved: {}", name);
-        Ok(())
+    #[test]
+    fn profile_round_trip() {
+        let mut store = registered();
+        let card = ContactCard {
+            display_name: "Alex".to_string(),
+            pronouns: "they/them".to_string(),
+            socials: vec![SocialHandle {
+                network: "mastodon".to_string(),
+                handle: "@alex@example.social".to_string(),
+            }],
+            links: vec!["https://example.com".to_string()],
+            ..ContactCard::default()
+        };
+        DeviceInfo::update_card(&mut store, card.clone()).unwrap();
+
+        let info = DeviceInfo::load(&store).unwrap();
+        assert_eq!(info.serial_num, "DU-0001");
+        assert_eq!(info.device_name, "Badge");
+        assert_eq!(info.device_owner, "Alex");
+        assert_eq!(info.card, card);
     }
 
-    pub(crate) fn store_device_owner(name: &str) -> Result<()> {
-        // Open or create NVS namespace
-        let nvs_partition = EspDefaultNvsPartition::take()?;
-        let mut nvs = EspNvs::<NvsDefault>::new(nvs_partition, "storage", true)?;
+    #[test]
+    fn updates_are_normalized() {
+        let mut store = registered();
+        DeviceInfo::update(&mut store, "  New name ", NVSKeyword::DeviceName).unwrap();
+        DeviceInfo::update(&mut store, "Sam", NVSKeyword::DeviceOwner).unwrap();
 
-        // Store the device owner
-        nvs.set_str("device_owner", name)?;
+        let info = DeviceInfo::load(&store).unwrap();
+        assert_eq!(info.device_name, "New name");
+        assert_eq!(info.device_owner, "Sam");
+    }
 
-        log::info!("Device owner saved: {}", name);
-        Ok(())
+    #[test]
+    fn serial_number_can_not_be_updated() {
+        let mut store = registered();
+        assert!(matches!(
+            DeviceInfo::update(&mut store, "DU-0002", NVSKeyword::SerialNumber),
+            Err(DeviceInfoError::SerialNumberImmutable)
+        ));
+        assert_eq!(DeviceInfo::load(&store).unwrap().serial_num, "DU-0001");
+    }
+
+    #[test]
+    fn owner_must_be_registered_first() {
+        let mut store = MemoryStore::new();
+        DeviceInfo::update(&mut store, "Badge", NVSKeyword::DeviceName).unwrap();
+
+        assert!(matches!(
+            DeviceInfo::update(&mut store, "Alex", NVSKeyword::DeviceOwner),
+            Err(DeviceInfoError::OwnerNotRegistered)
+        ));
+        assert!(matches!(
+            DeviceInfo::load(&store),
+            Err(DeviceInfoError::OwnerNotRegistered)
+        ));
+    }
+
+    #[test]
+    fn unreadable_profile_falls_back_to_unknown() {
+        let mut store = MemoryStore::new();
+        store
+            .set_blob(crate::utils::profile::PROFILE_KEY, b"garbage")
+            .unwrap();
+
+        assert!(matches!(
+            DeviceInfo::load(&store),
+            Err(DeviceInfoError::CorruptRecord { .. })
+        ));
+
+        let info = DeviceInfo::new(&store);
+        assert_eq!(info.device_name, "Unknown");
+        assert_eq!(info.device_owner, "Unknown");
     }
 }
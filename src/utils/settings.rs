@@ -0,0 +1,255 @@
+use super::storage::KeyValueStore;
+use crate::ble::duty_cycle::PowerProfile;
+use anyhow::Result;
+use log::LevelFilter;
+use std::time::Duration;
+
+pub const LOG_LEVEL_KEY: &str = "log_level";
+pub const EVENT_CODE_KEY: &str = "event_code";
+pub const RSSI_THRESHOLD_KEY: &str = "rssi_min";
+pub const TX_POWER_KEY: &str = "tx_power_1m";
+pub const DWELL_TIME_KEY: &str = "dwell_s";
+pub const ROTATION_INTERVAL_KEY: &str = "rotate_s";
+pub const POWER_PROFILE_KEY: &str = "power";
+
+/// Every key owned by [`Settings`]
+pub const SETTINGS_KEYS: &[&str] = &[
+    LOG_LEVEL_KEY,
+    EVENT_CODE_KEY,
+    RSSI_THRESHOLD_KEY,
+    TX_POWER_KEY,
+    DWELL_TIME_KEY,
+    ROTATION_INTERVAL_KEY,
+    POWER_PROFILE_KEY,
+];
+
+/// Runtime configurable behaviour.
+///
+/// Each setting is its own NVS string so new ones can be added without a
+/// migration, a missing or unparsable value falls back to the default.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub log_level: LevelFilter,
+    /// Advertised so badges at an event only exchange with each other, 0 is "any"
+    pub event_code: u16,
+    /// Smoothed RSSI (dBm) a peer must reach before it counts as close
+    pub rssi_threshold: i8,
+    /// Calibrated RSSI of this badge measured 1 m away, advertised if set
+    pub tx_power_at_1m: Option<i8>,
+    /// How long a peer has to stay close before an exchange is attempted,
+    /// stored in whole seconds
+    pub dwell_time: Duration,
+    /// How often the advertised token changes, stored in whole seconds
+    pub rotation_interval: Duration,
+    /// How much battery advertising and scanning may use
+    pub power_profile: PowerProfile,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            log_level: LevelFilter::Debug,
+            event_code: 0,
+            // Roughly arm's length for an ESP32 antenna in a badge case
+            rssi_threshold: -70,
+            tx_power_at_1m: None,
+            dwell_time: Duration::from_secs(3),
+            // Same as NimBLE's default address rotation
+            rotation_interval: Duration::from_secs(15 * 60),
+            power_profile: PowerProfile::Balanced,
+        }
+    }
+}
+
+impl Settings {
+    pub fn load<S: KeyValueStore>(store: &S) -> Self {
+        let mut settings = Settings::default();
+
+        for key in SETTINGS_KEYS {
+            match store.get_str(key) {
+                Ok(Some(value)) => {
+                    if let Err(e) = settings.set(key, &value) {
+                        log::warn!("Ignoring invalid setting [{}] = '{}': {}", key, value, e);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => log::warn!("Unable to read setting [{}]: {}", key, e),
+            }
+        }
+
+        settings
+    }
+
+    pub fn save<S: KeyValueStore>(&self, store: &mut S) -> Result<()> {
+        for (key, value) in self.entries() {
+            store.set_str(key, &value)?;
+        }
+        Ok(())
+    }
+
+    /// Changes one setting from its text form, as typed into the console
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let value = value.trim();
+
+        match key {
+            LOG_LEVEL_KEY => {
+                self.log_level = value
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("'{}' is not a log level", value))?
+            }
+            EVENT_CODE_KEY => self.event_code = value.parse()?,
+            RSSI_THRESHOLD_KEY => self.rssi_threshold = value.parse()?,
+            TX_POWER_KEY => {
+                self.tx_power_at_1m = match value {
+                    "" | "none" => None,
+                    value => Some(value.parse()?),
+                }
+            }
+            DWELL_TIME_KEY => self.dwell_time = Duration::from_secs(value.parse()?),
+            ROTATION_INTERVAL_KEY => {
+                let secs = value.parse()?;
+                if secs == 0 {
+                    anyhow::bail!("Rotation interval must be at least 1 second");
+                }
+                self.rotation_interval = Duration::from_secs(secs)
+            }
+            POWER_PROFILE_KEY => self.power_profile = value.parse()?,
+            _ => anyhow::bail!("Unknown setting '{}'", key),
+        }
+
+        Ok(())
+    }
+
+    /// Every setting in its text form, in [`SETTINGS_KEYS`] order
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        vec![
+            (LOG_LEVEL_KEY, self.log_level.as_str().to_string()),
+            (EVENT_CODE_KEY, self.event_code.to_string()),
+            (RSSI_THRESHOLD_KEY, self.rssi_threshold.to_string()),
+            (
+                TX_POWER_KEY,
+                self.tx_power_at_1m
+                    .map(|tx_power| tx_power.to_string())
+                    .unwrap_or_else(|| "none".to_string()),
+            ),
+            (DWELL_TIME_KEY, self.dwell_time.as_secs().to_string()),
+            (
+                ROTATION_INTERVAL_KEY,
+                self.rotation_interval.as_secs().to_string(),
+            ),
+            (POWER_PROFILE_KEY, self.power_profile.to_string()),
+        ]
+    }
+
+    /// Applies the settings that take effect globally
+    pub fn apply(&self) {
+        log::set_max_level(self.log_level);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::storage::MemoryStore;
+
+    fn changed() -> Settings {
+        let mut settings = Settings::default();
+        for (key, value) in [
+            (LOG_LEVEL_KEY, "warn"),
+            (EVENT_CODE_KEY, "4660"),
+            (RSSI_THRESHOLD_KEY, "-60"),
+            (TX_POWER_KEY, "-59"),
+            (DWELL_TIME_KEY, "5"),
+            (ROTATION_INTERVAL_KEY, "60"),
+            (POWER_PROFILE_KEY, "saver"),
+        ] {
+            settings.set(key, value).unwrap();
+        }
+        settings
+    }
+
+    #[test]
+    fn set_parses_every_setting() {
+        let settings = changed();
+
+        assert_eq!(settings.log_level, LevelFilter::Warn);
+        assert_eq!(settings.event_code, 0x1234);
+        assert_eq!(settings.rssi_threshold, -60);
+        assert_eq!(settings.tx_power_at_1m, Some(-59));
+        assert_eq!(settings.dwell_time, Duration::from_secs(5));
+        assert_eq!(settings.rotation_interval, Duration::from_secs(60));
+        assert_eq!(settings.power_profile, PowerProfile::Saver);
+    }
+
+    #[test]
+    fn entries_follow_the_key_order_and_parse_back() {
+        let settings = changed();
+        let entries = settings.entries();
+        let keys: Vec<_> = entries.iter().map(|(key, _)| *key).collect();
+        assert_eq!(keys, SETTINGS_KEYS);
+
+        let mut parsed = Settings::default();
+        for (key, value) in &entries {
+            parsed.set(key, value).unwrap();
+        }
+        assert_eq!(parsed, settings);
+    }
+
+    #[test]
+    fn round_trips_through_the_store() {
+        let mut store = MemoryStore::new();
+        assert_eq!(Settings::load(&store), Settings::default());
+
+        changed().save(&mut store).unwrap();
+        assert_eq!(store.get_str(TX_POWER_KEY).unwrap().as_deref(), Some("-59"));
+        assert_eq!(Settings::load(&store), changed());
+
+        let mut cleared = changed();
+        cleared.set(TX_POWER_KEY, " none ").unwrap();
+        cleared.save(&mut store).unwrap();
+        assert_eq!(Settings::load(&store).tx_power_at_1m, None);
+    }
+
+    #[test]
+    fn rejects_invalid_values_and_keeps_the_old_one() {
+        let mut settings = changed();
+
+        for (key, value) in [
+            (ROTATION_INTERVAL_KEY, "0"),
+            (ROTATION_INTERVAL_KEY, "-1"),
+            (EVENT_CODE_KEY, "65536"),
+            (RSSI_THRESHOLD_KEY, "-129"),
+            (TX_POWER_KEY, "128"),
+            (DWELL_TIME_KEY, "soon"),
+            (LOG_LEVEL_KEY, "loud"),
+            (POWER_PROFILE_KEY, "turbo"),
+        ] {
+            assert!(settings.set(key, value).is_err(), "[{}] = '{}'", key, value);
+        }
+
+        assert_eq!(settings, changed());
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let mut settings = Settings::default();
+        assert!(settings.set("volume", "11").is_err());
+        assert_eq!(settings, Settings::default());
+    }
+
+    #[test]
+    fn load_skips_invalid_stored_values() {
+        let mut store = MemoryStore::new();
+        store.set_str(ROTATION_INTERVAL_KEY, "0").unwrap();
+        store.set_str(EVENT_CODE_KEY, "not a number").unwrap();
+        store.set_str(DWELL_TIME_KEY, "7").unwrap();
+
+        let settings = Settings::load(&store);
+        assert_eq!(
+            settings.rotation_interval,
+            Settings::default().rotation_interval
+        );
+        assert_eq!(settings.event_code, 0);
+        assert_eq!(settings.dwell_time, Duration::from_secs(7));
+    }
+}
@@ -0,0 +1,476 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// NVS namespace every piece of device data lives under
+pub const NAMESPACE: &str = "storage";
+
+/// Largest encoded size of a list that grows with every exchange, i.e. the
+/// blocklist and the contacts.
+///
+/// The default NVS partition is 24 KB and one of its 4 KB pages is always kept
+/// free for garbage collection. A blob that is rewritten briefly exists twice,
+/// so both lists, a second copy of one of them and the profile have to fit
+/// into the remaining 20 KB: 3 x 5 KB plus ~3 KB for the rest.
+pub const MAX_LIST_LEN: usize = 5 * 1024;
+
+/// Key-value backend that `DeviceInfo` reads and writes through.
+///
+/// Like NVS, keys are limited to 15 characters and a key holds either a string
+/// or a blob, reading it back as the other type is not supported.
+///
+/// On the badge this is the `storage` NVS namespace, on a Linux host it can be
+/// a [`MemoryStore`] or a [`FileStore`] so the profile logic can run off-target.
+pub trait KeyValueStore {
+    /// Reads a string value, `Ok(None)` if the key does not exist.
+    ///
+    /// Backends report stored bytes that are not UTF-8 as [`InvalidUtf8`]
+    fn get_str(&self, key: &str) -> Result<Option<String>>;
+
+    /// Creates or overwrites a string value
+    fn set_str(&mut self, key: &str, value: &str) -> Result<()>;
+
+    /// Reads a binary value, `Ok(None)` if the key does not exist
+    fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Creates or overwrites a binary value
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> Result<()>;
+
+    /// Removes a key, returns `true` if something was actually removed
+    fn remove(&mut self, key: &str) -> Result<bool>;
+
+    fn contains(&self, key: &str) -> Result<bool> {
+        Ok(self.get_str(key)?.is_some())
+    }
+
+    /// Bytes that can still be written, `None` if the backend has no limit
+    /// worth checking
+    fn free_space(&self) -> Result<Option<usize>> {
+        Ok(None)
+    }
+}
+
+/// NVS stores everything in 32 byte entries, 126 of them to a 4 KB page
+#[cfg(target_os = "espidf")]
+const NVS_ENTRY_LEN: usize = 32;
+#[cfg(target_os = "espidf")]
+const NVS_PAGE_ENTRIES: usize = 126;
+
+#[cfg(target_os = "espidf")]
+impl<T: esp_idf_svc::nvs::NvsPartitionId> KeyValueStore for esp_idf_svc::nvs::EspNvs<T> {
+    fn get_str(&self, key: &str) -> Result<Option<String>> {
+        let len = match self.str_len(key)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+
+        let mut buffer = vec![0u8; len];
+        Ok(esp_idf_svc::nvs::EspNvs::get_str(self, key, &mut buffer)?
+            .map(|value| value.to_string()))
+    }
+
+    fn set_str(&mut self, key: &str, value: &str) -> Result<()> {
+        esp_idf_svc::nvs::EspNvs::set_str(self, key, value)?;
+        Ok(())
+    }
+
+    fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let len = match self.blob_len(key)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+
+        let mut buffer = vec![0u8; len];
+        Ok(esp_idf_svc::nvs::EspNvs::get_blob(self, key, &mut buffer)?.map(|value| value.to_vec()))
+    }
+
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        esp_idf_svc::nvs::EspNvs::set_blob(self, key, value)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool> {
+        Ok(esp_idf_svc::nvs::EspNvs::remove(self, key)?)
+    }
+
+    fn contains(&self, key: &str) -> Result<bool> {
+        Ok(esp_idf_svc::nvs::EspNvs::contains(self, key)?)
+    }
+
+    fn free_space(&self) -> Result<Option<usize>> {
+        let mut stats = esp_idf_svc::sys::nvs_stats_t::default();
+        // A null name reads the default partition, the one this namespace is in
+        esp_idf_svc::sys::esp!(unsafe {
+            esp_idf_svc::sys::nvs_get_stats(std::ptr::null(), &mut stats)
+        })?;
+
+        // One page is always kept free for garbage collection
+        let free = (stats.free_entries as usize).saturating_sub(NVS_PAGE_ENTRIES);
+        Ok(Some(free * NVS_ENTRY_LEN))
+    }
+}
+
+/// Error returned by [`KeyValueStore::get_str`] when the stored value is not UTF-8
+#[derive(Debug)]
+pub struct InvalidUtf8 {
+    pub key: String,
+}
+
+impl std::fmt::Display for InvalidUtf8 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Value of [{}] is not valid UTF-8", self.key)
+    }
+}
+
+impl std::error::Error for InvalidUtf8 {}
+
+/// Backend used by [`DeviceStorage::open_default`] on the current platform
+#[cfg(target_os = "espidf")]
+pub type PlatformStore = esp_idf_svc::nvs::EspNvs<esp_idf_svc::nvs::NvsDefault>;
+#[cfg(not(target_os = "espidf"))]
+pub type PlatformStore = FileStore;
+
+/// Long-lived, shareable handle to the device's persistent storage.
+///
+/// The default NVS partition can only be taken once per boot, so it is opened
+/// a single time in [`DeviceStorage::open_default`] and every subsystem (BLE,
+/// web, console) gets a clone of this handle instead.
+pub struct DeviceStorage<S: KeyValueStore> {
+    inner: Arc<Mutex<S>>,
+}
+
+impl<S: KeyValueStore> Clone for DeviceStorage<S> {
+    fn clone(&self) -> Self {
+        DeviceStorage {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S: KeyValueStore> DeviceStorage<S> {
+    pub fn new(store: S) -> Self {
+        DeviceStorage {
+            inner: Arc::new(Mutex::new(store)),
+        }
+    }
+
+    /// Locks the backend, use this when several reads/writes must not interleave
+    /// with another subsystem
+    pub fn lock(&self) -> MutexGuard<'_, S> {
+        // A panic in another thread does not leave the store itself in a bad state
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl DeviceStorage<PlatformStore> {
+    /// Takes the default NVS partition and opens the `storage` namespace.
+    ///
+    /// Must only be called once per boot, clone the returned handle instead.
+    pub fn open_default() -> Result<Self> {
+        let nvs_partition = esp_idf_svc::nvs::EspDefaultNvsPartition::take()?;
+        let nvs = esp_idf_svc::nvs::EspNvs::new(nvs_partition, NAMESPACE, true)?;
+
+        Ok(Self::new(nvs))
+    }
+}
+
+#[cfg(not(target_os = "espidf"))]
+impl DeviceStorage<PlatformStore> {
+    /// Opens the file backed store named by `DAPUP_STORAGE`, or `storage.kv` in
+    /// the working directory
+    pub fn open_default() -> Result<Self> {
+        let path = std::env::var("DAPUP_STORAGE").unwrap_or_else(|_| "storage.kv".to_string());
+
+        Ok(Self::new(FileStore::open(path)?))
+    }
+}
+
+impl<S: KeyValueStore> KeyValueStore for DeviceStorage<S> {
+    fn get_str(&self, key: &str) -> Result<Option<String>> {
+        self.lock().get_str(key)
+    }
+
+    fn set_str(&mut self, key: &str, value: &str) -> Result<()> {
+        self.lock().set_str(key, value)
+    }
+
+    fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.lock().get_blob(key)
+    }
+
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        self.lock().set_blob(key, value)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool> {
+        self.lock().remove(key)
+    }
+
+    fn contains(&self, key: &str) -> Result<bool> {
+        self.lock().contains(key)
+    }
+
+    fn free_space(&self) -> Result<Option<usize>> {
+        self.lock().free_space()
+    }
+}
+
+/// Volatile store, everything is lost when it is dropped
+#[cfg(not(target_os = "espidf"))]
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStore {
+    values: HashMap<String, Vec<u8>>,
+}
+
+#[cfg(not(target_os = "espidf"))]
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(not(target_os = "espidf"))]
+impl KeyValueStore for MemoryStore {
+    fn get_str(&self, key: &str) -> Result<Option<String>> {
+        match self.values.get(key) {
+            Some(bytes) => match String::from_utf8(bytes.clone()) {
+                Ok(value) => Ok(Some(value)),
+                Err(_) => Err(InvalidUtf8 {
+                    key: key.to_string(),
+                }
+                .into()),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn set_str(&mut self, key: &str, value: &str) -> Result<()> {
+        self.values
+            .insert(key.to_string(), value.as_bytes().to_vec());
+        Ok(())
+    }
+
+    fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.values.get(key).cloned())
+    }
+
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        self.values.insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool> {
+        Ok(self.values.remove(key).is_some())
+    }
+
+    fn contains(&self, key: &str) -> Result<bool> {
+        Ok(self.values.contains_key(key))
+    }
+}
+
+/// Host-only store that keeps a [`MemoryStore`] in sync with a file on disk.
+///
+/// Every line of the file is `key=value` with the value hex encoded, so values
+/// containing newlines or `=` survive a round trip.
+#[cfg(not(target_os = "espidf"))]
+#[derive(Debug)]
+pub struct FileStore {
+    path: std::path::PathBuf,
+    inner: MemoryStore,
+}
+
+#[cfg(not(target_os = "espidf"))]
+impl FileStore {
+    /// Opens the store at `path`, starting empty if the file does not exist yet
+    pub fn open(path: impl Into<std::path::PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let mut inner = MemoryStore::new();
+
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                for (line_num, line) in contents.lines().enumerate() {
+                    if line.is_empty() {
+                        continue;
+                    }
+
+                    let (key, value) = line.split_once('=').ok_or_else(|| {
+                        anyhow::anyhow!("Malformed line {} in {}", line_num + 1, path.display())
+                    })?;
+                    inner.values.insert(key.to_string(), decode_hex(value)?);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(FileStore { path, inner })
+    }
+
+    fn flush(&self) -> Result<()> {
+        let mut keys: Vec<&String> = self.inner.values.keys().collect();
+        keys.sort();
+
+        let mut contents = String::new();
+        for key in keys {
+            contents.push_str(key);
+            contents.push('=');
+            contents.push_str(&encode_hex(&self.inner.values[key]));
+            contents.push('\n');
+        }
+
+        std::fs::write(&self.path, contents)?;
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "espidf"))]
+impl KeyValueStore for FileStore {
+    fn get_str(&self, key: &str) -> Result<Option<String>> {
+        self.inner.get_str(key)
+    }
+
+    fn set_str(&mut self, key: &str, value: &str) -> Result<()> {
+        self.inner.set_str(key, value)?;
+        self.flush()
+    }
+
+    fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.inner.get_blob(key)
+    }
+
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        self.inner.set_blob(key, value)?;
+        self.flush()
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool> {
+        let removed = self.inner.remove(key)?;
+        if removed {
+            self.flush()?;
+        }
+        Ok(removed)
+    }
+
+    fn contains(&self, key: &str) -> Result<bool> {
+        self.inner.contains(key)
+    }
+}
+
+#[cfg(not(target_os = "espidf"))]
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(not(target_os = "espidf"))]
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        anyhow::bail!("Odd length hex value");
+    }
+
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| Ok(u8::from_str_radix(std::str::from_utf8(pair)?, 16)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A store file of its own for every test, removed again on drop
+    struct TempFile(std::path::PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("dapup-{}-{}.kv", name, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn memory_store_round_trip() {
+        let mut store = MemoryStore::new();
+        assert_eq!(store.get_str("name").unwrap(), None);
+
+        store.set_str("name", "Badge").unwrap();
+        store.set_blob("blob", &[0, 1, 0xff]).unwrap();
+        assert_eq!(store.get_str("name").unwrap().as_deref(), Some("Badge"));
+        assert_eq!(store.get_blob("blob").unwrap(), Some(vec![0, 1, 0xff]));
+        assert!(store.contains("blob").unwrap());
+
+        store.set_str("name", "Other").unwrap();
+        assert_eq!(store.get_str("name").unwrap().as_deref(), Some("Other"));
+
+        assert!(store.remove("name").unwrap());
+        assert!(!store.remove("name").unwrap());
+        assert!(!store.contains("name").unwrap());
+    }
+
+    #[test]
+    fn memory_store_reports_invalid_utf8() {
+        let mut store = MemoryStore::new();
+        store.set_blob("name", &[0xff, 0xfe]).unwrap();
+
+        let error = store.get_str("name").unwrap_err();
+        assert_eq!(error.downcast_ref::<InvalidUtf8>().unwrap().key, "name");
+    }
+
+    #[test]
+    fn file_store_survives_reopening() {
+        let file = TempFile::new("reopen");
+
+        let mut store = FileStore::open(&file.0).unwrap();
+        store.set_str("name", "a=b\nc").unwrap();
+        store.set_blob("blob", &[0, b'\n', b'=', 0xff]).unwrap();
+        store.set_str("gone", "soon").unwrap();
+        assert!(store.remove("gone").unwrap());
+
+        let store = FileStore::open(&file.0).unwrap();
+        assert_eq!(store.get_str("name").unwrap().as_deref(), Some("a=b\nc"));
+        assert_eq!(
+            store.get_blob("blob").unwrap(),
+            Some(vec![0, b'\n', b'=', 0xff])
+        );
+        assert!(!store.contains("gone").unwrap());
+    }
+
+    #[test]
+    fn file_store_starts_empty_without_a_file() {
+        let file = TempFile::new("missing");
+
+        let store = FileStore::open(&file.0).unwrap();
+        assert_eq!(store.get_str("name").unwrap(), None);
+        assert!(!file.0.exists());
+    }
+
+    #[test]
+    fn file_store_rejects_malformed_files() {
+        let file = TempFile::new("malformed");
+
+        std::fs::write(&file.0, "name=4\n").unwrap();
+        assert!(FileStore::open(&file.0).is_err());
+
+        std::fs::write(&file.0, "no separator\n").unwrap();
+        assert!(FileStore::open(&file.0).is_err());
+    }
+
+    #[test]
+    fn device_storage_shares_one_store() {
+        let mut storage = DeviceStorage::new(MemoryStore::new());
+        let clone = storage.clone();
+
+        storage.set_str("name", "Badge").unwrap();
+        assert_eq!(clone.get_str("name").unwrap().as_deref(), Some("Badge"));
+    }
+}
@@ -0,0 +1,118 @@
+use super::error::DeviceInfoError;
+use unicode_normalization::UnicodeNormalization;
+
+/// Normalises a user supplied value before it is stored.
+///
+/// The value is trimmed, put into Unicode NFC (so the same name typed on two
+/// keyboards compares and measures the same) and rejected if it contains
+/// control characters or is more than `max` bytes once normalised.
+pub fn normalize(key: &'static str, value: &str, max: usize) -> Result<String, DeviceInfoError> {
+    let value: String = value.trim().nfc().collect();
+
+    check(key, &value, max)?;
+    Ok(value)
+}
+
+/// Same as [`normalize`] but an empty value is an error
+pub fn normalize_required(
+    key: &'static str,
+    value: &str,
+    max: usize,
+) -> Result<String, DeviceInfoError> {
+    let value = normalize(key, value, max)?;
+
+    if value.is_empty() {
+        return Err(DeviceInfoError::EmptyValue(key));
+    }
+
+    Ok(value)
+}
+
+/// Checks an already stored or received value without changing it
+pub fn check(key: &'static str, value: &str, max: usize) -> Result<(), DeviceInfoError> {
+    if let Some(c) = value.chars().find(|c| c.is_control()) {
+        return Err(DeviceInfoError::InvalidCharacter { key, character: c });
+    }
+
+    if value.len() > max {
+        return Err(DeviceInfoError::ValueTooLong {
+            key,
+            len: value.len(),
+            max,
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPOSED: &str = "Jos\u{e9}";
+    const DECOMPOSED: &str = "Jose\u{301}";
+
+    #[test]
+    fn composes_decomposed_input() {
+        assert_ne!(COMPOSED, DECOMPOSED);
+        assert_eq!(normalize("name", DECOMPOSED, 64).unwrap(), COMPOSED);
+        assert_eq!(normalize("name", COMPOSED, 64).unwrap(), COMPOSED);
+    }
+
+    #[test]
+    fn trims_surrounding_whitespace() {
+        assert_eq!(normalize("name", " \t Sam \n", 64).unwrap(), "Sam");
+        assert_eq!(normalize("name", "Sam Lee", 64).unwrap(), "Sam Lee");
+    }
+
+    #[test]
+    fn rejects_control_characters() {
+        for value in ["Sam\nLee", "Sam\u{7}", "\u{0}Sam", "Sam\u{9b}Lee"] {
+            assert!(
+                matches!(
+                    normalize("name", value, 64),
+                    Err(DeviceInfoError::InvalidCharacter { key: "name", .. })
+                ),
+                "{:?}",
+                value
+            );
+        }
+        assert!(check("name", "Sam\r", 64).is_err());
+    }
+
+    #[test]
+    fn empty_after_trimming_is_only_an_error_when_required() {
+        assert_eq!(normalize("name", "  \t ", 64).unwrap(), "");
+        assert!(matches!(
+            normalize_required("name", "  \t ", 64),
+            Err(DeviceInfoError::EmptyValue("name"))
+        ));
+        assert_eq!(normalize_required("name", " Sam ", 64).unwrap(), "Sam");
+    }
+
+    #[test]
+    fn limits_bytes_not_characters() {
+        // 3 characters but 6 bytes
+        assert!(matches!(
+            normalize("name", "\u{e9}\u{e9}\u{e9}", 5),
+            Err(DeviceInfoError::ValueTooLong {
+                key: "name",
+                len: 6,
+                max: 5
+            })
+        ));
+        assert!(normalize("name", "\u{e9}\u{e9}\u{e9}", 6).is_ok());
+        // 4 byte characters are counted in full
+        assert!(normalize("name", "\u{1f44b}", 3).is_err());
+        assert!(normalize("name", "\u{1f44b}", 4).is_ok());
+    }
+
+    #[test]
+    fn measures_the_normalised_value() {
+        // 6 bytes as typed, 5 once composed
+        assert_eq!(DECOMPOSED.len(), 6);
+        assert_eq!(normalize("name", DECOMPOSED, 5).unwrap().len(), 5);
+        // Whitespace that is trimmed off does not count either
+        assert!(normalize("name", "  Sam  ", 3).is_ok());
+    }
+}
@@ -0,0 +1,137 @@
+use super::blocklist::BLOCKLIST_KEY;
+use super::contacts::CONTACTS_KEY;
+use super::error::DeviceInfoError;
+use super::profile::ProfileRecord;
+use super::provisioning::ProvisioningRecord;
+use super::settings::{Settings, SETTINGS_KEYS};
+use super::storage::KeyValueStore;
+
+/// Everything that was received from other devices
+pub const RECEIVED_DATA_KEYS: &[&str] = &[BLOCKLIST_KEY, CONTACTS_KEY];
+
+/// Forgets every device we exchanged with, the profile and settings are kept
+pub fn forget_received<S: KeyValueStore>(store: &mut S) -> Result<(), DeviceInfoError> {
+    remove_all(store, RECEIVED_DATA_KEYS)?;
+
+    log::warn!("All received data has been wiped");
+    Ok(())
+}
+
+/// Returns the device to how it left the factory.
+///
+/// Only the keys listed here are touched, so the serial number lock and the
+/// identity keys survive by construction. The profile is rewritten with just
+/// the serial number, dropping name, owner and contact card. A corrupted
+/// profile is replaced, with the serial number of the provisioning record.
+pub fn factory_reset<S: KeyValueStore>(store: &mut S) -> Result<(), DeviceInfoError> {
+    let serial_num = match ProfileRecord::load(store) {
+        Ok(record) => record.and_then(|record| record.serial_num),
+        Err(e @ DeviceInfoError::CorruptRecord { .. }) => {
+            log::warn!("{}, recovering the serial number from provisioning", e);
+            provisioned_serial(store)
+        }
+        Err(e) => return Err(e),
+    };
+    ProfileRecord {
+        serial_num,
+        ..Default::default()
+    }
+    .save(store)?;
+
+    remove_all(store, RECEIVED_DATA_KEYS)?;
+    remove_all(store, SETTINGS_KEYS)?;
+    Settings::default().apply();
+
+    log::warn!("Factory reset done");
+    Ok(())
+}
+
+fn provisioned_serial<S: KeyValueStore>(store: &S) -> Option<String> {
+    match ProvisioningRecord::load(store) {
+        Ok(record) => record.map(|record| record.serial_num),
+        Err(e) => {
+            log::warn!("{}, dropping the serial number", e);
+            None
+        }
+    }
+}
+
+fn remove_all<S: KeyValueStore>(store: &mut S, keys: &[&str]) -> Result<(), DeviceInfoError> {
+    for key in keys {
+        store.remove(key)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::identity::{self, IDENTITY_KEY_KEY};
+    use crate::utils::profile::PROFILE_KEY;
+    use crate::utils::provisioning::{self, SERIAL_LOCK_KEY};
+    use crate::utils::serial::{DeviceInfo, NVSKeyword};
+    use crate::utils::settings::RSSI_THRESHOLD_KEY;
+    use crate::utils::storage::MemoryStore;
+
+    fn provisioned() -> MemoryStore {
+        let mut store = MemoryStore::new();
+        provisioning::provision_serial(&mut store, "SN-0042", "B1", 1_700_000_000).unwrap();
+        DeviceInfo::update(&mut store, "Badge", NVSKeyword::DeviceName).unwrap();
+        store.set_str(RSSI_THRESHOLD_KEY, "-60").unwrap();
+        store.set_blob(BLOCKLIST_KEY, b"peers").unwrap();
+        store
+    }
+
+    #[test]
+    fn factory_reset_keeps_only_the_serial_number_and_identity_key() {
+        let mut store = provisioned();
+        let key = identity::identity_key(&mut store).unwrap();
+        factory_reset(&mut store).unwrap();
+
+        let record = ProfileRecord::load(&store).unwrap().unwrap();
+        assert_eq!(record.serial_num.as_deref(), Some("SN-0042"));
+        assert_eq!(record.device_name, None);
+        assert!(store.contains(SERIAL_LOCK_KEY).unwrap());
+        assert_eq!(
+            store.get_blob(IDENTITY_KEY_KEY).unwrap(),
+            Some(key.to_vec())
+        );
+        assert!(!store.contains(RSSI_THRESHOLD_KEY).unwrap());
+        assert!(!store.contains(BLOCKLIST_KEY).unwrap());
+    }
+
+    #[test]
+    fn factory_reset_replaces_a_corrupt_profile() {
+        let mut store = provisioned();
+        store.set_blob(PROFILE_KEY, b"garbage").unwrap();
+        factory_reset(&mut store).unwrap();
+
+        let record = ProfileRecord::load(&store).unwrap().unwrap();
+        assert_eq!(record.serial_num.as_deref(), Some("SN-0042"));
+        assert!(!store.contains(BLOCKLIST_KEY).unwrap());
+    }
+
+    #[test]
+    fn factory_reset_without_provisioning_drops_a_corrupt_serial() {
+        let mut store = MemoryStore::new();
+        store.set_blob(PROFILE_KEY, b"garbage").unwrap();
+        factory_reset(&mut store).unwrap();
+
+        assert_eq!(
+            ProfileRecord::load(&store).unwrap(),
+            Some(ProfileRecord::default())
+        );
+    }
+
+    #[test]
+    fn forget_received_keeps_the_profile() {
+        let mut store = provisioned();
+        forget_received(&mut store).unwrap();
+
+        assert!(!store.contains(BLOCKLIST_KEY).unwrap());
+        assert!(store.contains(RSSI_THRESHOLD_KEY).unwrap());
+        let record = ProfileRecord::load(&store).unwrap().unwrap();
+        assert_eq!(record.device_name.as_deref(), Some("Badge"));
+    }
+}